@@ -1,23 +1,122 @@
+use crate::pipeline::PipelineImpl;
+use crate::subscription::Subscription;
 use bytes::Bytes;
+use smq_lib::enums::code::Code;
+use smq_lib::enums::command::Command;
 use smq_lib::enums::errors::ClientError;
+use smq_lib::enums::feature::Feature;
+use smq_lib::enums::schedule::Schedule;
+use smq_lib::enums::status::Status;
+use smq_lib::structs::dead_letter::DeadLetter;
+use smq_lib::structs::delivery::Delivery;
+use smq_lib::structs::envelope::Envelope;
+use smq_lib::structs::hello::Hello;
 use smq_lib::structs::message::Message;
+use smq_lib::structs::queue_config::QueueConfig;
+use smq_lib::structs::queue_stats::QueueStats;
+use smq_lib::structs::request::{
+    decode_names, encode_name, Request, DEFAULT_MAX_FRAME_SIZE, HEADER_SIZE,
+};
+use smq_lib::traits::admin::Admin;
 use smq_lib::traits::client::Client;
-use std::io::{BufReader, Read, Write};
+use smq_lib::traits::pipeline::Pipeline;
+use std::io::{Read, Write};
 use std::net::TcpStream;
 use std::thread::sleep;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
-const PUSH_HEADER: [u8; 1] = [0];
-const PULL_HEADER: [u8; 1] = [1];
-const DISCONNECT_HEADER: [u8; 1] = [0xFF];
+const DISCONNECT_HEADER: [u8; 1] = [Command::Disconnect as u8];
+
+/// the name the client introduces itself with in the hello
+const DEFAULT_NAME: &str = "smq-client";
+
+/// writes a request to the stream
+pub(crate) fn send(
+    stream: &mut TcpStream,
+    command: Command,
+    queue: &str,
+    payload: Bytes,
+) -> Result<(), ClientError> {
+    let request = match Request::new(command, queue, payload) {
+        Ok(r) => r,
+        Err(e) => return Err(ClientError::MessageError(e)),
+    };
+
+    send_frame(stream, &request.serialize())
+}
+
+/// writes a whole frame to the stream
+pub(crate) fn send_frame(stream: &mut TcpStream, frame: &[u8]) -> Result<(), ClientError> {
+    if let Err(e) = stream.write_all(frame) {
+        return Err(ClientError::CantWriteToStream(e.to_string()));
+    };
+
+    Ok(())
+}
+
+/// reads a response from the stream and returns its body, a failed response
+/// is mapped to the error matching its status. A body bigger than
+/// `max_frame_size` is refused before it's read
+pub(crate) fn receive(stream: &mut TcpStream, max_frame_size: u64) -> Result<Vec<u8>, ClientError> {
+    let (status, response) = receive_frame(stream, max_frame_size)?;
+
+    Status::check(status, &response)?;
+    Ok(response)
+}
+
+/// reads a whole frame from the stream, returns its status and its body
+pub(crate) fn receive_frame(
+    stream: &mut TcpStream,
+    max_frame_size: u64,
+) -> Result<(u8, Vec<u8>), ClientError> {
+    let mut header: [u8; HEADER_SIZE] = [0; HEADER_SIZE];
+    if let Err(e) = stream.read_exact(&mut header) {
+        return Err(ClientError::CantReadFromStream(e.to_string()));
+    }
+
+    let size = u64::from_be_bytes([
+        header[1], header[2], header[3], header[4], header[5], header[6], header[7], header[8],
+    ]);
+    if size > max_frame_size {
+        return Err(ClientError::FrameTooLarge(size));
+    }
+
+    let mut response = vec![0_u8; size as usize];
+    if let Err(e) = stream.read_exact(&mut response) {
+        return Err(ClientError::CantReadFromStream(e.to_string()));
+    }
+
+    Ok((header[0], response))
+}
 
 pub struct ClientImpl {
     stream: Option<TcpStream>,
+    name: String,
+    max_frame_size: u64,
+    /// the version and the features negotiated when connecting
+    hello: Option<Hello>,
+    /// the queue the replies to the calls are pushed to, created on the first call
+    reply_queue: Option<String>,
+    next_correlation_id: u64,
 }
 
 impl ClientImpl {
     pub fn new() -> Self {
-        ClientImpl { stream: None }
+        ClientImpl::with_options(DEFAULT_NAME, DEFAULT_MAX_FRAME_SIZE)
+    }
+
+    /// a client introducing itself as `name` to the server and reading
+    /// response bodies up to `max_frame_size` bytes, the connection can't be
+    /// used anymore after a bigger response
+    pub fn with_options(name: &str, max_frame_size: u64) -> Self {
+        ClientImpl {
+            stream: None,
+            name: name.to_string(),
+            max_frame_size,
+            hello: None,
+            reply_queue: None,
+            next_correlation_id: 0,
+        }
     }
 
     fn get_stream(&mut self) -> Result<&mut TcpStream, ClientError> {
@@ -26,6 +125,93 @@ impl ClientImpl {
             None => Err(ClientError::StreamNotStarted),
         }
     }
+
+    /// sends a request to the server and returns the response's body
+    fn request(
+        &mut self,
+        command: Command,
+        queue: &str,
+        payload: Bytes,
+    ) -> Result<Vec<u8>, ClientError> {
+        let max_frame_size = self.max_frame_size;
+        let stream = self.get_stream()?;
+
+        send(stream, command, queue, payload)?;
+        receive(stream, max_frame_size)
+    }
+
+    /// negotiates the protocol version and the features with the server, the
+    /// servers that don't know the hello speak the first version without any
+    /// feature
+    fn handshake(&mut self) -> Result<Hello, ClientError> {
+        let name = self.name.clone();
+        // pipelining is only negotiated for a pipeline
+        let offer = Hello::current().without(Feature::Pipelining);
+        let hello = match self.request(Command::Hello, &name, offer.serialize()) {
+            Ok(response) => match Hello::deserialize(&response) {
+                Ok(hello) => hello,
+                Err(e) => return Err(ClientError::MessageError(e)),
+            },
+            Err(ClientError::UnknownCommand(_)) => Hello::legacy(),
+            Err(e) => return Err(e),
+        };
+
+        if !hello.is_supported() {
+            return Err(ClientError::UnsupportedVersion(format!(
+                "the server speaks protocol version {}",
+                hello.get_version()
+            )));
+        }
+
+        Ok(hello)
+    }
+
+    /// whether the server supports a feature of the protocol
+    fn supports(&self, feature: Feature) -> bool {
+        self.hello.is_some_and(|hello| hello.supports(feature))
+    }
+
+    /// sends an ack or a nack and returns whether the server knew the delivery
+    fn settle(
+        &mut self,
+        command: Command,
+        queue: &str,
+        delivery_id: u64,
+    ) -> Result<bool, ClientError> {
+        let payload = Bytes::from(delivery_id.to_be_bytes().to_vec());
+        let response = self.request(command, queue, payload)?;
+
+        if response.len() != 1 {
+            return Err(ClientError::ServerError(String::from(
+                "Server can't settle the delivery",
+            )));
+        }
+
+        Ok(response[0] == 1)
+    }
+
+    /// subscribes or unsubscribes a queue from a topic and returns whether
+    /// anything changed
+    fn bind_topic(
+        &mut self,
+        command: Command,
+        topic: &str,
+        queue: &str,
+    ) -> Result<bool, ClientError> {
+        let payload = match encode_name(queue) {
+            Ok(payload) => payload,
+            Err(e) => return Err(ClientError::MessageError(e)),
+        };
+        let response = self.request(command, topic, payload)?;
+
+        if response.len() != 1 {
+            return Err(ClientError::ServerError(String::from(
+                "Server can't change the topic's subscriptions",
+            )));
+        }
+
+        Ok(response[0] == 1)
+    }
 }
 
 impl Client for ClientImpl {
@@ -37,14 +223,63 @@ impl Client for ClientImpl {
             Ok(stream) => Some(stream),
             Err(e) => return Err(ClientError::UnableToStartStream(e.to_string())),
         };
+        self.hello = None;
+        // the reply queue is gone along with the previous connection
+        self.reply_queue = None;
 
-        Ok(())
+        match self.handshake() {
+            Ok(hello) => {
+                self.hello = Some(hello);
+                Ok(())
+            }
+            Err(e) => {
+                self.stream = None;
+                Err(e)
+            }
+        }
+    }
+
+    fn hello(&self) -> Option<&Hello> {
+        self.hello.as_ref()
+    }
+
+    fn pipeline(&mut self) -> Result<Box<dyn Pipeline + '_>, ClientError> {
+        let hello = match self.hello {
+            Some(hello) => hello,
+            None => return Err(ClientError::StreamNotStarted),
+        };
+
+        let name = self.name.clone();
+        let offer = hello.with(Feature::Pipelining);
+        let response = match self.request(Command::Hello, &name, offer.serialize()) {
+            Ok(response) => response,
+            // the servers that don't know the hello don't know pipelining either
+            Err(ClientError::UnknownCommand(_)) => {
+                return Err(ClientError::UnsupportedFeature(Feature::Pipelining))
+            }
+            Err(e) => return Err(e),
+        };
+        let negotiated = match Hello::deserialize(&response) {
+            Ok(hello) => hello,
+            Err(e) => return Err(ClientError::MessageError(e)),
+        };
+        if !negotiated.supports(Feature::Pipelining) {
+            return Err(ClientError::UnsupportedFeature(Feature::Pipelining));
+        }
+
+        let max_frame_size = self.max_frame_size;
+        Ok(Box::new(PipelineImpl::new(
+            self.get_stream()?,
+            &name,
+            hello,
+            max_frame_size,
+        )))
     }
 
     fn disconnect(&mut self) -> Result<(), ClientError> {
         let stream = self.get_stream()?;
 
-        let req = vec![DISCONNECT_HEADER.to_vec(), vec![0; 8]].concat();
+        let req = [DISCONNECT_HEADER.to_vec(), vec![0; 8]].concat();
 
         loop {
             if stream.write(&req).is_ok() {
@@ -56,62 +291,421 @@ impl Client for ClientImpl {
         Ok(())
     }
 
-    fn push(&mut self, message: &Message) -> Result<bool, ClientError> {
-        let stream = self.get_stream()?;
+    fn create_queue(&mut self, queue: &str, config: &QueueConfig) -> Result<bool, ClientError> {
+        let response = self.request(Command::CreateQueue, queue, config.serialize())?;
+
+        if response.len() != 1 {
+            return Err(ClientError::ServerError(String::from(
+                "Server can't create queue",
+            )));
+        }
+
+        Ok(response[0] == 1)
+    }
+
+    fn push(
+        &mut self,
+        queue: &str,
+        message: &Message,
+        priority: u8,
+        ttl: Option<Duration>,
+    ) -> Result<(), ClientError> {
+        let envelope = Envelope::new(message.clone(), priority, ttl);
+        self.request(Command::Push, queue, envelope.serialize())?;
+
+        Ok(())
+    }
+
+    fn push_batch(
+        &mut self,
+        queue: &str,
+        messages: &[Message],
+        priority: u8,
+        ttl: Option<Duration>,
+    ) -> Result<Vec<Result<(), ClientError>>, ClientError> {
+        if !self.supports(Feature::Batch) {
+            return Ok(messages
+                .iter()
+                .map(|message| self.push(queue, message, priority, ttl))
+                .collect());
+        }
+
+        let envelopes: Vec<Envelope> = messages
+            .iter()
+            .map(|message| Envelope::new(message.clone(), priority, ttl))
+            .collect();
+        let response = self.request(
+            Command::PushBatch,
+            queue,
+            Envelope::serialize_list(&envelopes),
+        )?;
+
+        if response.len() != messages.len() {
+            return Err(ClientError::ServerError(String::from(
+                "Server can't push the batch",
+            )));
+        }
+
+        Ok(response
+            .iter()
+            .map(|status| Status::check(*status, &[]))
+            .collect())
+    }
+
+    fn push_scheduled(
+        &mut self,
+        queue: &str,
+        message: &Message,
+        priority: u8,
+        ttl: Option<Duration>,
+        schedule: Schedule,
+    ) -> Result<(), ClientError> {
+        let envelope = Envelope::new(message.clone(), priority, ttl);
+        let payload = Bytes::from([&schedule.serialize()[..], &envelope.serialize()].concat());
+        self.request(Command::PushScheduled, queue, payload)?;
+
+        Ok(())
+    }
+
+    fn pull(&mut self, queue: &str) -> Result<Message, ClientError> {
+        let response = self.request(Command::Pull, queue, Bytes::new())?;
+
+        match Message::deserialize(&response) {
+            Ok(msg) => Ok(msg),
+            Err(e) => Err(ClientError::MessageError(e)),
+        }
+    }
+
+    fn pull_batch(&mut self, queue: &str, max: u32) -> Result<Vec<Message>, ClientError> {
+        if !self.supports(Feature::Batch) {
+            let mut messages = vec![];
+            while messages.len() < max as usize {
+                match self.pull(queue)? {
+                    msg if msg.get_code() == Code::EMPTY_QUEUE => break,
+                    msg => messages.push(msg),
+                }
+            }
+            return Ok(messages);
+        }
+
+        let payload = Bytes::from(max.to_be_bytes().to_vec());
+        let response = self.request(Command::PullBatch, queue, payload)?;
+
+        match Message::deserialize_list(&response) {
+            Ok(messages) => Ok(messages),
+            Err(e) => Err(ClientError::MessageError(e)),
+        }
+    }
+
+    fn pull_wait(&mut self, queue: &str, timeout: Duration) -> Result<Message, ClientError> {
+        let payload = Bytes::from((timeout.as_millis() as u64).to_be_bytes().to_vec());
+        let response = self.request(Command::PullWait, queue, payload)?;
+
+        match Message::deserialize(&response) {
+            Ok(msg) => Ok(msg),
+            Err(e) => Err(ClientError::MessageError(e)),
+        }
+    }
+
+    fn subscribe(
+        &mut self,
+        queue: &str,
+        prefetch: u32,
+    ) -> Result<Box<dyn Iterator<Item = Result<Message, ClientError>> + '_>, ClientError> {
+        let payload = Bytes::from(prefetch.max(1).to_be_bytes().to_vec());
+        self.request(Command::Subscribe, queue, payload)?;
 
-        let msg = message.serialize();
-        let request = vec![
-            Bytes::from(PUSH_HEADER.to_vec()),
-            Bytes::from(msg.len().to_be_bytes().to_vec()),
-            msg,
-        ]
-        .concat();
-        if let Err(e) = stream.write(&request) {
-            return Err(ClientError::CantWriteToStream(e.to_string()));
+        let max_frame_size = self.max_frame_size;
+        Ok(Box::new(Subscription::new(
+            self.get_stream()?,
+            queue,
+            max_frame_size,
+        )))
+    }
+
+    fn reserve(&mut self, queue: &str, timeout: Option<Duration>) -> Result<Delivery, ClientError> {
+        let payload = match timeout {
+            Some(timeout) => Bytes::from((timeout.as_millis() as u64).to_be_bytes().to_vec()),
+            None => Bytes::new(),
         };
+        let response = self.request(Command::Reserve, queue, payload)?;
+
+        match Delivery::deserialize(&response) {
+            Ok(delivery) => Ok(delivery),
+            Err(e) => Err(ClientError::MessageError(e)),
+        }
+    }
+
+    fn ack(&mut self, queue: &str, delivery_id: u64) -> Result<bool, ClientError> {
+        self.settle(Command::Ack, queue, delivery_id)
+    }
+
+    fn nack(&mut self, queue: &str, delivery_id: u64) -> Result<bool, ClientError> {
+        self.settle(Command::Nack, queue, delivery_id)
+    }
+
+    fn dead_letters(&mut self, queue: &str) -> Result<Vec<DeadLetter>, ClientError> {
+        let response = self.request(Command::ListDeadLetters, queue, Bytes::new())?;
 
-        let mut buf_reader = BufReader::new(stream);
-        let mut response_buf: [u8; 1] = [0];
-        if let Err(e) = buf_reader.read_exact(&mut response_buf) {
-            return Err(ClientError::CantReadFromStream(e.to_string()));
+        match DeadLetter::deserialize_list(&response) {
+            Ok(dead_letters) => Ok(dead_letters),
+            Err(e) => Err(ClientError::MessageError(e)),
+        }
+    }
+
+    fn inspect_dead_letter(&mut self, queue: &str, id: u64) -> Result<Message, ClientError> {
+        let payload = Bytes::from(id.to_be_bytes().to_vec());
+        let response = self.request(Command::InspectDeadLetter, queue, payload)?;
+
+        match Message::deserialize(&response) {
+            Ok(msg) => Ok(msg),
+            Err(e) => Err(ClientError::MessageError(e)),
+        }
+    }
+
+    fn redrive(&mut self, queue: &str, id: Option<u64>) -> Result<usize, ClientError> {
+        let payload = match id {
+            Some(id) => Bytes::from(id.to_be_bytes().to_vec()),
+            None => Bytes::new(),
         };
+        let response = self.request(Command::Redrive, queue, payload)?;
 
-        Ok(response_buf[0] == 0)
+        if response.len() != 8 {
+            return Err(ClientError::ServerError(String::from(
+                "Server can't redrive the dead letters",
+            )));
+        }
+
+        Ok(u64::from_be_bytes(response.try_into().unwrap()) as usize)
     }
 
-    fn pull(&mut self) -> Result<Message, ClientError> {
-        let stream = self.get_stream()?;
+    fn subscribe_topic(&mut self, topic: &str, queue: &str) -> Result<bool, ClientError> {
+        self.bind_topic(Command::SubscribeTopic, topic, queue)
+    }
+
+    fn unsubscribe_topic(&mut self, topic: &str, queue: &str) -> Result<bool, ClientError> {
+        self.bind_topic(Command::UnsubscribeTopic, topic, queue)
+    }
 
-        let header = [PULL_HEADER.to_vec(), vec![0_u8; 8]].concat();
-        if let Err(e) = stream.write(&header) {
-            return Err(ClientError::CantWriteToStream(e.to_string()));
+    fn publish(&mut self, topic: &str, message: &Message) -> Result<usize, ClientError> {
+        let response = self.request(Command::Publish, topic, message.serialize())?;
+
+        if response.len() != 8 {
+            return Err(ClientError::ServerError(String::from(
+                "Server can't publish the message",
+            )));
+        }
+
+        Ok(u64::from_be_bytes(response.try_into().unwrap()) as usize)
+    }
+
+    fn join_group(&mut self, topic: &str, group: &str) -> Result<String, ClientError> {
+        let payload = match encode_name(group) {
+            Ok(payload) => payload,
+            Err(e) => return Err(ClientError::MessageError(e)),
         };
+        let response = self.request(Command::JoinGroup, topic, payload)?;
 
-        let mut buf_reader = BufReader::new(stream);
-        let mut header: [u8; 9] = [0; 9];
-        if let Err(e) = buf_reader.read_exact(&mut header) {
-            return Err(ClientError::CantReadFromStream(e.to_string()));
+        match String::from_utf8(response) {
+            Ok(queue) => Ok(queue),
+            Err(e) => Err(ClientError::ServerError(e.to_string())),
         }
+    }
+
+    fn leave_group(&mut self, topic: &str, group: &str) -> Result<bool, ClientError> {
+        let payload = match encode_name(group) {
+            Ok(payload) => payload,
+            Err(e) => return Err(ClientError::MessageError(e)),
+        };
+        let response = self.request(Command::LeaveGroup, topic, payload)?;
 
-        if header[0] != 0 {
+        if response.len() != 1 {
             return Err(ClientError::ServerError(String::from(
-                "Server can't send data",
+                "Server can't leave the group",
             )));
         }
 
-        let size = u64::from_be_bytes([
-            header[1], header[2], header[3], header[4], header[5], header[6], header[7], header[8],
-        ]);
+        Ok(response[0] == 1)
+    }
+
+    fn create_reply_queue(&mut self) -> Result<String, ClientError> {
+        let name = self.name.clone();
+        let response = self.request(Command::CreateReplyQueue, &name, Bytes::new())?;
 
-        let mut response = vec![0_u8; size as usize];
-        if let Err(e) = buf_reader.read_exact(&mut response) {
-            return Err(ClientError::CantReadFromStream(e.to_string()));
+        match String::from_utf8(response) {
+            Ok(queue) => Ok(queue),
+            Err(e) => Err(ClientError::ServerError(e.to_string())),
         }
+    }
 
-        match Message::deserialize(&response) {
-            Ok(msg) => Ok(msg),
+    fn call(
+        &mut self,
+        queue: &str,
+        request: &Message,
+        timeout: Duration,
+    ) -> Result<Message, ClientError> {
+        let reply_queue = match &self.reply_queue {
+            Some(reply_queue) => reply_queue.clone(),
+            None => {
+                let reply_queue = self.create_reply_queue()?;
+                self.reply_queue = Some(reply_queue.clone());
+                reply_queue
+            }
+        };
+        // the reply queue's name is unique, so is the correlation id
+        self.next_correlation_id += 1;
+        let correlation_id = format!("{}-{}", reply_queue, self.next_correlation_id);
+
+        let request = request
+            .clone()
+            .with_reply_to(&reply_queue)
+            .with_correlation_id(&correlation_id);
+        self.push(queue, &request, 0, None)?;
+
+        let deadline = Instant::now() + timeout;
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Err(ClientError::Timeout);
+            }
+
+            match self.pull_wait(&reply_queue, remaining)? {
+                reply if reply.get_code() == Code::EMPTY_QUEUE => return Err(ClientError::Timeout),
+                reply if reply.get_correlation_id() == Some(&correlation_id) => return Ok(reply),
+                // a late reply to an earlier call
+                _ => continue,
+            }
+        }
+    }
+
+    fn reply(&mut self, request: &Message, reply: &Message) -> Result<bool, ClientError> {
+        let reply_queue = match request.get_reply_to() {
+            Some(reply_queue) => reply_queue,
+            None => return Ok(false),
+        };
+
+        let reply = match request.get_correlation_id() {
+            Some(correlation_id) => reply.clone().with_correlation_id(correlation_id),
+            None => reply.clone(),
+        };
+        self.push(reply_queue, &reply, 0, None)?;
+
+        Ok(true)
+    }
+}
+
+impl Admin for ClientImpl {
+    fn list_queues(&mut self) -> Result<Vec<String>, ClientError> {
+        let name = self.name.clone();
+        let response = self.request(Command::ListQueues, &name, Bytes::new())?;
+
+        match decode_names(&response) {
+            Ok(names) => Ok(names),
+            Err(e) => Err(ClientError::MessageError(e)),
+        }
+    }
+
+    fn queue_stats(&mut self, queue: &str) -> Result<Option<QueueStats>, ClientError> {
+        let response = self.request(Command::QueueStats, queue, Bytes::new())?;
+        if response.is_empty() {
+            return Ok(None);
+        }
+
+        match QueueStats::deserialize(&response) {
+            Ok(stats) => Ok(Some(stats)),
+            Err(e) => Err(ClientError::MessageError(e)),
+        }
+    }
+
+    fn peek(&mut self, queue: &str, max: u32) -> Result<Vec<Message>, ClientError> {
+        let payload = Bytes::from(max.to_be_bytes().to_vec());
+        let response = self.request(Command::Peek, queue, payload)?;
+
+        match Message::deserialize_list(&response) {
+            Ok(messages) => Ok(messages),
             Err(e) => Err(ClientError::MessageError(e)),
         }
     }
+
+    fn purge(&mut self, queue: &str) -> Result<usize, ClientError> {
+        let response = self.request(Command::Purge, queue, Bytes::new())?;
+
+        if response.len() != 8 {
+            return Err(ClientError::ServerError(String::from(
+                "Server can't purge the queue",
+            )));
+        }
+
+        Ok(u64::from_be_bytes(response.try_into().unwrap()) as usize)
+    }
+
+    fn delete_queue(&mut self, queue: &str) -> Result<bool, ClientError> {
+        let response = self.request(Command::DeleteQueue, queue, Bytes::new())?;
+
+        if response.len() != 1 {
+            return Err(ClientError::ServerError(String::from(
+                "Server can't delete the queue",
+            )));
+        }
+
+        Ok(response[0] == 1)
+    }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::ClientImpl;
+    use smq_lib::enums::command::Command;
+    use smq_lib::enums::errors::ClientError;
+    use smq_lib::enums::status::Status;
+    use smq_lib::structs::message::Message;
+    use smq_lib::structs::request::HEADER_SIZE;
+    use smq_lib::traits::client::Client;
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    /// reads a request and returns its command
+    fn read_request(stream: &mut TcpStream) -> u8 {
+        let mut header = [0; HEADER_SIZE];
+        stream.read_exact(&mut header).unwrap();
+        let size = u64::from_be_bytes(header[1..].try_into().unwrap());
+        stream.read_exact(&mut vec![0; size as usize]).unwrap();
+        header[0]
+    }
+
+    fn respond(stream: &mut TcpStream, status: Status) {
+        let header = [&[status as u8][..], &0_u64.to_be_bytes()].concat();
+        stream.write_all(&header).unwrap();
+    }
+
+    #[test]
+    fn full_queue_reaches_the_client() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        // answers like a server without the hello, with a full queue and then with a queue
+        // blocking the push until there's room
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            assert_eq!(read_request(&mut stream), Command::Hello as u8);
+            respond(&mut stream, Status::UnknownCommand);
+            assert_eq!(read_request(&mut stream), Command::Push as u8);
+            respond(&mut stream, Status::QueueFull);
+            assert_eq!(read_request(&mut stream), Command::Push as u8);
+            thread::sleep(Duration::from_millis(100));
+            respond(&mut stream, Status::Success);
+        });
+
+        let mut client = ClientImpl::new();
+        client.connect("127.0.0.1", port).unwrap();
+        let message = Message::from_u8_arr(&[1]);
+        let result = client.push("q", &message, 0, None);
+        assert!(matches!(result, Err(ClientError::QueueFull)));
+
+        let started = Instant::now();
+        assert!(client.push("q", &message, 0, None).is_ok());
+        assert!(started.elapsed() >= Duration::from_millis(100));
+        server.join().unwrap();
+    }
+}
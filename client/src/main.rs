@@ -6,6 +6,10 @@ use std::thread;
 use std::time::Duration;
 
 mod client;
+mod pipeline;
+mod subscription;
+
+const QUEUE: &str = "default";
 
 fn main() {
     ::std::env::set_var("RUST_LOG", "INFO");
@@ -22,13 +26,12 @@ fn main() {
 
             if id % 2 == 0 {
                 let msg = Message::from_i32_arr(&[id]);
-                let result = client.push(&msg).expect("Can't push message");
-                if !result {
-                    panic!("Failed to push message, server can't receive");
-                }
+                client
+                    .push(QUEUE, &msg, 0, None)
+                    .expect("Can't push message");
                 info!("client id {} has pushed a message", id);
             } else {
-                let result = client.pull().expect("Can't pull message");
+                let result = client.pull(QUEUE).expect("Can't pull message");
                 println!("client id {} pulled:\n{:#?}", id, result);
             }
 
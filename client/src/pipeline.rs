@@ -0,0 +1,152 @@
+use crate::client::{receive_frame, send_frame};
+use bytes::Bytes;
+use smq_lib::enums::command::Command;
+use smq_lib::enums::errors::ClientError;
+use smq_lib::enums::status::Status;
+use smq_lib::structs::envelope::Envelope;
+use smq_lib::structs::hello::Hello;
+use smq_lib::structs::message::Message;
+use smq_lib::structs::request::{tag_frame, untag_body, Request};
+use smq_lib::traits::pipeline::Pipeline;
+use std::collections::HashMap;
+use std::net::TcpStream;
+use std::time::Duration;
+
+/// the requests sent on a pipelined connection. The responses read while
+/// waiting for another one are kept until they're asked for.
+///
+/// The connection goes back to one request at a time when it's dropped, the
+/// responses that haven't been asked for yet are discarded.
+pub struct PipelineImpl<'a> {
+    stream: &'a mut TcpStream,
+    name: String,
+    /// the hello negotiated before pipelining, it's negotiated again once
+    /// the pipeline is dropped
+    hello: Hello,
+    max_frame_size: u64,
+    next_id: u32,
+    /// the status and the body of the requests sent, `None` until the
+    /// response is read
+    responses: HashMap<u32, Option<(u8, Vec<u8>)>>,
+}
+
+impl<'a> PipelineImpl<'a> {
+    pub(crate) fn new(
+        stream: &'a mut TcpStream,
+        name: &str,
+        hello: Hello,
+        max_frame_size: u64,
+    ) -> Self {
+        PipelineImpl {
+            stream,
+            name: name.to_string(),
+            hello,
+            max_frame_size,
+            next_id: 1,
+            responses: HashMap::new(),
+        }
+    }
+
+    /// sends a request tagged with a new id, returns the id
+    fn send(&mut self, command: Command, queue: &str, payload: Bytes) -> Result<u32, ClientError> {
+        let request = match Request::new(command, queue, payload) {
+            Ok(r) => r,
+            Err(e) => return Err(ClientError::MessageError(e)),
+        };
+
+        // `0` is the id of the responses that don't answer a request
+        let id = self.next_id;
+        self.next_id = self.next_id.checked_add(1).unwrap_or(1);
+        send_frame(self.stream, &tag_frame(&request.serialize(), id))?;
+        self.responses.insert(id, None);
+
+        Ok(id)
+    }
+
+    /// reads the responses until the one of the request, returns its body
+    fn receive(&mut self, id: u32) -> Result<Vec<u8>, ClientError> {
+        if !self.responses.contains_key(&id) {
+            return Err(ClientError::ServerError(format!("no request {}", id)));
+        }
+
+        loop {
+            if let Some(Some(_)) = self.responses.get(&id) {
+                let (status, body) = self.responses.remove(&id).unwrap().unwrap();
+                Status::check(status, &body)?;
+                return Ok(body);
+            }
+
+            let (status, body) = receive_frame(self.stream, self.max_frame_size)?;
+            let (response_id, body) = match untag_body(&body) {
+                Ok(untagged) => untagged,
+                Err(e) => return Err(ClientError::MessageError(e)),
+            };
+
+            match self.responses.get_mut(&response_id) {
+                Some(response) => *response = Some((status, body.to_vec())),
+                // the server refuses a frame it can't tie to a request
+                None => {
+                    Status::check(status, body)?;
+                    return Err(ClientError::ServerError(format!(
+                        "response to an unknown request {}",
+                        response_id
+                    )));
+                }
+            }
+        }
+    }
+}
+
+impl Pipeline for PipelineImpl<'_> {
+    fn push(
+        &mut self,
+        queue: &str,
+        message: &Message,
+        priority: u8,
+        ttl: Option<Duration>,
+    ) -> Result<u32, ClientError> {
+        let envelope = Envelope::new(message.clone(), priority, ttl);
+        self.send(Command::Push, queue, envelope.serialize())
+    }
+
+    fn pull(&mut self, queue: &str) -> Result<u32, ClientError> {
+        self.send(Command::Pull, queue, Bytes::new())
+    }
+
+    fn pull_wait(&mut self, queue: &str, timeout: Duration) -> Result<u32, ClientError> {
+        let payload = Bytes::from((timeout.as_millis() as u64).to_be_bytes().to_vec());
+        self.send(Command::PullWait, queue, payload)
+    }
+
+    fn pushed(&mut self, id: u32) -> Result<(), ClientError> {
+        self.receive(id)?;
+        Ok(())
+    }
+
+    fn pulled(&mut self, id: u32) -> Result<Message, ClientError> {
+        let response = self.receive(id)?;
+
+        match Message::deserialize(&response) {
+            Ok(msg) => Ok(msg),
+            Err(e) => Err(ClientError::MessageError(e)),
+        }
+    }
+}
+
+impl Drop for PipelineImpl<'_> {
+    fn drop(&mut self) {
+        // every request is answered before the framing changes back, the
+        // connection is given up if a response can't be read
+        let pending: Vec<u32> = self.responses.keys().copied().collect();
+        for id in pending {
+            if self.receive(id).is_err() && self.responses.contains_key(&id) {
+                return;
+            }
+        }
+
+        let name = self.name.clone();
+        if let Ok(id) = self.send(Command::Hello, &name, self.hello.serialize()) {
+            let _ = self.receive(id);
+        }
+    }
+}
@@ -0,0 +1,99 @@
+use crate::client::{receive, send};
+use bytes::Bytes;
+use smq_lib::enums::command::Command;
+use smq_lib::enums::errors::ClientError;
+use smq_lib::structs::message::Message;
+use std::net::TcpStream;
+
+/// the messages streamed by the server after subscribing to a queue. A credit
+/// is given back to the server every time the next message is requested, so
+/// the server never streams more than the prefetch count ahead of the consumer.
+///
+/// The subscription is stopped when it's dropped, the server puts the messages
+/// that are already streamed but not consumed yet back to the head of the
+/// queue.
+pub struct Subscription<'a> {
+    stream: &'a mut TcpStream,
+    queue: String,
+    max_frame_size: u64,
+    /// whether a message has been consumed without giving its credit back
+    consumed: bool,
+    done: bool,
+}
+
+impl<'a> Subscription<'a> {
+    pub(crate) fn new(stream: &'a mut TcpStream, queue: &str, max_frame_size: u64) -> Self {
+        Subscription {
+            stream,
+            queue: queue.to_string(),
+            max_frame_size,
+            consumed: false,
+            done: false,
+        }
+    }
+}
+
+impl Subscription<'_> {
+    /// gives the credit of the consumed message back, the server takes the
+    /// message off the queue then
+    fn give_credit(&mut self) -> Result<(), ClientError> {
+        if self.consumed {
+            let credit = Bytes::from(1_u32.to_be_bytes().to_vec());
+            send(self.stream, Command::Credit, &self.queue, credit)?;
+            self.consumed = false;
+        }
+        Ok(())
+    }
+}
+
+impl Iterator for Subscription<'_> {
+    type Item = Result<Message, ClientError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+
+        if let Err(e) = self.give_credit() {
+            self.done = true;
+            return Some(Err(e));
+        }
+
+        let response = match receive(self.stream, self.max_frame_size) {
+            Ok(response) => response,
+            Err(e) => {
+                self.done = true;
+                return Some(Err(e));
+            }
+        };
+
+        // the server ends the subscription with an empty response
+        if response.is_empty() {
+            self.done = true;
+            return None;
+        }
+
+        self.consumed = true;
+        Some(Message::deserialize(&response).map_err(ClientError::MessageError))
+    }
+}
+
+impl Drop for Subscription<'_> {
+    fn drop(&mut self) {
+        if self.done || self.give_credit().is_err() {
+            return;
+        }
+
+        if send(self.stream, Command::Unsubscribe, &self.queue, Bytes::new()).is_err() {
+            return;
+        }
+
+        // the server puts the streamed messages back, they're skipped until
+        // the end of the subscription
+        while let Ok(response) = receive(self.stream, self.max_frame_size) {
+            if response.is_empty() {
+                break;
+            }
+        }
+    }
+}
@@ -0,0 +1,71 @@
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Command {
+    Push = 0,
+    Pull = 1,
+    CreateQueue = 2,
+    Reserve = 3,
+    Ack = 4,
+    Nack = 5,
+    ListDeadLetters = 6,
+    InspectDeadLetter = 7,
+    Redrive = 8,
+    PullWait = 9,
+    Subscribe = 10,
+    Credit = 11,
+    Unsubscribe = 12,
+    Publish = 13,
+    SubscribeTopic = 14,
+    UnsubscribeTopic = 15,
+    JoinGroup = 16,
+    LeaveGroup = 17,
+    PushScheduled = 18,
+    Hello = 19,
+    PushBatch = 20,
+    PullBatch = 21,
+    CreateReplyQueue = 22,
+    ListQueues = 23,
+    QueueStats = 24,
+    Peek = 25,
+    Purge = 26,
+    DeleteQueue = 27,
+    Disconnect = 0xFF,
+}
+
+impl Command {
+    /// maps the first byte of a request header to a command, unknown bytes are
+    /// mapped to `None`
+    pub fn from_byte(byte: u8) -> Option<Command> {
+        match byte {
+            0 => Some(Command::Push),
+            1 => Some(Command::Pull),
+            2 => Some(Command::CreateQueue),
+            3 => Some(Command::Reserve),
+            4 => Some(Command::Ack),
+            5 => Some(Command::Nack),
+            6 => Some(Command::ListDeadLetters),
+            7 => Some(Command::InspectDeadLetter),
+            8 => Some(Command::Redrive),
+            9 => Some(Command::PullWait),
+            10 => Some(Command::Subscribe),
+            11 => Some(Command::Credit),
+            12 => Some(Command::Unsubscribe),
+            13 => Some(Command::Publish),
+            14 => Some(Command::SubscribeTopic),
+            15 => Some(Command::UnsubscribeTopic),
+            16 => Some(Command::JoinGroup),
+            17 => Some(Command::LeaveGroup),
+            18 => Some(Command::PushScheduled),
+            19 => Some(Command::Hello),
+            20 => Some(Command::PushBatch),
+            21 => Some(Command::PullBatch),
+            22 => Some(Command::CreateReplyQueue),
+            23 => Some(Command::ListQueues),
+            24 => Some(Command::QueueStats),
+            25 => Some(Command::Peek),
+            26 => Some(Command::Purge),
+            27 => Some(Command::DeleteQueue),
+            0xFF => Some(Command::Disconnect),
+            _ => None,
+        }
+    }
+}
@@ -1,15 +1,24 @@
+use crate::enums::feature::Feature;
+
 #[derive(Debug, PartialEq)]
 pub enum MessageError {
     InvalidType,
     InvalidDataLength,
     InvalidData,
     InvalidHeaderBits,
+    InvalidQueueName,
 }
 
 #[derive(Debug)]
 pub enum ServerError {
     UnableToStartServer(String),
     ServerNotYetStarted,
+    LogError(String),
+    MessageError(MessageError),
+    /// the queue is at its retention limits and rejects new messages
+    QueueFull,
+    /// the queue has consumers and can't be deleted
+    QueueInUse,
 }
 
 #[derive(Debug)]
@@ -18,6 +27,28 @@ pub enum ClientError {
     StreamNotStarted,
     CantWriteToStream(String),
     CantReadFromStream(String),
+    /// the server can't fulfill the request
     ServerError(String),
     MessageError(MessageError),
+    /// the queue is at its capacity and the pushed message is rejected
+    QueueFull,
+    /// the server can't read the request's header
+    MalformedHeader(String),
+    /// the server rejects the request's body
+    InvalidBody(String),
+    /// the server doesn't know the request's command
+    UnknownCommand(String),
+    /// the server doesn't allow the request
+    Unauthorized(String),
+    /// the server failed while handling the request
+    InternalError(String),
+    /// the response's body is bigger than the client's max frame size, the
+    /// connection can't be used anymore
+    FrameTooLarge(u64),
+    /// the server and the client don't speak a common protocol version
+    UnsupportedVersion(String),
+    /// the server doesn't support an optional feature of the protocol
+    UnsupportedFeature(Feature),
+    /// no reply came back before the call's timeout
+    Timeout,
 }
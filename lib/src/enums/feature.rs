@@ -0,0 +1,10 @@
+/// the optional features of the protocol, each one is a bit of the features
+/// exchanged in the hello
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Feature {
+    /// pushing and pulling many messages in a single request
+    Batch = 0b1,
+    /// sending requests without waiting for the previous responses, the
+    /// requests carry an id their response is matched with
+    Pipelining = 0b10,
+}
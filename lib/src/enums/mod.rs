@@ -1,4 +1,8 @@
 pub mod code;
+pub mod command;
 pub mod errors;
+pub mod feature;
+pub mod overflow;
+pub mod schedule;
+pub mod status;
 pub mod r#type;
-
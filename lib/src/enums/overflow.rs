@@ -0,0 +1,31 @@
+/// what happens to a push when the queue is already at its retention limits
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub enum Overflow {
+    /// the pushed message is rejected
+    #[default]
+    RejectNew,
+    /// the oldest messages are dropped until the pushed message fits
+    DropOldest,
+    /// the push waits until the pulled messages make room for it, it's
+    /// rejected if there's still no room after the server's block timeout
+    Block,
+}
+
+impl Overflow {
+    pub fn to_byte(self) -> u8 {
+        match self {
+            Overflow::RejectNew => 0,
+            Overflow::DropOldest => 1,
+            Overflow::Block => 2,
+        }
+    }
+
+    pub fn from_byte(byte: u8) -> Option<Overflow> {
+        match byte {
+            0 => Some(Overflow::RejectNew),
+            1 => Some(Overflow::DropOldest),
+            2 => Some(Overflow::Block),
+            _ => None,
+        }
+    }
+}
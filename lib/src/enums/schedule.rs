@@ -0,0 +1,55 @@
+use crate::enums::errors::MessageError;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+pub const SCHEDULE_SIZE: usize = 9;
+
+/// when a scheduled message becomes visible to the pullers
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Schedule {
+    /// after a delay, counted from when the server gets the message
+    After(Duration),
+    /// at a point in time, the message is visible right away if it's in the
+    /// past
+    At(SystemTime),
+}
+
+impl Schedule {
+    /// the time the message becomes visible, in milliseconds since the unix
+    /// epoch
+    pub fn deliver_at(&self, now: SystemTime) -> u64 {
+        let at = match self {
+            Schedule::After(delay) => now + *delay,
+            Schedule::At(at) => *at,
+        };
+
+        at.duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64
+    }
+
+    /// the kind (`0` for a delay and `1` for a point in time) followed by the
+    /// delay or the time since the unix epoch, in milliseconds (8 bytes)
+    pub fn serialize(&self) -> [u8; SCHEDULE_SIZE] {
+        let (kind, millis) = match self {
+            Schedule::After(delay) => (0, delay.as_millis() as u64),
+            Schedule::At(_) => (1, self.deliver_at(UNIX_EPOCH)),
+        };
+
+        let mut bytes = [kind; SCHEDULE_SIZE];
+        bytes[1..].copy_from_slice(&millis.to_be_bytes());
+        bytes
+    }
+
+    pub fn deserialize(schedule: &[u8]) -> Result<Schedule, MessageError> {
+        if schedule.len() != SCHEDULE_SIZE {
+            return Err(MessageError::InvalidDataLength);
+        }
+
+        let millis = Duration::from_millis(u64::from_be_bytes(schedule[1..].try_into().unwrap()));
+        match schedule[0] {
+            0 => Ok(Schedule::After(millis)),
+            1 => Ok(Schedule::At(UNIX_EPOCH + millis)),
+            _ => Err(MessageError::InvalidData),
+        }
+    }
+}
@@ -0,0 +1,62 @@
+use crate::enums::errors::ClientError;
+
+/// the first byte of a response's header. The body of a failed response is
+/// an optional human-readable message explaining the failure
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Status {
+    Success = 0,
+    /// the request is valid but the server can't fulfill it
+    Failed = 1,
+    /// the queue is at its capacity, only pushes fail with it
+    QueueFull = 2,
+    /// the request's header can't be read
+    MalformedHeader = 3,
+    /// the request's body (the queue name or the payload) is invalid
+    InvalidBody = 4,
+    /// the request's command isn't known by the server
+    UnknownCommand = 5,
+    /// the client isn't allowed to make the request
+    Unauthorized = 6,
+    /// the server failed while handling the request, like writing the message
+    /// log
+    InternalError = 7,
+    /// the server doesn't speak the client's protocol version
+    UnsupportedVersion = 8,
+}
+
+impl Status {
+    pub fn from_byte(byte: u8) -> Option<Status> {
+        match byte {
+            0 => Some(Status::Success),
+            1 => Some(Status::Failed),
+            2 => Some(Status::QueueFull),
+            3 => Some(Status::MalformedHeader),
+            4 => Some(Status::InvalidBody),
+            5 => Some(Status::UnknownCommand),
+            6 => Some(Status::Unauthorized),
+            7 => Some(Status::InternalError),
+            8 => Some(Status::UnsupportedVersion),
+            _ => None,
+        }
+    }
+
+    /// maps a response's status and body to the client's error, `Ok` if the
+    /// request succeeded. Unknown statuses are mapped to a server error
+    pub fn check(status: u8, body: &[u8]) -> Result<(), ClientError> {
+        let message = String::from_utf8_lossy(body).into_owned();
+        let error = match Status::from_byte(status) {
+            Some(Status::Success) => return Ok(()),
+            Some(Status::Failed) => ClientError::ServerError(message),
+            Some(Status::QueueFull) => ClientError::QueueFull,
+            Some(Status::MalformedHeader) => ClientError::MalformedHeader(message),
+            Some(Status::InvalidBody) => ClientError::InvalidBody(message),
+            Some(Status::UnknownCommand) => ClientError::UnknownCommand(message),
+            Some(Status::Unauthorized) => ClientError::Unauthorized(message),
+            Some(Status::InternalError) => ClientError::InternalError(message),
+            Some(Status::UnsupportedVersion) => ClientError::UnsupportedVersion(message),
+            None => ClientError::ServerError(format!("unknown response status {}", status)),
+        };
+
+        Err(error)
+    }
+}
@@ -4,10 +4,29 @@ pub mod traits;
 
 #[cfg(test)]
 mod tests {
-    use crate::enums::errors::MessageError;
-    use crate::structs::message::Message;
+    use crate::enums::code::Code;
+    use crate::enums::command::Command;
+    use crate::enums::errors::{ClientError, MessageError};
+    use crate::enums::feature::Feature;
+    use crate::enums::overflow::Overflow;
+    use crate::enums::schedule::Schedule;
+    use crate::enums::status::Status;
+    use crate::structs::dead_letter::DeadLetter;
+    use crate::structs::delivery::Delivery;
+    use crate::structs::envelope::Envelope;
+    use crate::structs::hello::{Hello, HELLO_SIZE, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION};
+    use crate::structs::message::{
+        Message, CORRELATION_ID_HEADER, MESSAGE_ID_HEADER, REPLY_TO_HEADER,
+    };
+    use crate::structs::queue_config::{QueueConfig, QUEUE_CONFIG_SIZE};
+    use crate::structs::queue_stats::{QueueStats, QUEUE_STATS_SIZE};
+    use crate::structs::request::{
+        decode_name, decode_names, encode_name, encode_names, tag_frame, untag_body, Request,
+        REQUEST_ID_SIZE,
+    };
     use bytes::Bytes;
     use std::str::FromStr;
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
     #[test]
     fn message_serialize_success() {
@@ -42,6 +61,14 @@ mod tests {
         assert_eq!(res.unwrap_err(), MessageError::InvalidDataLength);
     }
 
+    #[test]
+    fn message_deserialize_too_short() {
+        for len in 0..5 {
+            let res = Message::deserialize(&[0; 5][..len]);
+            assert_eq!(res.unwrap_err(), MessageError::InvalidDataLength);
+        }
+    }
+
     #[test]
     fn message_deserialize_invalid_data() {
         let msg = [0b0000_1010, 0, 0, 0, 1, 1];
@@ -50,6 +77,136 @@ mod tests {
         assert_eq!(res.unwrap_err(), MessageError::InvalidData);
     }
 
+    #[test]
+    fn message_deserialize_empty_str_body() {
+        let res = Message::deserialize(&[0b0000_1010, 0, 0, 0, 0]);
+        assert_eq!(res.unwrap_err(), MessageError::InvalidDataLength);
+
+        // the headers use up every byte
+        let res = Message::deserialize(&[0b0100_1010, 0, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(res.unwrap_err(), MessageError::InvalidDataLength);
+    }
+
+    #[test]
+    fn message_headers_serialize_deserialize_success() {
+        let msg = Message::from_u16_arr(&[1, 127])
+            .with_header("trace-id", "abc")
+            .with_header("content-type", "text/plain");
+        let expected = Bytes::from(
+            [
+                &[0b0100_0001, 0, 0, 0, 2, 0, 0, 0, 2][..],
+                &[0, 0, 0, 12],
+                b"content-type",
+                &[0, 0, 0, 10],
+                b"text/plain",
+                &[0, 0, 0, 8],
+                b"trace-id",
+                &[0, 0, 0, 3],
+                b"abc",
+                &[0, 1, 0, 127],
+            ]
+            .concat(),
+        );
+
+        assert_eq!(expected, msg.serialize());
+
+        let res = Message::deserialize(&msg.serialize()).unwrap();
+        assert_eq!(res, msg);
+        assert_eq!(res.get_header("trace-id"), Some("abc"));
+        assert_eq!(res.get_header("producer-id"), None);
+        assert_eq!(
+            res.get_headers().keys().collect::<Vec<_>>(),
+            ["content-type", "trace-id"]
+        );
+        assert_eq!(res.get_code(), Code::SUCCESS);
+    }
+
+    #[test]
+    fn message_headers_str_body() {
+        let msg = Message::from_str_arr(&["a".to_string()]).with_header("k", "v");
+        let res = Message::deserialize(&msg.serialize()).unwrap();
+        assert_eq!(res, msg);
+        assert_eq!(res.parse_data_to_str().unwrap(), ["a"]);
+
+        // nothing is left for the strings once the headers are read
+        let msg = Message::from_str_arr(&[]).with_header("k", "v");
+        let res = Message::deserialize(&msg.serialize());
+        assert_eq!(res.unwrap_err(), MessageError::InvalidDataLength);
+    }
+
+    #[test]
+    fn message_without_headers_is_unchanged() {
+        let msg = Message::from_u16_arr(&[1, 127])
+            .with_header("trace-id", "abc")
+            .without_header("trace-id");
+        let expected = Bytes::from(vec![0b0000_0001, 0, 0, 0, 2, 0, 1, 0, 127]);
+
+        assert_eq!(expected, msg.serialize());
+        assert!(msg.get_headers().is_empty());
+    }
+
+    #[test]
+    fn message_headers_empty_queue_code() {
+        let msg = Message::empty_message().with_headers([("a", "1")]);
+
+        let res = Message::deserialize(&msg.serialize()).unwrap();
+        assert_eq!(res.get_code(), Code::EMPTY_QUEUE);
+        assert_eq!(res.get_header("a"), Some("1"));
+    }
+
+    #[test]
+    fn message_id_header() {
+        let msg = Message::from_u8_arr(&[1]).with_message_id("m1");
+
+        assert_eq!(msg.get_message_id(), Some("m1"));
+        assert_eq!(msg.get_header(MESSAGE_ID_HEADER), Some("m1"));
+        assert_eq!(Message::from_u8_arr(&[1]).get_message_id(), None);
+    }
+
+    #[test]
+    fn message_reply_headers() {
+        let msg = Message::from_u8_arr(&[1])
+            .with_reply_to("#reply-1")
+            .with_correlation_id("c1");
+
+        assert_eq!(msg.get_reply_to(), Some("#reply-1"));
+        assert_eq!(msg.get_correlation_id(), Some("c1"));
+        assert_eq!(msg.get_header(REPLY_TO_HEADER), Some("#reply-1"));
+        assert_eq!(msg.get_header(CORRELATION_ID_HEADER), Some("c1"));
+    }
+
+    #[test]
+    fn message_deserialize_invalid_headers() {
+        let msg = [0b0100_0001, 0, 0, 0, 1, 0, 0, 0, 1, 0, 0, 0, 9, b'a'];
+
+        let res = Message::deserialize(&msg);
+        assert_eq!(res.unwrap_err(), MessageError::InvalidDataLength);
+
+        let msg = [
+            0b0100_0000,
+            0,
+            0,
+            0,
+            0,
+            0,
+            0,
+            0,
+            1,
+            0,
+            0,
+            0,
+            1,
+            0xFF,
+            0,
+            0,
+            0,
+            0,
+        ];
+
+        let res = Message::deserialize(&msg);
+        assert_eq!(res.unwrap_err(), MessageError::InvalidData);
+    }
+
     #[test]
     fn message_parse_data_to_str_success_from_str_arr() {
         let expected = vec![String::from("Hello, World!"), String::from("Hi, World!")];
@@ -186,4 +343,289 @@ mod tests {
 
         assert_eq!(expected, parsed);
     }
+
+    #[test]
+    fn request_serialize_success() {
+        let msg = Message::from_u8_arr(&[7]);
+        let req = Request::new(Command::Push, "q", msg.serialize()).unwrap();
+        let expected = Bytes::from(vec![0, 0, 0, 0, 0, 0, 0, 0, 8, 1, b'q', 0, 0, 0, 0, 1, 7]);
+
+        assert_eq!(expected, req.serialize());
+    }
+
+    #[test]
+    fn request_deserialize_success() {
+        let body = [1, b'q', 0, 0, 0, 0, 1, 7];
+        let req = Request::deserialize(Command::Push, &body).unwrap();
+
+        assert_eq!(req.get_queue(), "q");
+        assert_eq!(
+            Message::deserialize(req.get_payload()).unwrap(),
+            Message::from_u8_arr(&[7])
+        );
+    }
+
+    #[test]
+    fn request_deserialize_invalid_queue_name() {
+        let body = [5, b'q'];
+
+        let res = Request::deserialize(Command::Pull, &body);
+        assert_eq!(res.unwrap_err(), MessageError::InvalidQueueName);
+    }
+
+    #[test]
+    fn request_new_empty_queue_name() {
+        let res = Request::new(Command::Pull, "", Bytes::new());
+        assert_eq!(res.unwrap_err(), MessageError::InvalidQueueName);
+    }
+
+    #[test]
+    fn delivery_serialize_deserialize_success() {
+        let delivery = Delivery::new(42, 3, Message::from_i16_arr(&[-1, 1]));
+        let serialized = delivery.serialize();
+
+        assert_eq!(serialized[..8], 42_u64.to_be_bytes());
+        assert_eq!(serialized[8..12], 3_u32.to_be_bytes());
+        assert_eq!(Delivery::deserialize(&serialized).unwrap(), delivery);
+    }
+
+    #[test]
+    fn delivery_deserialize_invalid_data_length() {
+        let res = Delivery::deserialize(&[0, 0, 1]);
+        assert_eq!(res.unwrap_err(), MessageError::InvalidDataLength);
+    }
+
+    #[test]
+    fn envelope_serialize_deserialize_success() {
+        let envelope = Envelope::new(Message::from_u32_arr(&[7, 8]), 9, None);
+        let serialized = envelope.serialize();
+
+        assert_eq!(serialized[0], 9);
+        assert_eq!(Envelope::deserialize(&serialized).unwrap(), envelope);
+
+        let envelope = Envelope::new(Message::from_u8_arr(&[1]), 0, Some(Duration::from_secs(3)));
+        let deserialized = Envelope::deserialize(&envelope.serialize()).unwrap();
+        assert_eq!(deserialized.get_ttl(), Some(Duration::from_secs(3)));
+    }
+
+    #[test]
+    fn envelope_deserialize_invalid_data_length() {
+        let res = Envelope::deserialize(&[3, 0, 0]);
+        assert_eq!(res.unwrap_err(), MessageError::InvalidDataLength);
+    }
+
+    #[test]
+    fn queue_config_serialize_deserialize_success() {
+        let config = QueueConfig {
+            ttl: Some(Duration::from_millis(250)),
+            max_length: Some(10),
+            max_bytes: None,
+            overflow: Overflow::DropOldest,
+            dead_letter_expired: true,
+        };
+        let serialized = config.serialize();
+
+        assert_eq!(serialized.len(), QUEUE_CONFIG_SIZE);
+        assert_eq!(QueueConfig::deserialize(&serialized).unwrap(), config);
+        assert_eq!(
+            QueueConfig::deserialize(&QueueConfig::default().serialize()).unwrap(),
+            QueueConfig::default()
+        );
+    }
+
+    #[test]
+    fn queue_config_deserialize_invalid_overflow() {
+        let mut config = QueueConfig::default().serialize().to_vec();
+        config[24] = 9;
+        let res = QueueConfig::deserialize(&config);
+        assert_eq!(res.unwrap_err(), MessageError::InvalidData);
+    }
+
+    #[test]
+    fn queue_stats_serialize_deserialize_success() {
+        let stats = QueueStats {
+            depth: 3,
+            scheduled: 1,
+            bytes: 64,
+            in_flight: 2,
+            dead_letters: 0,
+            consumers: 5,
+        };
+        let serialized = stats.serialize();
+
+        assert_eq!(serialized.len(), QUEUE_STATS_SIZE);
+        assert_eq!(QueueStats::deserialize(&serialized).unwrap(), stats);
+        let res = QueueStats::deserialize(&serialized[1..]);
+        assert_eq!(res.unwrap_err(), MessageError::InvalidDataLength);
+    }
+
+    #[test]
+    fn names_encode_decode_success() {
+        let names = vec![String::from("a"), String::from("topic#group")];
+        let encoded = encode_names(&names).unwrap();
+
+        assert_eq!(decode_names(&encoded).unwrap(), names);
+        assert_eq!(decode_names(&encode_names(&[]).unwrap()).unwrap().len(), 0);
+        let res = decode_names(&[&encoded[..], &[0]].concat());
+        assert_eq!(res.unwrap_err(), MessageError::InvalidDataLength);
+    }
+
+    #[test]
+    fn status_check_success() {
+        assert!(Status::check(Status::Success as u8, &[]).is_ok());
+    }
+
+    #[test]
+    fn status_check_errors() {
+        let res = Status::check(Status::QueueFull as u8, &[]);
+        assert!(matches!(res, Err(ClientError::QueueFull)));
+
+        let res = Status::check(Status::InvalidBody as u8, b"bad name");
+        assert!(matches!(res, Err(ClientError::InvalidBody(m)) if m == "bad name"));
+
+        let res = Status::check(42, &[]);
+        assert!(matches!(res, Err(ClientError::ServerError(_))));
+    }
+
+    #[test]
+    fn hello_serialize_deserialize_success() {
+        let hello = Hello::new(3, 0b101);
+        let serialized = hello.serialize();
+        assert_eq!(serialized.len(), HELLO_SIZE);
+        assert_eq!(serialized[..], [0, 3, 0, 0, 0, 0b101]);
+        assert_eq!(Hello::deserialize(&serialized).unwrap(), hello);
+
+        let res = Hello::deserialize(&serialized[..HELLO_SIZE - 1]);
+        assert_eq!(res.unwrap_err(), MessageError::InvalidDataLength);
+    }
+
+    #[test]
+    fn hello_supports() {
+        assert!(Hello::new(1, Feature::Batch as u32).supports(Feature::Batch));
+        assert!(!Hello::legacy().supports(Feature::Batch));
+    }
+
+    #[test]
+    fn envelope_list_serialize_deserialize_success() {
+        let envelopes = vec![
+            Envelope::new(Message::from_u8_arr(&[1, 2]), 3, None),
+            Envelope::new(
+                Message::from_i32_arr(&[-1]),
+                0,
+                Some(Duration::from_secs(1)),
+            ),
+        ];
+
+        let list = Envelope::serialize_list(&envelopes);
+        assert_eq!(list[..4], 2_u32.to_be_bytes());
+        assert_eq!(Envelope::deserialize_list(&list).unwrap(), envelopes);
+        assert!(Envelope::deserialize_list(&Envelope::serialize_list(&[]))
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn message_list_deserialize_invalid_length() {
+        let messages = vec![Message::from_u8_arr(&[1]), Message::from_u16_arr(&[2, 3])];
+        let list = Message::serialize_list(&messages);
+        assert_eq!(Message::deserialize_list(&list).unwrap(), messages);
+
+        // a truncated list and trailing bytes are both refused
+        let res = Message::deserialize_list(&list[..list.len() - 1]);
+        assert_eq!(res.unwrap_err(), MessageError::InvalidDataLength);
+        let res = Message::deserialize_list(&[&list[..], &[0]].concat());
+        assert_eq!(res.unwrap_err(), MessageError::InvalidDataLength);
+    }
+
+    #[test]
+    fn hello_with_without() {
+        let hello = Hello::legacy().with(Feature::Pipelining);
+        assert!(hello.supports(Feature::Pipelining));
+        assert!(!hello.supports(Feature::Batch));
+        assert_eq!(hello.without(Feature::Pipelining), Hello::legacy());
+    }
+
+    #[test]
+    fn tag_untag_frame() {
+        let request = Request::new(Command::Pull, "q", Bytes::new()).unwrap();
+        let frame = tag_frame(&request.serialize(), 7);
+        assert_eq!(frame[..9], [1, 0, 0, 0, 0, 0, 0, 0, 6]);
+
+        let (id, body) = untag_body(&frame[9..]).unwrap();
+        assert_eq!(id, 7);
+        assert_eq!(Request::deserialize(Command::Pull, body).unwrap(), request);
+
+        let res = untag_body(&[0; REQUEST_ID_SIZE - 1]);
+        assert_eq!(res.unwrap_err(), MessageError::InvalidDataLength);
+    }
+
+    #[test]
+    fn hello_negotiate() {
+        // a newer peer speaks the current version with the common features
+        let peer = Hello::new(PROTOCOL_VERSION + 1, 0b110);
+        let ours = Hello::new(PROTOCOL_VERSION, 0b011);
+        assert_eq!(
+            ours.negotiate(&peer),
+            Some(Hello::new(PROTOCOL_VERSION, 0b010))
+        );
+
+        let peer = Hello::new(MIN_PROTOCOL_VERSION - 1, 0);
+        assert_eq!(Hello::current().negotiate(&peer), None);
+    }
+
+    #[test]
+    fn schedule_serialize_deserialize_success() {
+        let delay = Schedule::After(Duration::from_millis(1500));
+        assert_eq!(Schedule::deserialize(&delay.serialize()).unwrap(), delay);
+
+        let at = Schedule::At(UNIX_EPOCH + Duration::from_millis(1_700_000_000_123));
+        let serialized = at.serialize();
+        assert_eq!(serialized[0], 1);
+        assert_eq!(Schedule::deserialize(&serialized).unwrap(), at);
+        assert_eq!(at.deliver_at(SystemTime::now()), 1_700_000_000_123);
+    }
+
+    #[test]
+    fn schedule_deserialize_invalid_kind() {
+        let res = Schedule::deserialize(&[2, 0, 0, 0, 0, 0, 0, 0, 1]);
+        assert_eq!(res.unwrap_err(), MessageError::InvalidData);
+    }
+
+    #[test]
+    fn dead_letter_serialize_deserialize_list_success() {
+        let dead_letters = vec![
+            DeadLetter::new(1, 5, Message::from_u8_arr(&[1])),
+            DeadLetter::new(7, 6, Message::from_str_arr(&[String::from("dead")])),
+        ];
+
+        let serialized = DeadLetter::serialize_list(&dead_letters);
+        assert_eq!(
+            DeadLetter::deserialize_list(&serialized).unwrap(),
+            dead_letters
+        );
+    }
+
+    #[test]
+    fn dead_letter_deserialize_list_invalid_data_length() {
+        let res = DeadLetter::deserialize_list(&[0, 0, 0, 1, 0, 0]);
+        assert_eq!(res.unwrap_err(), MessageError::InvalidDataLength);
+    }
+
+    #[test]
+    fn name_encode_decode_success() {
+        let encoded = encode_name("topic").unwrap();
+        let bytes = [&encoded[..], &[1, 2]].concat();
+
+        let (name, rest) = decode_name(&bytes).unwrap();
+        assert_eq!(name, "topic");
+        assert_eq!(rest, [1, 2]);
+    }
+
+    #[test]
+    fn name_encode_too_long() {
+        let name = "a".repeat(256);
+
+        let res = encode_name(&name);
+        assert_eq!(res.unwrap_err(), MessageError::InvalidQueueName);
+    }
 }
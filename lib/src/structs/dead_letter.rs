@@ -0,0 +1,71 @@
+use crate::enums::errors::MessageError;
+use crate::structs::helper::list::{deserialize_list, serialize_list};
+use crate::structs::message::Message;
+use bytes::Bytes;
+
+/// a message that has been moved aside after too many delivery attempts
+#[derive(Clone, Debug, PartialEq)]
+pub struct DeadLetter {
+    id: u64,
+    attempts: u32,
+    message: Message,
+}
+
+impl DeadLetter {
+    pub fn new(id: u64, attempts: u32, message: Message) -> Self {
+        DeadLetter {
+            id,
+            attempts,
+            message,
+        }
+    }
+
+    pub fn get_id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn get_attempts(&self) -> u32 {
+        self.attempts
+    }
+
+    pub fn get_message(&self) -> &Message {
+        &self.message
+    }
+
+    /// the id (8 bytes), the delivery attempts (4 bytes) and the message
+    pub fn serialize(&self) -> Bytes {
+        Bytes::from(
+            [
+                &self.id.to_be_bytes()[..],
+                &self.attempts.to_be_bytes(),
+                &self.message.serialize(),
+            ]
+            .concat(),
+        )
+    }
+
+    pub fn deserialize(dead_letter: &[u8]) -> Result<DeadLetter, MessageError> {
+        if dead_letter.len() < 12 {
+            return Err(MessageError::InvalidDataLength);
+        }
+
+        let id = u64::from_be_bytes(dead_letter[..8].try_into().unwrap());
+        let attempts = u32::from_be_bytes(dead_letter[8..12].try_into().unwrap());
+        let message = Message::deserialize(&dead_letter[12..])?;
+
+        Ok(DeadLetter::new(id, attempts, message))
+    }
+
+    /// the number of dead letters (4 bytes) followed by each dead letter's
+    /// size (8 bytes) and the dead letter itself
+    pub fn serialize_list(dead_letters: &[DeadLetter]) -> Bytes {
+        serialize_list(dead_letters.iter().map(DeadLetter::serialize))
+    }
+
+    pub fn deserialize_list(list: &[u8]) -> Result<Vec<DeadLetter>, MessageError> {
+        deserialize_list(list)?
+            .into_iter()
+            .map(DeadLetter::deserialize)
+            .collect()
+    }
+}
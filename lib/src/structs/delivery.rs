@@ -0,0 +1,72 @@
+use crate::enums::errors::MessageError;
+use crate::structs::message::Message;
+use bytes::Bytes;
+
+/// a message handed out by the server that stays in the server until it's
+/// acknowledged, the id is `0` if the queue is empty
+#[derive(Clone, Debug, PartialEq)]
+pub struct Delivery {
+    id: u64,
+    attempts: u32,
+    message: Message,
+}
+
+impl Delivery {
+    pub fn new(id: u64, attempts: u32, message: Message) -> Self {
+        Delivery {
+            id,
+            attempts,
+            message,
+        }
+    }
+
+    pub fn empty_delivery() -> Self {
+        Delivery::new(0, 0, Message::empty_message())
+    }
+
+    pub fn get_id(&self) -> u64 {
+        self.id
+    }
+
+    /// how many times the message has been handed out, this delivery included
+    pub fn get_attempts(&self) -> u32 {
+        self.attempts
+    }
+
+    pub fn get_message(&self) -> &Message {
+        &self.message
+    }
+
+    pub fn into_message(self) -> Message {
+        self.message
+    }
+
+    /// the delivery id (8 bytes) and the attempts (4 bytes) followed by the
+    /// message
+    pub fn serialize(&self) -> Bytes {
+        Bytes::from(
+            [
+                &self.id.to_be_bytes()[..],
+                &self.attempts.to_be_bytes(),
+                &self.message.serialize(),
+            ]
+            .concat(),
+        )
+    }
+
+    pub fn deserialize(delivery: &[u8]) -> Result<Delivery, MessageError> {
+        if delivery.len() < 12 {
+            return Err(MessageError::InvalidDataLength);
+        }
+
+        let id = u64::from_be_bytes(delivery[..8].try_into().unwrap());
+        let attempts = u32::from_be_bytes(delivery[8..12].try_into().unwrap());
+        let message = Message::deserialize(&delivery[12..])?;
+
+        Ok(Delivery {
+            id,
+            attempts,
+            message,
+        })
+    }
+}
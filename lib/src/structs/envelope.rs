@@ -0,0 +1,85 @@
+use crate::enums::errors::MessageError;
+use crate::structs::helper::list::{deserialize_list, serialize_list};
+use crate::structs::message::Message;
+use bytes::Bytes;
+use std::time::Duration;
+
+/// a pushed message along with how the server queues it. Messages with a
+/// higher priority are handed out first, `0` is the lowest priority. Messages
+/// without a time-to-live get the default of their queue
+#[derive(Clone, Debug, PartialEq)]
+pub struct Envelope {
+    priority: u8,
+    ttl: Option<Duration>,
+    message: Message,
+}
+
+impl Envelope {
+    pub fn new(message: Message, priority: u8, ttl: Option<Duration>) -> Self {
+        Envelope {
+            priority,
+            ttl,
+            message,
+        }
+    }
+
+    pub fn get_priority(&self) -> u8 {
+        self.priority
+    }
+
+    pub fn get_ttl(&self) -> Option<Duration> {
+        self.ttl
+    }
+
+    pub fn get_message(&self) -> &Message {
+        &self.message
+    }
+
+    pub fn into_message(self) -> Message {
+        self.message
+    }
+
+    /// the priority (1 byte) and the ttl in milliseconds (8 bytes, `0` when
+    /// there's none) followed by the message
+    pub fn serialize(&self) -> Bytes {
+        let ttl = self.ttl.map_or(0, |ttl| ttl.as_millis() as u64);
+        Bytes::from(
+            [
+                &[self.priority][..],
+                &ttl.to_be_bytes(),
+                &self.message.serialize(),
+            ]
+            .concat(),
+        )
+    }
+
+    pub fn deserialize(envelope: &[u8]) -> Result<Envelope, MessageError> {
+        // the priority, the ttl and the message's metadata
+        if envelope.len() < 14 {
+            return Err(MessageError::InvalidDataLength);
+        }
+
+        let priority = envelope[0];
+        let ttl = u64::from_be_bytes(envelope[1..9].try_into().unwrap());
+        let message = Message::deserialize(&envelope[9..])?;
+
+        Ok(Envelope {
+            priority,
+            ttl: (ttl > 0).then(|| Duration::from_millis(ttl)),
+            message,
+        })
+    }
+
+    /// the number of envelopes (4 bytes) followed by each envelope's size (8
+    /// bytes) and the envelope itself
+    pub fn serialize_list(envelopes: &[Envelope]) -> Bytes {
+        serialize_list(envelopes.iter().map(Envelope::serialize))
+    }
+
+    pub fn deserialize_list(list: &[u8]) -> Result<Vec<Envelope>, MessageError> {
+        deserialize_list(list)?
+            .into_iter()
+            .map(Envelope::deserialize)
+            .collect()
+    }
+}
@@ -0,0 +1,99 @@
+use crate::enums::errors::MessageError;
+use crate::enums::feature::Feature;
+use bytes::Bytes;
+
+pub const HELLO_SIZE: usize = 6;
+
+/// the version of the protocol spoken by this crate
+pub const PROTOCOL_VERSION: u16 = 1;
+
+/// the oldest version of the protocol still spoken by this crate
+pub const MIN_PROTOCOL_VERSION: u16 = 1;
+
+/// the optional features supported by this crate, one bit per feature
+pub const FEATURES: u32 = Feature::Batch as u32 | Feature::Pipelining as u32;
+
+/// the protocol version and the optional features of a peer, exchanged when
+/// the client connects. The peers speak the oldest of their versions and use
+/// the features they both support
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Hello {
+    version: u16,
+    features: u32,
+}
+
+impl Hello {
+    pub fn new(version: u16, features: u32) -> Self {
+        Hello { version, features }
+    }
+
+    /// the hello of this crate
+    pub fn current() -> Self {
+        Hello::new(PROTOCOL_VERSION, FEATURES)
+    }
+
+    /// the hello of a peer that doesn't know the handshake, it speaks the
+    /// first version without any feature
+    pub fn legacy() -> Self {
+        Hello::new(1, 0)
+    }
+
+    pub fn get_version(&self) -> u16 {
+        self.version
+    }
+
+    pub fn get_features(&self) -> u32 {
+        self.features
+    }
+
+    pub fn supports(&self, feature: Feature) -> bool {
+        self.features & feature as u32 != 0
+    }
+
+    /// the same hello with a feature added
+    pub fn with(self, feature: Feature) -> Self {
+        Hello::new(self.version, self.features | feature as u32)
+    }
+
+    /// the same hello with a feature left out
+    pub fn without(self, feature: Feature) -> Self {
+        Hello::new(self.version, self.features & !(feature as u32))
+    }
+
+    /// whether the version is spoken by this crate
+    pub fn is_supported(&self) -> bool {
+        (MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&self.version)
+    }
+
+    /// the version and the features spoken with a peer, `None` if the peer's
+    /// version is too old
+    pub fn negotiate(&self, peer: &Hello) -> Option<Hello> {
+        let hello = Hello::new(
+            self.version.min(peer.version),
+            self.features & peer.features,
+        );
+        hello.is_supported().then_some(hello)
+    }
+
+    /// the version (2 bytes) followed by the features (4 bytes)
+    pub fn serialize(&self) -> Bytes {
+        Bytes::from(
+            [
+                &self.version.to_be_bytes()[..],
+                &self.features.to_be_bytes(),
+            ]
+            .concat(),
+        )
+    }
+
+    pub fn deserialize(hello: &[u8]) -> Result<Hello, MessageError> {
+        if hello.len() != HELLO_SIZE {
+            return Err(MessageError::InvalidDataLength);
+        }
+
+        Ok(Hello::new(
+            u16::from_be_bytes([hello[0], hello[1]]),
+            u32::from_be_bytes(hello[2..].try_into().unwrap()),
+        ))
+    }
+}
@@ -0,0 +1,48 @@
+use crate::enums::errors::MessageError;
+use bytes::Bytes;
+
+/// the number of items (4 bytes) followed by each item's size (8 bytes) and
+/// the item itself
+pub(in super::super) fn serialize_list(items: impl ExactSizeIterator<Item = Bytes>) -> Bytes {
+    let mut list = (items.len() as u32).to_be_bytes().to_vec();
+
+    for item in items {
+        list.extend_from_slice(&(item.len() as u64).to_be_bytes());
+        list.extend_from_slice(&item);
+    }
+
+    Bytes::from(list)
+}
+
+/// splits a list back into its serialized items
+pub(in super::super) fn deserialize_list(list: &[u8]) -> Result<Vec<&[u8]>, MessageError> {
+    if list.len() < 4 {
+        return Err(MessageError::InvalidDataLength);
+    }
+
+    let count = u32::from_be_bytes(list[..4].try_into().unwrap());
+    let mut items = vec![];
+    let mut offset = 4;
+
+    for _ in 0..count {
+        if list.len() < offset + 8 {
+            return Err(MessageError::InvalidDataLength);
+        }
+
+        let size = u64::from_be_bytes(list[offset..offset + 8].try_into().unwrap());
+        offset += 8;
+
+        let end = match offset.checked_add(size as usize) {
+            Some(end) if end <= list.len() => end,
+            _ => return Err(MessageError::InvalidDataLength),
+        };
+        items.push(&list[offset..end]);
+        offset = end;
+    }
+
+    if offset != list.len() {
+        return Err(MessageError::InvalidDataLength);
+    }
+
+    Ok(items)
+}
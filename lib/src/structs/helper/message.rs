@@ -2,6 +2,11 @@ use crate::enums::code::Code;
 use crate::enums::errors::MessageError;
 use crate::enums::r#type::Type;
 use crate::structs::message::{Message, Metadata};
+use bytes::Bytes;
+use std::collections::BTreeMap;
+
+/// set in the code nibble when the metadata is followed by headers
+pub(in super::super) const HEADERS_FLAG: u8 = 0b0100 << 4;
 
 impl Eq for Message {}
 
@@ -112,7 +117,7 @@ macro_rules! bytes_to_num {
     };
 }
 
-pub(in super::super) fn parse_num(data: &Vec<u8>, ty: Type) -> Vec<Type> {
+pub(in super::super) fn parse_num(data: &[u8], ty: Type) -> Vec<Type> {
     let size: usize = ty.get_size();
     let mut ret_val: Vec<Type> = vec![];
 
@@ -144,7 +149,7 @@ pub(in super::super) fn parse_num(data: &Vec<u8>, ty: Type) -> Vec<Type> {
 pub(in super::super) fn validate_header(header: &[u8]) -> Result<(), MessageError> {
     let first_byte = header[0];
 
-    let first_nibble = first_byte >> 4;
+    let first_nibble = (first_byte & !HEADERS_FLAG) >> 4;
     if first_nibble != 0b0000 && first_nibble != 0b0010 {
         return Err(MessageError::InvalidHeaderBits);
     }
@@ -157,6 +162,59 @@ pub(in super::super) fn validate_header(header: &[u8]) -> Result<(), MessageErro
     Ok(())
 }
 
+/// the number of headers (4 bytes) followed by each key's and value's size (4
+/// bytes) and the string itself, empty when there's no header
+pub(in super::super) fn serialize_headers(headers: &BTreeMap<String, String>) -> Bytes {
+    if headers.is_empty() {
+        return Bytes::new();
+    }
+
+    let mut bytes = (headers.len() as u32).to_be_bytes().to_vec();
+    for (key, value) in headers {
+        for string in [key, value] {
+            bytes.extend_from_slice(&(string.len() as u32).to_be_bytes());
+            bytes.extend_from_slice(string.as_bytes());
+        }
+    }
+
+    Bytes::from(bytes)
+}
+
+/// splits the headers from the data following them
+pub(in super::super) fn deserialize_headers(
+    bytes: &[u8],
+) -> Result<(BTreeMap<String, String>, &[u8]), MessageError> {
+    fn take(bytes: &[u8], len: usize) -> Result<(&[u8], &[u8]), MessageError> {
+        if bytes.len() < len {
+            return Err(MessageError::InvalidDataLength);
+        }
+        Ok(bytes.split_at(len))
+    }
+
+    fn take_string(bytes: &[u8]) -> Result<(String, &[u8]), MessageError> {
+        let (len, bytes) = take(bytes, 4)?;
+        let len = u32::from_be_bytes(len.try_into().unwrap()) as usize;
+        let (string, bytes) = take(bytes, len)?;
+        match String::from_utf8(string.to_vec()) {
+            Ok(string) => Ok((string, bytes)),
+            Err(_) => Err(MessageError::InvalidData),
+        }
+    }
+
+    let (count, mut bytes) = take(bytes, 4)?;
+    let count = u32::from_be_bytes(count.try_into().unwrap());
+    let mut headers = BTreeMap::new();
+
+    for _ in 0..count {
+        let (key, rest) = take_string(bytes)?;
+        let (value, rest) = take_string(rest)?;
+        headers.insert(key, value);
+        bytes = rest;
+    }
+
+    Ok((headers, bytes))
+}
+
 pub(in super::super) fn validate_body(
     body: &[u8],
     len: usize,
@@ -165,6 +223,10 @@ pub(in super::super) fn validate_body(
     let size = ty.get_size();
 
     if size == 0 {
+        // the strings end with a nul byte, there's at least one
+        if body.is_empty() {
+            return Err(MessageError::InvalidDataLength);
+        }
         return if body[body.len() - 1] == 0 {
             Ok(())
         } else {
@@ -172,9 +234,9 @@ pub(in super::super) fn validate_body(
         };
     }
 
-    let expected_size = len * size;
-    if expected_size > u64::max as usize || expected_size != body.len() {
-        return Err(MessageError::InvalidDataLength);
+    match len.checked_mul(size) {
+        Some(expected_size) if expected_size == body.len() => (),
+        _ => return Err(MessageError::InvalidDataLength),
     }
 
     Ok(())
@@ -201,6 +263,7 @@ macro_rules! generate_constructor_from_number {
                     code: Code::SUCCESS,
                     size: data.len(),
                 },
+                headers: BTreeMap::new(),
                 data: Arc::new(msg_data),
             }
         })+
@@ -1,18 +1,36 @@
 use crate::enums::code::Code;
 use crate::enums::errors::MessageError;
 use crate::enums::r#type::Type;
+use crate::structs::helper::list::{deserialize_list, serialize_list};
 use crate::structs::helper::message::*;
 use bytes::Bytes;
+use std::collections::BTreeMap;
 use std::str::FromStr;
 use std::sync::Arc;
 
-#[derive(Debug, PartialEq)]
+/// the header carrying the id a producer gives to its message, the server doesn't enqueue a
+/// message again if its id has been pushed to the queue recently
+pub const MESSAGE_ID_HEADER: &str = "message-id";
+
+/// the header carrying the group of a message, the messages of a group are handed out one at a
+/// time in the order they're pushed
+pub const MESSAGE_GROUP_HEADER: &str = "message-group";
+
+/// the header naming the queue a request's reply is pushed to
+pub const REPLY_TO_HEADER: &str = "reply-to";
+
+/// the header tying a reply to its request, the reply carries the request's
+pub const CORRELATION_ID_HEADER: &str = "correlation-id";
+
+#[derive(Clone, Debug, PartialEq)]
 pub struct Message {
     metadata: Metadata,
+    /// the optional headers, only serialized when there's at least one
+    headers: BTreeMap<String, String>,
     data: Arc<Vec<u8>>,
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq)]
 pub(super) struct Metadata {
     r#type: Type,
     code: Code,
@@ -36,12 +54,78 @@ impl Message {
         Arc::clone(&self.data)
     }
 
+    pub fn get_header(&self, key: &str) -> Option<&str> {
+        self.headers.get(key).map(String::as_str)
+    }
+
+    pub fn get_headers(&self) -> &BTreeMap<String, String> {
+        &self.headers
+    }
+
+    /// sets a header, replacing the previous value of the key
+    pub fn with_header(mut self, key: &str, value: &str) -> Self {
+        self.headers.insert(key.to_string(), value.to_string());
+        self
+    }
+
+    pub fn with_headers<K, V>(mut self, headers: impl IntoIterator<Item = (K, V)>) -> Self
+    where
+        K: Into<String>,
+        V: Into<String>,
+    {
+        self.headers
+            .extend(headers.into_iter().map(|(k, v)| (k.into(), v.into())));
+        self
+    }
+
+    pub fn without_header(mut self, key: &str) -> Self {
+        self.headers.remove(key);
+        self
+    }
+
+    pub fn get_message_id(&self) -> Option<&str> {
+        self.get_header(MESSAGE_ID_HEADER)
+    }
+
+    /// sets the id used to drop the duplicates of the message, see `MESSAGE_ID_HEADER`
+    pub fn with_message_id(self, id: &str) -> Self {
+        self.with_header(MESSAGE_ID_HEADER, id)
+    }
+
+    pub fn get_message_group(&self) -> Option<&str> {
+        self.get_header(MESSAGE_GROUP_HEADER)
+    }
+
+    /// puts the message in a group, see `MESSAGE_GROUP_HEADER`
+    pub fn with_message_group(self, group: &str) -> Self {
+        self.with_header(MESSAGE_GROUP_HEADER, group)
+    }
+
+    pub fn get_reply_to(&self) -> Option<&str> {
+        self.get_header(REPLY_TO_HEADER)
+    }
+
+    pub fn with_reply_to(self, queue: &str) -> Self {
+        self.with_header(REPLY_TO_HEADER, queue)
+    }
+
+    pub fn get_correlation_id(&self) -> Option<&str> {
+        self.get_header(CORRELATION_ID_HEADER)
+    }
+
+    pub fn with_correlation_id(self, id: &str) -> Self {
+        self.with_header(CORRELATION_ID_HEADER, id)
+    }
+
     pub fn serialize(&self) -> Bytes {
         let metadata = {
             let metadata = &self.metadata;
             let code = map_code_to_nibble(&metadata.code);
             let ty = map_type_to_nibble(&metadata.r#type);
-            let first_byte = code + ty;
+            let mut first_byte = code + ty;
+            if !self.headers.is_empty() {
+                first_byte |= HEADERS_FLAG;
+            }
 
             let size = metadata.size;
             let size_first_byte = (size & 0xFF00_0000) as u8;
@@ -58,14 +142,19 @@ impl Message {
             ])
         };
 
-        Bytes::from([metadata, Bytes::from((*self.data).clone())].concat())
+        let headers = serialize_headers(&self.headers);
+
+        Bytes::from([metadata, headers, Bytes::from((*self.data).clone())].concat())
     }
 
     pub fn deserialize(message: &[u8]) -> Result<Message, MessageError> {
         // header
+        if message.len() < 5 {
+            return Err(MessageError::InvalidDataLength);
+        }
         validate_header(&message[..5])?;
         let first_byte = message[0];
-        let code = map_nibble_to_code(first_byte & 0xF0);
+        let code = map_nibble_to_code(first_byte & 0xF0 & !HEADERS_FLAG);
         let r#type = map_nibble_to_type(first_byte & 0x0F);
         let size_bytes = message[1..5].to_vec();
         let size: usize = ((size_bytes[0] as usize) << 24)
@@ -73,17 +162,37 @@ impl Message {
             + ((size_bytes[2] as usize) << 8)
             + (size_bytes[3] as usize);
 
+        // headers
+        let (headers, body) = if first_byte & HEADERS_FLAG != 0 {
+            deserialize_headers(&message[5..])?
+        } else {
+            (BTreeMap::new(), &message[5..])
+        };
+
         // body
-        validate_body(&message[5..], size, &r#type)?;
-        let data = message[5..].to_vec();
-        let data = Arc::new(data);
+        validate_body(body, size, &r#type)?;
+        let data = Arc::new(body.to_vec());
 
         Ok(Message {
             metadata: Metadata { r#type, code, size },
+            headers,
             data,
         })
     }
 
+    /// the number of messages (4 bytes) followed by each message's size (8
+    /// bytes) and the message itself
+    pub fn serialize_list(messages: &[Message]) -> Bytes {
+        serialize_list(messages.iter().map(Message::serialize))
+    }
+
+    pub fn deserialize_list(list: &[u8]) -> Result<Vec<Message>, MessageError> {
+        deserialize_list(list)?
+            .into_iter()
+            .map(Message::deserialize)
+            .collect()
+    }
+
     pub fn validate(&self) -> Result<(), MessageError> {
         validate_body(
             &self.data.clone(),
@@ -106,6 +215,7 @@ impl FromStr for Message {
                 code: Code::SUCCESS,
                 size: 1,
             },
+            headers: BTreeMap::new(),
             data: Arc::new(data),
         })
     }
@@ -127,6 +237,7 @@ impl Message {
                 code: Code::SUCCESS,
                 size: data.len(),
             },
+            headers: BTreeMap::new(),
             data: Arc::new(msg_data.concat()),
         }
     }
@@ -152,6 +263,7 @@ impl Message {
                 code: Code::EMPTY_QUEUE,
                 size: 0,
             },
+            headers: BTreeMap::new(),
             data: Arc::new(vec![]),
         }
     }
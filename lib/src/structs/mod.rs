@@ -1,3 +1,9 @@
+pub mod dead_letter;
+pub mod delivery;
+pub mod envelope;
+pub mod hello;
 mod helper;
 pub mod message;
-
+pub mod queue_config;
+pub mod queue_stats;
+pub mod request;
@@ -0,0 +1,65 @@
+use crate::enums::errors::MessageError;
+use crate::enums::overflow::Overflow;
+use bytes::Bytes;
+use std::time::Duration;
+
+pub const QUEUE_CONFIG_SIZE: usize = 26;
+
+/// how long a queue keeps its messages, the limits are off when they're
+/// `None`. The in-flight messages and the dead letters don't count towards
+/// the limits
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct QueueConfig {
+    /// the time-to-live of the messages pushed without their own
+    pub ttl: Option<Duration>,
+    /// how many messages the queue keeps, its capacity
+    pub max_length: Option<u64>,
+    /// how many bytes of message data the queue keeps
+    pub max_bytes: Option<u64>,
+    /// what happens to a push once a limit is reached
+    pub overflow: Overflow,
+    /// whether the expired messages are moved to the dead letters instead of
+    /// being dropped
+    pub dead_letter_expired: bool,
+}
+
+impl QueueConfig {
+    /// the ttl in milliseconds, the max length and the max bytes (8 bytes
+    /// each, `0` when they're off) followed by the overflow policy and whether
+    /// the expired messages are dead-lettered (1 byte each)
+    pub fn serialize(&self) -> Bytes {
+        let ttl = self.ttl.map_or(0, |ttl| ttl.as_millis() as u64);
+        Bytes::from(
+            [
+                &ttl.to_be_bytes()[..],
+                &self.max_length.unwrap_or(0).to_be_bytes(),
+                &self.max_bytes.unwrap_or(0).to_be_bytes(),
+                &[self.overflow.to_byte(), self.dead_letter_expired as u8],
+            ]
+            .concat(),
+        )
+    }
+
+    pub fn deserialize(config: &[u8]) -> Result<QueueConfig, MessageError> {
+        if config.len() != QUEUE_CONFIG_SIZE {
+            return Err(MessageError::InvalidDataLength);
+        }
+
+        let limit = |i: usize| {
+            let value = u64::from_be_bytes(config[i..i + 8].try_into().unwrap());
+            (value > 0).then_some(value)
+        };
+        let overflow = match Overflow::from_byte(config[24]) {
+            Some(overflow) => overflow,
+            None => return Err(MessageError::InvalidData),
+        };
+
+        Ok(QueueConfig {
+            ttl: limit(0).map(Duration::from_millis),
+            max_length: limit(8),
+            max_bytes: limit(16),
+            overflow,
+            dead_letter_expired: config[25] != 0,
+        })
+    }
+}
@@ -0,0 +1,56 @@
+use crate::enums::errors::MessageError;
+use bytes::Bytes;
+
+pub const QUEUE_STATS_SIZE: usize = 44;
+
+/// what a queue holds at the moment it's asked
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct QueueStats {
+    /// how many messages are ready to be handed out
+    pub depth: u64,
+    /// how many messages wait for their schedule
+    pub scheduled: u64,
+    /// how many bytes of message data the ready and the scheduled messages
+    /// take
+    pub bytes: u64,
+    /// how many messages are reserved and not acknowledged yet
+    pub in_flight: u64,
+    pub dead_letters: u64,
+    /// how many subscriptions stream the queue
+    pub consumers: u32,
+}
+
+impl QueueStats {
+    /// the depth, the scheduled messages, the bytes, the in-flight messages
+    /// and the dead letters (8 bytes each) followed by the consumers (4 bytes)
+    pub fn serialize(&self) -> Bytes {
+        Bytes::from(
+            [
+                &self.depth.to_be_bytes()[..],
+                &self.scheduled.to_be_bytes(),
+                &self.bytes.to_be_bytes(),
+                &self.in_flight.to_be_bytes(),
+                &self.dead_letters.to_be_bytes(),
+                &self.consumers.to_be_bytes(),
+            ]
+            .concat(),
+        )
+    }
+
+    pub fn deserialize(stats: &[u8]) -> Result<QueueStats, MessageError> {
+        if stats.len() != QUEUE_STATS_SIZE {
+            return Err(MessageError::InvalidDataLength);
+        }
+
+        let count = |i: usize| u64::from_be_bytes(stats[i..i + 8].try_into().unwrap());
+
+        Ok(QueueStats {
+            depth: count(0),
+            scheduled: count(8),
+            bytes: count(16),
+            in_flight: count(24),
+            dead_letters: count(32),
+            consumers: u32::from_be_bytes(stats[40..].try_into().unwrap()),
+        })
+    }
+}
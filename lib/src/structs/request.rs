@@ -0,0 +1,167 @@
+use crate::enums::command::Command;
+use crate::enums::errors::MessageError;
+use bytes::Bytes;
+
+/// every request and response starts with a 9 bytes header, 1 byte for the
+/// command (or the response status) and 8 bytes for the body size
+pub const HEADER_SIZE: usize = 9;
+
+/// the largest body accepted by default, bigger frames are refused before
+/// they're read
+pub const DEFAULT_MAX_FRAME_SIZE: u64 = 16 * 1024 * 1024;
+
+/// once pipelining is negotiated, every body (except the disconnect's) starts
+/// with a 4 bytes request id. The id `0` is kept for the responses that don't
+/// answer a request, like refusing a frame that's too large
+pub const REQUEST_ID_SIZE: usize = 4;
+
+#[derive(Debug, PartialEq)]
+pub struct Request {
+    command: Command,
+    queue: String,
+    payload: Bytes,
+}
+
+impl Request {
+    /// queue names must be between 1 and 255 bytes long
+    pub fn new(command: Command, queue: &str, payload: Bytes) -> Result<Self, MessageError> {
+        validate_name(queue)?;
+
+        Ok(Request {
+            command,
+            queue: queue.to_string(),
+            payload,
+        })
+    }
+
+    pub fn get_command(&self) -> Command {
+        self.command
+    }
+
+    pub fn get_queue(&self) -> &str {
+        &self.queue
+    }
+
+    pub fn get_payload(&self) -> &Bytes {
+        &self.payload
+    }
+
+    /// serializes the request to a whole frame, header included
+    pub fn serialize(&self) -> Bytes {
+        let queue = self.queue.as_bytes();
+        let body_size = 1 + queue.len() + self.payload.len();
+
+        Bytes::from(
+            [
+                &[self.command as u8][..],
+                &(body_size as u64).to_be_bytes(),
+                &[queue.len() as u8],
+                queue,
+                &self.payload,
+            ]
+            .concat(),
+        )
+    }
+
+    /// deserializes the body of a request, the command comes from the header
+    pub fn deserialize(command: Command, body: &[u8]) -> Result<Request, MessageError> {
+        let (queue, payload) = decode_name(body)?;
+
+        Request::new(command, &queue, Bytes::copy_from_slice(payload))
+    }
+}
+
+/// puts a request id in front of the body of a whole frame
+pub fn tag_frame(frame: &[u8], id: u32) -> Bytes {
+    let size = u64::from_be_bytes(frame[1..HEADER_SIZE].try_into().unwrap());
+
+    Bytes::from(
+        [
+            &frame[..1],
+            &(size + REQUEST_ID_SIZE as u64).to_be_bytes(),
+            &id.to_be_bytes(),
+            &frame[HEADER_SIZE..],
+        ]
+        .concat(),
+    )
+}
+
+/// splits the request id off the body of a frame, returns the id and the rest
+/// of the body
+pub fn untag_body(body: &[u8]) -> Result<(u32, &[u8]), MessageError> {
+    if body.len() < REQUEST_ID_SIZE {
+        return Err(MessageError::InvalidDataLength);
+    }
+
+    let id = u32::from_be_bytes(body[..REQUEST_ID_SIZE].try_into().unwrap());
+    Ok((id, &body[REQUEST_ID_SIZE..]))
+}
+
+fn validate_name(name: &str) -> Result<(), MessageError> {
+    if name.is_empty() || name.len() > u8::MAX as usize {
+        return Err(MessageError::InvalidQueueName);
+    }
+
+    Ok(())
+}
+
+/// encodes a queue (or topic) name as its size (1 byte) followed by the name
+pub fn encode_name(name: &str) -> Result<Bytes, MessageError> {
+    validate_name(name)?;
+
+    Ok(Bytes::from(
+        [&[name.len() as u8][..], name.as_bytes()].concat(),
+    ))
+}
+
+/// decodes a queue (or topic) name, returns the name and the rest of the bytes
+pub fn decode_name(bytes: &[u8]) -> Result<(String, &[u8]), MessageError> {
+    if bytes.is_empty() {
+        return Err(MessageError::InvalidQueueName);
+    }
+
+    let name_size = bytes[0] as usize;
+    if bytes.len() < 1 + name_size {
+        return Err(MessageError::InvalidQueueName);
+    }
+
+    let name = match std::str::from_utf8(&bytes[1..1 + name_size]) {
+        Ok(name) => name.to_string(),
+        Err(_) => return Err(MessageError::InvalidQueueName),
+    };
+    validate_name(&name)?;
+
+    Ok((name, &bytes[1 + name_size..]))
+}
+
+/// encodes a list of names as the number of names (4 bytes) followed by each
+/// encoded name
+pub fn encode_names(names: &[String]) -> Result<Bytes, MessageError> {
+    let mut bytes = (names.len() as u32).to_be_bytes().to_vec();
+    for name in names {
+        bytes.extend_from_slice(&encode_name(name)?);
+    }
+
+    Ok(Bytes::from(bytes))
+}
+
+pub fn decode_names(bytes: &[u8]) -> Result<Vec<String>, MessageError> {
+    if bytes.len() < 4 {
+        return Err(MessageError::InvalidDataLength);
+    }
+
+    let count = u32::from_be_bytes(bytes[..4].try_into().unwrap());
+    let mut rest = &bytes[4..];
+    let mut names = vec![];
+    for _ in 0..count {
+        let (name, next) = decode_name(rest)?;
+        names.push(name);
+        rest = next;
+    }
+
+    if !rest.is_empty() {
+        return Err(MessageError::InvalidDataLength);
+    }
+
+    Ok(names)
+}
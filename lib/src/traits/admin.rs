@@ -0,0 +1,28 @@
+use crate::enums::errors::ClientError;
+use crate::structs::message::Message;
+use crate::structs::queue_stats::QueueStats;
+
+/// looks inside the server and cleans its queues up, meant for the operators
+/// rather than the producers and the consumers
+pub trait Admin {
+    /// lists the names of the server's queues, sorted
+    fn list_queues(&mut self) -> Result<Vec<String>, ClientError>;
+
+    /// counts the messages and the consumers of a queue, `None` if there's no
+    /// such queue
+    fn queue_stats(&mut self, queue: &str) -> Result<Option<QueueStats>, ClientError>;
+
+    /// gets up to `max` of the next messages of a queue without removing
+    /// them, in the order they're handed out
+    fn peek(&mut self, queue: &str, max: u32) -> Result<Vec<Message>, ClientError>;
+
+    /// removes the ready and the scheduled messages of a queue, the reserved
+    /// messages and the dead letters are kept. Returns how many messages are
+    /// removed
+    fn purge(&mut self, queue: &str) -> Result<usize, ClientError>;
+
+    /// removes a queue along with every message it holds and its topic
+    /// subscriptions, returns `false` if there's no such queue. It fails while
+    /// a subscription streams the queue
+    fn delete_queue(&mut self, queue: &str) -> Result<bool, ClientError>;
+}
@@ -1,16 +1,162 @@
 use crate::enums::errors::ClientError;
+use crate::enums::schedule::Schedule;
+use crate::structs::dead_letter::DeadLetter;
+use crate::structs::delivery::Delivery;
+use crate::structs::hello::Hello;
 use crate::structs::message::Message;
+use crate::structs::queue_config::QueueConfig;
+use crate::traits::pipeline::Pipeline;
+use std::time::Duration;
 
 pub trait Client {
-    /// a method to connect the client to the server
+    /// a method to connect the client to the server, the protocol version and
+    /// the features are negotiated with a hello. Fails with
+    /// `UnsupportedVersion` if the server doesn't speak the client's version
     fn connect(&mut self, host: &str, port: u16) -> Result<(), ClientError>;
 
+    /// the protocol version and the features negotiated with the server,
+    /// `None` if the client isn't connected
+    fn hello(&self) -> Option<&Hello>;
+
+    /// starts pipelining the requests on the connection, the client can't be
+    /// used until the pipeline is dropped. Fails with `UnsupportedFeature` if
+    /// the server doesn't support pipelining
+    fn pipeline(&mut self) -> Result<Box<dyn Pipeline + '_>, ClientError>;
+
     /// a method to disconnect the client from the server
     fn disconnect(&mut self) -> Result<(), ClientError>;
 
-    /// pushes message to the server's queue
-    fn push(&mut self, message: &Message) -> Result<bool, ClientError>;
+    /// creates a named queue in the server with its ttl and retention limits,
+    /// returns `false` if the queue already exists
+    fn create_queue(&mut self, queue: &str, config: &QueueConfig) -> Result<bool, ClientError>;
+
+    /// pushes message to one of the server's queues, the queue is created if
+    /// it doesn't exist. Messages with a higher priority are pulled first, the
+    /// message is never pulled after its ttl (or its queue's) runs out. Fails
+    /// with `QueueFull` if the queue is at its capacity, a queue that blocks
+    /// its producers answers once there's room or its block timeout expires
+    fn push(
+        &mut self,
+        queue: &str,
+        message: &Message,
+        priority: u8,
+        ttl: Option<Duration>,
+    ) -> Result<(), ClientError>;
+
+    /// pushes messages to one of the server's queues in a single request, each
+    /// message gets its own result. The batch isn't blocked by a full queue,
+    /// the messages that don't fit fail with `QueueFull`. The messages are
+    /// pushed one by one if the server doesn't support batches
+    fn push_batch(
+        &mut self,
+        queue: &str,
+        messages: &[Message],
+        priority: u8,
+        ttl: Option<Duration>,
+    ) -> Result<Vec<Result<(), ClientError>>, ClientError>;
+
+    /// pushes a message that's only visible to the pullers once its schedule
+    /// is due, the message is pulled along with the pushed messages after that.
+    /// The ttl is counted from when the message is due
+    fn push_scheduled(
+        &mut self,
+        queue: &str,
+        message: &Message,
+        priority: u8,
+        ttl: Option<Duration>,
+        schedule: Schedule,
+    ) -> Result<(), ClientError>;
+
+    /// pulls a message from one of the server's queues
+    fn pull(&mut self, queue: &str) -> Result<Message, ClientError>;
+
+    /// pulls up to `max` messages from one of the server's queues in a single
+    /// request, the list is empty if the queue is
+    fn pull_batch(&mut self, queue: &str, max: u32) -> Result<Vec<Message>, ClientError>;
+
+    /// pulls a message from one of the server's queues, the server waits until
+    /// a message is pushed if the queue is empty. The message is empty if the
+    /// timeout expires first
+    fn pull_wait(&mut self, queue: &str, timeout: Duration) -> Result<Message, ClientError>;
+
+    /// subscribes to one of the server's queues, the server streams the
+    /// queue's messages as they are pushed. At most `prefetch` (at least 1)
+    /// messages are streamed ahead of the consumer. The subscription ends when
+    /// the iterator is dropped
+    fn subscribe(
+        &mut self,
+        queue: &str,
+        prefetch: u32,
+    ) -> Result<Box<dyn Iterator<Item = Result<Message, ClientError>> + '_>, ClientError>;
+
+    /// pulls a message from one of the server's queues, the message is kept in
+    /// the server until it's acknowledged. The message is handed out again if
+    /// it's not acknowledged before the visibility timeout (or the server's
+    /// default) expires
+    fn reserve(&mut self, queue: &str, timeout: Option<Duration>) -> Result<Delivery, ClientError>;
+
+    /// acknowledges a reserved message so the server removes it, returns
+    /// `false` if the delivery is unknown or has expired
+    fn ack(&mut self, queue: &str, delivery_id: u64) -> Result<bool, ClientError>;
+
+    /// puts a reserved message back to the head of the queue, returns `false`
+    /// if the delivery is unknown or has expired
+    fn nack(&mut self, queue: &str, delivery_id: u64) -> Result<bool, ClientError>;
+
+    /// lists the messages of a queue that have been dead-lettered after too
+    /// many delivery attempts
+    fn dead_letters(&mut self, queue: &str) -> Result<Vec<DeadLetter>, ClientError>;
+
+    /// gets a dead-lettered message of a queue by its id, the message is empty
+    /// if there's no such dead letter
+    fn inspect_dead_letter(&mut self, queue: &str, id: u64) -> Result<Message, ClientError>;
+
+    /// moves dead-lettered messages back to the tail of their queue, every
+    /// dead letter of the queue is moved if `id` is `None`. Returns how many
+    /// messages are moved
+    fn redrive(&mut self, queue: &str, id: Option<u64>) -> Result<usize, ClientError>;
+
+    /// subscribes a queue to a topic, every message published to the topic is
+    /// copied to the queue. The queue is created if it doesn't exist, returns
+    /// `false` if the queue is already subscribed
+    fn subscribe_topic(&mut self, topic: &str, queue: &str) -> Result<bool, ClientError>;
+
+    /// unsubscribes a queue from a topic, returns `false` if the queue isn't
+    /// subscribed
+    fn unsubscribe_topic(&mut self, topic: &str, queue: &str) -> Result<bool, ClientError>;
+
+    /// publishes a message to a topic, returns how many queues the message is
+    /// copied to
+    fn publish(&mut self, topic: &str, message: &Message) -> Result<usize, ClientError>;
+
+    /// joins a consumer group of a topic, every group gets its own copy of the
+    /// topic's messages. Returns the queue the group's messages are consumed
+    /// from, the messages are spread across the members consuming it
+    fn join_group(&mut self, topic: &str, group: &str) -> Result<String, ClientError>;
+
+    /// leaves a consumer group of a topic, returns `false` if the client isn't
+    /// a member of the group. The group's queue is deleted along with its
+    /// messages once every member left
+    fn leave_group(&mut self, topic: &str, group: &str) -> Result<bool, ClientError>;
+
+    /// creates a temporary queue only the client consumes, the other clients
+    /// can only push to it. The queue is deleted once the client disconnects,
+    /// returns the queue's name
+    fn create_reply_queue(&mut self) -> Result<String, ClientError>;
+
+    /// pushes a request to a queue and waits for its reply, the request names
+    /// the client's reply queue and a correlation id the reply has to carry.
+    /// The replies to the earlier calls that timed out are dropped. Fails with
+    /// `Timeout` if no reply comes back in time
+    fn call(
+        &mut self,
+        queue: &str,
+        request: &Message,
+        timeout: Duration,
+    ) -> Result<Message, ClientError>;
 
-    /// pulls a message from the server's queue
-    fn pull(&mut self) -> Result<Message, ClientError>;
+    /// pushes the reply to a request to the request's reply queue, along with
+    /// the request's correlation id. Returns `false` if the request doesn't
+    /// expect a reply
+    fn reply(&mut self, request: &Message, reply: &Message) -> Result<bool, ClientError>;
 }
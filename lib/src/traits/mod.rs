@@ -1,3 +1,5 @@
+pub mod admin;
 pub mod client;
+pub mod pipeline;
 pub mod server;
 
@@ -0,0 +1,35 @@
+use crate::enums::errors::ClientError;
+use crate::structs::message::Message;
+use std::time::Duration;
+
+/// requests sent without waiting for the previous responses. Every request
+/// gets an id its response is matched with, the responses can come back in
+/// any order (a pull waiting for a message is answered after the requests sent
+/// after it)
+pub trait Pipeline {
+    /// sends a push without waiting for its response, returns the request's
+    /// id
+    fn push(
+        &mut self,
+        queue: &str,
+        message: &Message,
+        priority: u8,
+        ttl: Option<Duration>,
+    ) -> Result<u32, ClientError>;
+
+    /// sends a pull without waiting for its response, returns the request's
+    /// id
+    fn pull(&mut self, queue: &str) -> Result<u32, ClientError>;
+
+    /// sends a pull with a wait timeout without waiting for its response,
+    /// returns the request's id
+    fn pull_wait(&mut self, queue: &str, timeout: Duration) -> Result<u32, ClientError>;
+
+    /// waits for the response of a push, fails with `QueueFull` if the queue
+    /// is at its capacity
+    fn pushed(&mut self, id: u32) -> Result<(), ClientError>;
+
+    /// waits for the response of a pull, the message is empty if the queue
+    /// is
+    fn pulled(&mut self, id: u32) -> Result<Message, ClientError>;
+}
@@ -1,8 +1,17 @@
-use crate::enums::errors::{MessageError, ServerError};
+use crate::enums::errors::ServerError;
+use crate::enums::schedule::Schedule;
+use crate::structs::dead_letter::DeadLetter;
+use crate::structs::delivery::Delivery;
+use crate::structs::envelope::Envelope;
 use crate::structs::message::Message;
-use std::collections::VecDeque;
+use crate::structs::queue_config::QueueConfig;
+use crate::structs::queue_stats::QueueStats;
+use std::time::Duration;
 
 pub trait Server {
+    /// the named queues kept by the server
+    type Queues;
+
     /// binds the server to a port
     fn bind(&mut self, port: Option<usize>) -> Result<(), ServerError>;
 
@@ -12,10 +21,126 @@ pub trait Server {
     /// a method to stop the server
     fn stop(&mut self) -> Result<(), ServerError>;
 
-    /// a method to enqueue a message to the server (usually from outside of the
-    /// client)
-    fn enqueue(queue: &mut VecDeque<Message>, message: Message) -> Result<(), MessageError>;
+    /// a method to create a named queue with its ttl and retention limits,
+    /// returns `false` if the queue already exists (its config is kept)
+    fn create_queue(
+        queues: &Self::Queues,
+        queue: &str,
+        config: QueueConfig,
+    ) -> Result<bool, ServerError>;
+
+    /// a method to enqueue a message to one of the server's queues (usually
+    /// from outside of the client), the queue is created if it doesn't exist.
+    /// The message is queued behind the messages of the same or a higher
+    /// priority. Fails with `QueueFull` if the queue is at its limits and
+    /// doesn't drop its oldest messages, the queues that block their producers
+    /// are waited on by the connection instead
+    fn enqueue(queues: &Self::Queues, queue: &str, envelope: Envelope) -> Result<(), ServerError>;
+
+    /// a method to enqueue messages to one of the server's queues while taking
+    /// the queue's lock once, the queue is created if it doesn't exist. Each
+    /// message gets its own result, the batch never waits for room in the
+    /// queue
+    fn enqueue_batch(
+        queues: &Self::Queues,
+        queue: &str,
+        envelopes: Vec<Envelope>,
+    ) -> Result<Vec<Result<(), ServerError>>, ServerError>;
+
+    /// a method to enqueue a message that's hidden from the dequeuers until
+    /// its schedule is due, the message is then queued as if it was enqueued
+    /// at that time
+    fn schedule(
+        queues: &Self::Queues,
+        queue: &str,
+        envelope: Envelope,
+        schedule: Schedule,
+    ) -> Result<(), ServerError>;
+
+    /// a method to dequeue a message from one of the server's queues, expired
+    /// messages are never dequeued. A message of a group is only dequeued once
+    /// the earlier messages of its group are and none of them is reserved
+    fn dequeue(queues: &Self::Queues, queue: &str) -> Message;
+
+    /// a method to dequeue up to `max` messages from one of the server's queues
+    /// while taking the queue's lock once, the list is empty if the queue is.
+    /// The messages stop before their list takes more than `max_bytes`
+    fn dequeue_batch(
+        queues: &Self::Queues,
+        queue: &str,
+        max: usize,
+        max_bytes: usize,
+    ) -> Vec<Message>;
+
+    /// a method to dequeue a message from one of the server's queues, waiting
+    /// until a message is pushed if the queue is empty. The empty message is
+    /// returned if the timeout expires first
+    fn dequeue_wait(queues: &Self::Queues, queue: &str, timeout: Duration) -> Message;
+
+    /// a method to dequeue a message without removing it from the queue, the
+    /// message is put back to the head of the queue if it's not acknowledged
+    /// before the visibility timeout (or the server's default) expires
+    fn reserve(queues: &Self::Queues, queue: &str, timeout: Option<Duration>) -> Delivery;
+
+    /// a method to acknowledge a reserved message, removing it from the queue.
+    /// Returns `false` if the delivery is unknown or has expired
+    fn ack(queues: &Self::Queues, queue: &str, delivery_id: u64) -> Result<bool, ServerError>;
+
+    /// a method to put a reserved message back to the head of the queue.
+    /// Returns `false` if the delivery is unknown or has expired
+    fn nack(queues: &Self::Queues, queue: &str, delivery_id: u64) -> bool;
+
+    /// a method to list the messages of a queue that have been dead-lettered
+    /// after too many delivery attempts
+    fn dead_letters(queues: &Self::Queues, queue: &str) -> Vec<DeadLetter>;
+
+    /// a method to get a dead-lettered message of a queue by its id
+    fn inspect_dead_letter(queues: &Self::Queues, queue: &str, id: u64) -> Option<DeadLetter>;
+
+    /// a method to move dead-lettered messages back to the tail of their
+    /// queue, every dead letter of the queue is moved if `id` is `None`.
+    /// Returns how many messages are moved
+    fn redrive(queues: &Self::Queues, queue: &str, id: Option<u64>) -> Result<usize, ServerError>;
+
+    /// a method to subscribe a queue to a topic, every message published to
+    /// the topic is copied to the queue. The queue is created if it doesn't
+    /// exist, returns `false` if the queue is already subscribed
+    fn subscribe_topic(
+        queues: &Self::Queues,
+        topic: &str,
+        queue: &str,
+    ) -> Result<bool, ServerError>;
+
+    /// a method to unsubscribe a queue from a topic, returns `false` if the
+    /// queue isn't subscribed
+    fn unsubscribe_topic(
+        queues: &Self::Queues,
+        topic: &str,
+        queue: &str,
+    ) -> Result<bool, ServerError>;
+
+    /// a method to publish a message to a topic, returns how many queues the
+    /// message is copied to
+    fn publish(queues: &Self::Queues, topic: &str, message: Message) -> Result<usize, ServerError>;
+
+    /// a method to list the names of the server's queues, sorted
+    fn list_queues(queues: &Self::Queues) -> Vec<String>;
+
+    /// a method to count the messages and the consumers of a queue, `None` if
+    /// there's no such queue
+    fn queue_stats(queues: &Self::Queues, queue: &str) -> Option<QueueStats>;
+
+    /// a method to get up to `max` of the next messages of a queue without
+    /// removing them, in the order they're dequeued. The messages stop before
+    /// their list takes more than `max_bytes`
+    fn peek(queues: &Self::Queues, queue: &str, max: usize, max_bytes: usize) -> Vec<Message>;
+
+    /// a method to remove the ready and the scheduled messages of a queue,
+    /// returns how many messages are removed
+    fn purge(queues: &Self::Queues, queue: &str) -> usize;
 
-    /// a method to dequeue a message to the server
-    fn dequeue(queue: &mut VecDeque<Message>) -> Message;
+    /// a method to remove a queue along with its messages and its topic
+    /// subscriptions, returns `false` if there's no such queue. A queue with
+    /// consumers isn't removed
+    fn delete_queue(queues: &Self::Queues, queue: &str) -> Result<bool, ServerError>;
 }
@@ -0,0 +1,678 @@
+use crate::group::Membership;
+use crate::metrics::Metered;
+use crate::queue::{Consumer, Queues};
+use crate::reply::Ownership;
+use crate::server::{Action, ServerImpl, Shared, MAX_WAITING_REQUESTS};
+use crate::subscription::{ack_consumed, Credits, POLL_INTERVAL};
+use log::{error, info, warn};
+use smq_lib::enums::command::Command;
+use smq_lib::enums::errors::ServerError;
+use smq_lib::enums::feature::Feature;
+use smq_lib::enums::status::Status;
+use smq_lib::structs::message::Message;
+use smq_lib::structs::request::HEADER_SIZE;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::tcp::OwnedWriteHalf;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{watch, Notify};
+use tokio::task::{JoinHandle, JoinSet};
+use uuid::Uuid;
+
+type Writer = Arc<tokio::sync::Mutex<Metered<OwnedWriteHalf>>>;
+
+/// serves the connections on a tokio runtime until the server gets a ctrl-c, every connection is
+/// a task instead of a thread
+pub(crate) fn serve(
+    listener: std::net::TcpListener,
+    shared: Shared,
+    max_frame_size: u64,
+) -> Result<(), ServerError> {
+    let runtime = match tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+    {
+        Ok(runtime) => runtime,
+        Err(e) => return Err(ServerError::UnableToStartServer(e.to_string())),
+    };
+
+    let shutdown = tokio::signal::ctrl_c();
+    runtime.block_on(accept(listener, shared, max_frame_size, shutdown))
+}
+
+/// accepts the connections until `shutdown` completes, then waits for the connections to end
+async fn accept<F: Future>(
+    listener: std::net::TcpListener,
+    shared: Shared,
+    max_frame_size: u64,
+    shutdown: F,
+) -> Result<(), ServerError> {
+    let listener = match TcpListener::from_std(listener) {
+        Ok(listener) => listener,
+        Err(e) => return Err(ServerError::UnableToStartServer(e.to_string())),
+    };
+
+    // the connections stop reading requests once it's set
+    let (stop_tx, stop_rx) = watch::channel(false);
+    let mut connections = JoinSet::new();
+    tokio::pin!(shutdown);
+
+    loop {
+        tokio::select! {
+            _ = &mut shutdown => {
+                info!("Gracefully shutting down...");
+                break;
+            }
+            accepted = listener.accept() => {
+                let stream = match accepted {
+                    Ok((stream, _)) => stream,
+                    Err(e) => {
+                        error!("Can't accept connection, error: {}", e);
+                        continue;
+                    }
+                };
+
+                let id = Uuid::new_v4();
+                let shared = shared.clone();
+                let stop = stop_rx.clone();
+                connections.spawn(handle_incoming(shared, stream, id, stop, max_frame_size));
+            }
+            // finished connections are reaped as they go
+            Some(_) = connections.join_next(), if !connections.is_empty() => {}
+        }
+    }
+
+    let _ = stop_tx.send(true);
+    info!("Waiting for {} connections...", connections.len());
+    while connections.join_next().await.is_some() {}
+
+    Ok(())
+}
+
+/// whether the connection already has `MAX_WAITING_REQUESTS` waiting, the answered ones are
+/// let go
+fn too_many(waiting: &mut JoinSet<()>) -> bool {
+    while waiting.try_join_next().is_some() {}
+    waiting.len() >= MAX_WAITING_REQUESTS
+}
+
+async fn handle_incoming(
+    shared: Shared,
+    stream: TcpStream,
+    id: Uuid,
+    mut stop: watch::Receiver<bool>,
+    max_frame_size: u64,
+) {
+    info!("Started a TCP handler");
+    let Shared {
+        queues,
+        groups,
+        reply_queues,
+        metrics,
+    } = shared;
+    let _connection = metrics.connection();
+    // shared with the blocking threads handling the requests
+    let membership = Arc::new(Membership::new(groups, queues.clone(), id));
+    let ownership = Arc::new(Ownership::new(reply_queues, queues.clone(), id));
+    let (reader, writer) = stream.into_split();
+    let mut reader = Metered::new(reader, metrics.clone());
+    // subscriptions write to the stream from their own task
+    let writer: Writer = Arc::new(tokio::sync::Mutex::new(Metered::new(
+        writer,
+        metrics.clone(),
+    )));
+    let mut subscription: Option<Subscription> = None;
+    // the bodies start with a request id once the client negotiated pipelining
+    let mut pipelined = false;
+    // the requests waiting for a message or for room, aborted when the connection is dropped
+    let mut waiting = JoinSet::new();
+
+    loop {
+        let mut header: [u8; HEADER_SIZE] = [0; HEADER_SIZE];
+        // a partially read header is dropped along with the connection
+        tokio::select! {
+            read = reader.read_exact(&mut header) => {
+                if read.is_err() {
+                    break;
+                }
+            }
+            _ = stop.changed() => break,
+        }
+        let command = match Command::from_byte(header[0]) {
+            Some(Command::Disconnect) => break,
+            command => command,
+        };
+        let size = u64::from_be_bytes(header[1..].try_into().unwrap());
+        // the body is never read, the connection can't go on
+        if let Err(response) = ServerImpl::check_frame_size(size, max_frame_size) {
+            warn!("Connection {} sent a frame of {} bytes", id, size);
+            let response = ServerImpl::tag(response, pipelined.then_some(0));
+            let _ = writer.lock().await.write_all(&response).await;
+            break;
+        }
+
+        let mut body = vec![0_u8; size as usize];
+        if reader.read_exact(&mut body).await.is_err() {
+            break;
+        }
+        let started = Instant::now();
+        let (request_id, body) = match ServerImpl::untag(pipelined, &body) {
+            Ok(untagged) => untagged,
+            Err(response) => {
+                if writer.lock().await.write_all(&response).await.is_err() {
+                    break;
+                }
+                continue;
+            }
+        };
+        let request = match ServerImpl::parse(command, body) {
+            Ok(request) => request,
+            Err(response) => {
+                let response = ServerImpl::tag(response, request_id);
+                if writer.lock().await.write_all(&response).await.is_err() {
+                    break;
+                }
+                continue;
+            }
+        };
+        let (command, queue) = (request.get_command(), request.get_queue().to_string());
+
+        // the queues lock their mutexes and write the log, the runtime's threads can't block
+        let (dispatched, membership, ownership) =
+            (queues.clone(), membership.clone(), ownership.clone());
+        let action = tokio::task::spawn_blocking(move || {
+            ServerImpl::dispatch(
+                &dispatched,
+                &membership,
+                &ownership,
+                &request,
+                max_frame_size,
+            )
+        });
+        let action = match action.await {
+            Ok(action) => action,
+            Err(e) => {
+                error!("Can't handle a request of connection {}: {}", id, e);
+                break;
+            }
+        };
+
+        let response = match action {
+            Action::Respond(response) => response,
+            // the other requests are served while waiting
+            Action::PullWait(_) | Action::PushWait(_) if pipelined && too_many(&mut waiting) => {
+                ServerImpl::error(Status::Failed, "too many waiting requests")
+            }
+            Action::PullWait(timeout) if pipelined => {
+                let (queues, writer) = (queues.clone(), writer.clone());
+                let metrics = metrics.clone();
+                waiting.spawn(async move {
+                    let msg = queues
+                        .pop_wait_async(&queue, timeout)
+                        .await
+                        .unwrap_or_else(Message::empty_message)
+                        .serialize();
+                    let response = ServerImpl::response(Status::Success, &msg);
+                    let response = ServerImpl::tag(response, request_id);
+                    let _ = writer.lock().await.write_all(&response).await;
+                    metrics.observe(command, started.elapsed());
+                });
+                continue;
+            }
+            Action::PushWait(envelope) if pipelined => {
+                let (queues, writer) = (queues.clone(), writer.clone());
+                let metrics = metrics.clone();
+                waiting.spawn(async move {
+                    let result = queues.push_wait_async(&queue, envelope).await;
+                    let response = ServerImpl::tag(ServerImpl::pushed(result), request_id);
+                    let _ = writer.lock().await.write_all(&response).await;
+                    metrics.observe(command, started.elapsed());
+                });
+                continue;
+            }
+            Action::PullWait(timeout) => {
+                let msg = queues
+                    .pop_wait_async(&queue, timeout)
+                    .await
+                    .unwrap_or_else(Message::empty_message)
+                    .serialize();
+                ServerImpl::response(Status::Success, &msg)
+            }
+            Action::PushWait(envelope) => {
+                let result = queues.push_wait_async(&queue, envelope).await;
+                ServerImpl::pushed(result)
+            }
+            Action::Hello(hello) => {
+                // the hello is answered the way it's been sent
+                let response = ServerImpl::response(Status::Success, &hello.serialize());
+                let response = ServerImpl::tag(response, request_id);
+                if writer.lock().await.write_all(&response).await.is_err() {
+                    break;
+                }
+                metrics.observe(command, started.elapsed());
+                pipelined = hello.supports(Feature::Pipelining);
+                continue;
+            }
+            Action::Subscribe(prefetch) => {
+                if subscription.is_some() {
+                    ServerImpl::error(Status::Failed, "already subscribed")
+                } else {
+                    // the response has to be written before the first message is streamed
+                    let response = ServerImpl::response(Status::Success, &[]);
+                    let response = ServerImpl::tag(response, request_id);
+                    if writer.lock().await.write_all(&response).await.is_err() {
+                        break;
+                    }
+                    metrics.observe(command, started.elapsed());
+                    subscription = Some(Subscription::start(
+                        queues.clone(),
+                        &queue,
+                        prefetch,
+                        writer.clone(),
+                        request_id,
+                    ));
+                    continue;
+                }
+            }
+            Action::Credit(credits) => {
+                if let Some(subscription) = &subscription {
+                    subscription.add_credits(credits);
+                }
+                // credits don't have a response
+                continue;
+            }
+            Action::Unsubscribe => {
+                if let Some(subscription) = subscription.take() {
+                    subscription.stop().await;
+                }
+                ServerImpl::response(Status::Success, &[])
+            }
+        };
+        let response = ServerImpl::tag(response, request_id);
+        if let Err(e) = writer.lock().await.write_all(&response).await {
+            error!("Failed to send response: {}", e);
+            break;
+        }
+        metrics.observe(command, started.elapsed());
+    }
+
+    if let Some(subscription) = subscription.take() {
+        subscription.stop().await;
+    }
+    info!("Stopped a TCP handler");
+}
+
+/// the async version of the subscriptions, the queue's messages are streamed by a task
+struct Subscription {
+    queues: Arc<Queues>,
+    queue: String,
+    credits: Arc<(Mutex<Credits>, Notify)>,
+    task: JoinHandle<()>,
+}
+
+impl Subscription {
+    fn start(
+        queues: Arc<Queues>,
+        queue: &str,
+        prefetch: u32,
+        writer: Writer,
+        id: Option<u32>,
+    ) -> Self {
+        let credits = Arc::new((Mutex::new(Credits::new(prefetch)), Notify::new()));
+        // counted before the response, a missing queue is counted once it's created
+        let consumer = queues.consumer(queue);
+
+        let (task_queues, task_queue) = (queues.clone(), queue.to_string());
+        let task_credits = credits.clone();
+        let task = tokio::spawn(async move {
+            info!("Started streaming queue {}", task_queue);
+            Subscription::stream(task_queues, &task_queue, consumer, task_credits, writer, id)
+                .await;
+            info!("Stopped streaming queue {}", task_queue);
+        });
+
+        Subscription {
+            queues,
+            queue: queue.to_string(),
+            credits,
+            task,
+        }
+    }
+
+    fn add_credits(&self, n: u32) {
+        let (credits, notify) = &*self.credits;
+        let mut credits = credits.lock().unwrap();
+        // the consumed messages are acknowledged before the credits can be taken again
+        ack_consumed(&self.queues, &self.queue, credits.give(n));
+        notify.notify_one();
+    }
+
+    /// stops streaming, no message is written after this returns. The messages that are
+    /// streamed but not consumed are put back to the head of the queue
+    async fn stop(self) {
+        {
+            let (credits, notify) = &*self.credits;
+            credits.lock().unwrap().active = false;
+            notify.notify_one();
+        }
+        let _ = self.task.await;
+
+        let unconsumed = self.credits.0.lock().unwrap().unconsumed();
+        self.queues.requeue(&self.queue, &unconsumed);
+    }
+
+    async fn stream(
+        queues: Arc<Queues>,
+        queue: &str,
+        mut consumer: Option<Consumer>,
+        credits: Arc<(Mutex<Credits>, Notify)>,
+        writer: Writer,
+        id: Option<u32>,
+    ) {
+        let (lock, notify) = &*credits;
+
+        loop {
+            loop {
+                let changed = notify.notified();
+                tokio::pin!(changed);
+                changed.as_mut().enable();
+                {
+                    let credits = lock.lock().unwrap();
+                    if !credits.active {
+                        return;
+                    }
+                    if credits.available > 0 {
+                        break;
+                    }
+                }
+                changed.await;
+            }
+
+            if consumer.is_none() {
+                consumer = queues.consumer(queue);
+            }
+            let (delivery_id, msg) = match queues.hold_wait_async(queue, POLL_INTERVAL).await {
+                Some(held) => held,
+                None => continue,
+            };
+            // the subscription can stop while waiting for the message
+            if !lock.lock().unwrap().take(delivery_id) {
+                queues.requeue(queue, &[delivery_id]);
+                return;
+            }
+
+            let response = ServerImpl::response(Status::Success, &msg.serialize());
+            let response = ServerImpl::tag(response, id);
+            // the message is put back once the subscription stops
+            if let Err(e) = writer.lock().await.write_all(&response).await {
+                error!("Can't stream a message of queue {}: {}", queue, e);
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::accept;
+    use crate::group::Groups;
+    use crate::metrics::Metrics;
+    use crate::queue::Queues;
+    use crate::reply::ReplyQueues;
+    use crate::server::Shared;
+    use crate::wal::{FsyncPolicy, Wal};
+    use smq_lib::enums::command::Command;
+    use smq_lib::enums::errors::ServerError;
+    use smq_lib::enums::feature::Feature;
+    use smq_lib::enums::status::Status;
+    use smq_lib::structs::envelope::Envelope;
+    use smq_lib::structs::hello::Hello;
+    use smq_lib::structs::message::Message;
+    use smq_lib::structs::request::{tag_frame, untag_body, Request, HEADER_SIZE};
+    use std::io::{Read, Write};
+    use std::net::{SocketAddr, TcpListener, TcpStream};
+    use std::path::PathBuf;
+    use std::sync::Arc;
+    use std::thread::{self, JoinHandle};
+    use std::time::Duration;
+    use tokio::sync::oneshot;
+    use uuid::Uuid;
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+    const MAX_FRAME_SIZE: u64 = 1024;
+
+    /// the async server accepting connections on its own runtime until it's stopped, the queues
+    /// are logged so the log is written from the runtime
+    struct Server {
+        address: SocketAddr,
+        path: PathBuf,
+        queues: Arc<Queues>,
+        stop: oneshot::Sender<()>,
+        thread: JoinHandle<Result<(), ServerError>>,
+    }
+
+    fn start() -> Server {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.set_nonblocking(true).unwrap();
+        let address = listener.local_addr().unwrap();
+
+        let path = std::env::temp_dir().join(format!("smq-async-{}.wal", Uuid::new_v4()));
+        let (wal, events) = Wal::open(&path, FsyncPolicy::Always).unwrap();
+        let queues = Queues::restore(wal, events, TIMEOUT, None, TIMEOUT, Duration::ZERO);
+        let queues = Arc::new(queues.unwrap());
+        let shared = Shared {
+            queues: queues.clone(),
+            groups: Arc::new(Groups::new()),
+            reply_queues: Arc::new(ReplyQueues::new()),
+            metrics: Arc::new(Metrics::new()),
+        };
+        let (stop, stopped) = oneshot::channel::<()>();
+        let thread = thread::spawn(move || {
+            let runtime = tokio::runtime::Builder::new_multi_thread()
+                .enable_all()
+                .build()
+                .unwrap();
+            runtime.block_on(accept(listener, shared, MAX_FRAME_SIZE, stopped))
+        });
+
+        Server {
+            address,
+            path,
+            queues,
+            stop,
+            thread,
+        }
+    }
+
+    impl Server {
+        fn connect(&self) -> Connection {
+            let client = TcpStream::connect(self.address).unwrap();
+            client.set_read_timeout(Some(TIMEOUT)).unwrap();
+            Connection { client }
+        }
+
+        /// the open connections are closed by the server
+        fn stop(self) {
+            self.stop.send(()).unwrap();
+            assert!(self.thread.join().unwrap().is_ok());
+            std::fs::remove_file(&self.path).unwrap();
+        }
+    }
+
+    struct Connection {
+        client: TcpStream,
+    }
+
+    impl Connection {
+        fn send(&mut self, command: Command, queue: &str, payload: &[u8]) {
+            let request = Request::new(command, queue, payload.to_vec().into()).unwrap();
+            self.client.write_all(&request.serialize()).unwrap();
+        }
+
+        fn request(&mut self, command: Command, queue: &str, payload: &[u8]) -> (u8, Vec<u8>) {
+            self.send(command, queue, payload);
+            self.response()
+        }
+
+        fn response(&mut self) -> (u8, Vec<u8>) {
+            let mut header = [0; HEADER_SIZE];
+            self.client.read_exact(&mut header).unwrap();
+            let size = u64::from_be_bytes(header[1..].try_into().unwrap());
+            let mut body = vec![0; size as usize];
+            self.client.read_exact(&mut body).unwrap();
+            (header[0], body)
+        }
+
+        fn send_tagged(&mut self, command: Command, queue: &str, payload: &[u8], id: u32) {
+            let request = Request::new(command, queue, payload.to_vec().into()).unwrap();
+            let frame = tag_frame(&request.serialize(), id);
+            self.client.write_all(&frame).unwrap();
+        }
+
+        fn tagged_response(&mut self) -> (u32, u8, Vec<u8>) {
+            let (status, body) = self.response();
+            let (id, body) = untag_body(&body).unwrap();
+            (id, status, body.to_vec())
+        }
+
+        /// the value of the message in a response
+        fn value(&mut self) -> u8 {
+            let (status, body) = self.response();
+            assert_eq!(status, Status::Success as u8);
+            Message::deserialize(&body).unwrap().get_data()[0]
+        }
+
+        fn disconnect(mut self) {
+            self.client.write_all(&[0xFF; HEADER_SIZE]).unwrap();
+        }
+    }
+
+    fn push_payload(value: u8) -> Vec<u8> {
+        Envelope::new(Message::from_u8_arr(&[value]), 0, None)
+            .serialize()
+            .to_vec()
+    }
+
+    #[test]
+    fn push_and_pull() {
+        let server = start();
+        let mut connection = server.connect();
+
+        let (status, _) = connection.request(Command::Push, "q", &push_payload(1));
+        assert_eq!(status, Status::Success as u8);
+        connection.send(Command::Pull, "q", &[]);
+        assert_eq!(connection.value(), 1);
+        let (status, body) = connection.request(Command::Pull, "q", &[]);
+        assert_eq!(status, Status::Success as u8);
+        assert!(Message::deserialize(&body).unwrap().get_data().is_empty());
+
+        connection.disconnect();
+        server.stop();
+    }
+
+    #[test]
+    fn pull_wait_is_woken_by_a_push() {
+        let server = start();
+        let mut puller = server.connect();
+        let mut producer = server.connect();
+
+        let timeout = TIMEOUT.as_millis() as u64;
+        puller.send(Command::PullWait, "q", &timeout.to_be_bytes());
+        thread::sleep(Duration::from_millis(50));
+        let (status, _) = producer.request(Command::Push, "q", &push_payload(7));
+        assert_eq!(status, Status::Success as u8);
+        assert_eq!(puller.value(), 7);
+
+        puller.disconnect();
+        producer.disconnect();
+        server.stop();
+    }
+
+    #[test]
+    fn subscription_streams_a_message_per_credit() {
+        let server = start();
+        let mut connection = server.connect();
+        for value in 1..=3 {
+            let (status, _) = connection.request(Command::Push, "q", &push_payload(value));
+            assert_eq!(status, Status::Success as u8);
+        }
+
+        let (status, _) = connection.request(Command::Subscribe, "q", &2_u32.to_be_bytes());
+        assert_eq!(status, Status::Success as u8);
+        assert_eq!((connection.value(), connection.value()), (1, 2));
+        let stats = server.queues.stats("q").unwrap();
+        assert_eq!((stats.depth, stats.in_flight), (1, 2));
+
+        connection.send(Command::Credit, "q", &1_u32.to_be_bytes());
+        assert_eq!(connection.value(), 3);
+        let (status, body) = connection.request(Command::Unsubscribe, "q", &[]);
+        assert_eq!((status, body.len()), (Status::Success as u8, 0));
+        let stats = server.queues.stats("q").unwrap();
+        assert_eq!((stats.depth, stats.in_flight), (2, 0));
+
+        // the messages of a subscription closed mid-stream are handed out again
+        let (status, _) = connection.request(Command::Subscribe, "q", &1_u32.to_be_bytes());
+        assert_eq!(status, Status::Success as u8);
+        assert_eq!(connection.value(), 2);
+        drop(connection);
+        let mut connection = server.connect();
+        connection.send(Command::PullWait, "q", &1000_u64.to_be_bytes());
+        assert_eq!(connection.value(), 2);
+
+        connection.disconnect();
+        server.stop();
+    }
+
+    #[test]
+    fn pipelined_responses_out_of_order() {
+        let server = start();
+        let mut connection = server.connect();
+        let hello = Hello::legacy().with(Feature::Pipelining);
+        let (status, _) = connection.request(Command::Hello, "client", &hello.serialize());
+        assert_eq!(status, Status::Success as u8);
+
+        let timeout = TIMEOUT.as_millis() as u64;
+        connection.send_tagged(Command::PullWait, "a", &timeout.to_be_bytes(), 1);
+        connection.send_tagged(Command::Push, "b", &push_payload(1), 2);
+        assert_eq!(
+            connection.tagged_response(),
+            (2, Status::Success as u8, vec![])
+        );
+
+        connection.send_tagged(Command::Push, "a", &push_payload(7), 3);
+        let mut responses = [connection.tagged_response(), connection.tagged_response()];
+        responses.sort();
+        assert_eq!(
+            responses[0],
+            (1, Status::Success as u8, push_payload(7)[9..].to_vec())
+        );
+        assert_eq!(responses[1], (3, Status::Success as u8, vec![]));
+
+        connection.disconnect();
+        server.stop();
+    }
+
+    #[test]
+    fn oversized_frame_closes_the_connection() {
+        let server = start();
+        let mut connection = server.connect();
+
+        let header = [
+            &[Command::Push as u8][..],
+            &(MAX_FRAME_SIZE + 1).to_be_bytes(),
+        ]
+        .concat();
+        connection.client.write_all(&header).unwrap();
+        let (status, _) = connection.response();
+        assert_eq!(status, Status::MalformedHeader as u8);
+
+        // the server closes the connection without waiting for the body
+        let mut rest = Vec::new();
+        connection.client.read_to_end(&mut rest).unwrap();
+        assert!(rest.is_empty());
+
+        server.stop();
+    }
+}
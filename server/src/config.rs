@@ -0,0 +1,92 @@
+use crate::wal::FsyncPolicy;
+use log::warn;
+use smq_lib::structs::request::DEFAULT_MAX_FRAME_SIZE;
+use std::env;
+use std::path::PathBuf;
+use std::time::Duration;
+
+const DEFAULT_VISIBILITY_TIMEOUT: Duration = Duration::from_secs(30);
+const DEFAULT_REAP_INTERVAL: Duration = Duration::from_secs(1);
+const DEFAULT_BLOCK_TIMEOUT: Duration = Duration::from_secs(30);
+const DEFAULT_DEDUP_WINDOW: Duration = Duration::from_secs(5 * 60);
+
+/// the server's configuration, read from the environment
+pub(crate) struct Config {
+    /// `SMQ_PORT`, the port the server listens to
+    pub port: Option<usize>,
+    /// `SMQ_WAL_PATH`, the message log, messages are only kept in memory if it's not set
+    pub wal_path: Option<PathBuf>,
+    /// `SMQ_FSYNC`, either `always`, `batch`, `batch:<n>` or `os`
+    pub fsync: FsyncPolicy,
+    /// `SMQ_VISIBILITY_TIMEOUT`, how long a reserved message waits to be acknowledged before
+    /// it's handed out again, in milliseconds
+    pub visibility_timeout: Duration,
+    /// `SMQ_MAX_DELIVERY_ATTEMPTS`, how many times a message can be reserved before it's moved
+    /// to the dead letters of its queue, messages are never dead-lettered if it's not set
+    pub max_delivery_attempts: Option<u32>,
+    /// `SMQ_REAP_INTERVAL`, how often the expired messages are dropped from the queues, in
+    /// milliseconds
+    pub reap_interval: Duration,
+    /// `SMQ_BLOCK_TIMEOUT`, how long a push to a full queue that blocks its producers waits for
+    /// room before it's rejected, in milliseconds
+    pub block_timeout: Duration,
+    /// `SMQ_DEDUP_WINDOW`, how long the ids of the pushed messages are remembered to drop their
+    /// duplicates, in milliseconds. Duplicates aren't dropped if it's `0`
+    pub dedup_window: Duration,
+    /// `SMQ_MAX_FRAME_SIZE`, the largest request body the server reads, in bytes. The connections
+    /// sending bigger requests are closed
+    pub max_frame_size: u64,
+    /// `SMQ_METRICS_PORT`, the port the prometheus metrics are served on, they aren't served if
+    /// it's not set
+    pub metrics_port: Option<usize>,
+    /// `SMQ_ASYNC`, whether the connections are served on a tokio runtime instead of a thread
+    /// per connection, `true` by default
+    #[cfg(feature = "async")]
+    pub async_runtime: bool,
+}
+
+impl Config {
+    pub fn from_env() -> Self {
+        Config {
+            port: Config::parse("SMQ_PORT", |s| s.parse().ok()),
+            wal_path: env::var_os("SMQ_WAL_PATH").map(PathBuf::from),
+            fsync: Config::parse("SMQ_FSYNC", FsyncPolicy::parse).unwrap_or(FsyncPolicy::Always),
+            visibility_timeout: Config::parse("SMQ_VISIBILITY_TIMEOUT", |s| s.parse().ok())
+                .map(Duration::from_millis)
+                .unwrap_or(DEFAULT_VISIBILITY_TIMEOUT),
+            max_delivery_attempts: Config::parse("SMQ_MAX_DELIVERY_ATTEMPTS", |s| {
+                s.parse().ok().filter(|n| *n > 0)
+            }),
+            reap_interval: Config::parse("SMQ_REAP_INTERVAL", |s| {
+                s.parse().ok().filter(|n| *n > 0)
+            })
+            .map(Duration::from_millis)
+            .unwrap_or(DEFAULT_REAP_INTERVAL),
+            block_timeout: Config::parse("SMQ_BLOCK_TIMEOUT", |s| s.parse().ok())
+                .map(Duration::from_millis)
+                .unwrap_or(DEFAULT_BLOCK_TIMEOUT),
+            dedup_window: Config::parse("SMQ_DEDUP_WINDOW", |s| s.parse().ok())
+                .map(Duration::from_millis)
+                .unwrap_or(DEFAULT_DEDUP_WINDOW),
+            max_frame_size: Config::parse("SMQ_MAX_FRAME_SIZE", |s| {
+                s.parse().ok().filter(|n| *n > 0)
+            })
+            .unwrap_or(DEFAULT_MAX_FRAME_SIZE),
+            metrics_port: Config::parse("SMQ_METRICS_PORT", |s| s.parse().ok()),
+            #[cfg(feature = "async")]
+            async_runtime: Config::parse("SMQ_ASYNC", |s| s.parse().ok()).unwrap_or(true),
+        }
+    }
+
+    fn parse<T, F>(key: &str, f: F) -> Option<T>
+    where
+        F: FnOnce(&str) -> Option<T>,
+    {
+        let value = env::var(key).ok()?;
+        let parsed = f(&value);
+        if parsed.is_none() {
+            warn!("Ignoring invalid value of {}: {}", key, value);
+        }
+        parsed
+    }
+}
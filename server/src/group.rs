@@ -0,0 +1,173 @@
+use crate::queue::Queues;
+use crate::server::ServerImpl;
+use log::{error, info};
+use smq_lib::enums::command::Command;
+use smq_lib::enums::errors::ServerError;
+use smq_lib::enums::status::Status;
+use smq_lib::structs::request::encode_name;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use uuid::Uuid;
+
+/// the names of the groups' queues start with it, the requests to a group that doesn't exist are
+/// refused so no other queue is named like that
+const GROUP_QUEUE_PREFIX: &str = "#group-";
+
+/// the members of the consumer groups, keyed by the queue backing each group. A group is a queue
+/// subscribed to its topic, so every group gets its own copy of the topic's messages and the
+/// members of a group compete for the messages of that queue. A group's queue is deleted once
+/// its last member leaves
+pub(crate) struct Groups {
+    members: Mutex<HashMap<String, HashSet<Uuid>>>,
+}
+
+impl Groups {
+    pub fn new() -> Self {
+        Groups {
+            members: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// the name of the queue backing a group, the topic's length tells where the group's name
+    /// starts so no two groups share a queue
+    pub fn queue(topic: &str, group: &str) -> String {
+        format!("{}{}:{}{}", GROUP_QUEUE_PREFIX, topic.len(), topic, group)
+    }
+
+    /// whether a queue backs a group, the groups are gone along with their members after a
+    /// restart
+    pub fn is_group_queue(queue: &str) -> bool {
+        queue.starts_with(GROUP_QUEUE_PREFIX)
+    }
+
+    /// adds a connection to a group, the group's queue is created and subscribed to the topic if
+    /// it's the first time the group is joined. Returns the group's queue
+    pub fn join(
+        &self,
+        queues: &Queues,
+        topic: &str,
+        group: &str,
+        member: Uuid,
+    ) -> Result<String, ServerError> {
+        let queue = Groups::queue(topic, group);
+        if let Err(e) = encode_name(&queue) {
+            return Err(ServerError::MessageError(e));
+        }
+
+        // a group left by its last member in between would delete the queue
+        let mut members = self.members.lock().unwrap();
+        queues.subscribe_topic(topic, &queue)?;
+        let group_members = members.entry(queue.clone()).or_default();
+        group_members.insert(member);
+        info!(
+            "Connection {} joined group {} of topic {}, the group has {} members",
+            member,
+            group,
+            topic,
+            group_members.len()
+        );
+
+        Ok(queue)
+    }
+
+    /// removes a connection from a group, returns `false` if it isn't a member
+    pub fn leave(&self, queues: &Queues, topic: &str, group: &str, member: Uuid) -> bool {
+        let queue = Groups::queue(topic, group);
+
+        let mut members = self.members.lock().unwrap();
+        let group_members = match members.get_mut(&queue) {
+            Some(group_members) => group_members,
+            None => return false,
+        };
+        if !group_members.remove(&member) {
+            return false;
+        }
+        info!(
+            "Connection {} left group {} of topic {}, the group has {} members",
+            member,
+            group,
+            topic,
+            group_members.len()
+        );
+        if group_members.is_empty() {
+            members.remove(&queue);
+            Groups::delete(queues, &queue);
+        }
+
+        true
+    }
+
+    /// removes a connection from every group it's a member of
+    pub fn leave_all(&self, queues: &Queues, member: Uuid) {
+        let mut members = self.members.lock().unwrap();
+        members.retain(|queue, group_members| {
+            if !group_members.remove(&member) || !group_members.is_empty() {
+                return true;
+            }
+            Groups::delete(queues, queue);
+            false
+        });
+    }
+
+    /// the error is the response to a request to a group's queue while the group doesn't exist,
+    /// so the queue isn't created again
+    pub fn check(&self, command: Command, queue: &str) -> Result<(), Vec<u8>> {
+        // the queue name of these requests isn't a queue
+        if !Groups::is_group_queue(queue)
+            || matches!(
+                command,
+                Command::Hello | Command::CreateReplyQueue | Command::ListQueues
+            )
+        {
+            return Ok(());
+        }
+
+        match self.members.lock().unwrap().contains_key(queue) {
+            true => Ok(()),
+            false => Err(ServerImpl::error(Status::Failed, "no such group")),
+        }
+    }
+
+    /// deletes the queue of a group left by its last member, along with its topic subscription
+    fn delete(queues: &Queues, queue: &str) {
+        match queues.delete(queue) {
+            Ok(_) => info!("Deleted the queue {} of a group without members", queue),
+            Err(e) => error!("Can't delete group queue {}: {:?}", queue, e),
+        }
+    }
+}
+
+/// the groups joined by a connection, they're left when the connection's handler ends
+pub(crate) struct Membership {
+    groups: Arc<Groups>,
+    queues: Arc<Queues>,
+    member: Uuid,
+}
+
+impl Membership {
+    pub fn new(groups: Arc<Groups>, queues: Arc<Queues>, member: Uuid) -> Self {
+        Membership {
+            groups,
+            queues,
+            member,
+        }
+    }
+
+    pub fn join(&self, topic: &str, group: &str) -> Result<String, ServerError> {
+        self.groups.join(&self.queues, topic, group, self.member)
+    }
+
+    pub fn leave(&self, topic: &str, group: &str) -> bool {
+        self.groups.leave(&self.queues, topic, group, self.member)
+    }
+
+    pub fn check(&self, command: Command, queue: &str) -> Result<(), Vec<u8>> {
+        self.groups.check(command, queue)
+    }
+}
+
+impl Drop for Membership {
+    fn drop(&mut self) {
+        self.groups.leave_all(&self.queues, self.member);
+    }
+}
@@ -1,5 +1,16 @@
+#[cfg(feature = "async")]
+mod async_server;
+mod config;
+mod group;
+mod metrics;
+mod queue;
+mod reaper;
+mod reply;
 mod server;
+mod subscription;
+mod wal;
 
+use config::Config;
 use server::ServerImpl;
 use smq_lib::traits::server::Server;
 
@@ -7,8 +18,11 @@ fn main() {
     ::std::env::set_var("RUST_LOG", "INFO");
     env_logger::init();
 
-    let mut server = ServerImpl::new();
-    server.bind(None).expect("Server encountered an error");
+    let config = Config::from_env();
+    let mut server = ServerImpl::new(&config).expect("Can't restore the message log");
+    server
+        .bind(config.port)
+        .expect("Server encountered an error");
 
     server
         .r#loop()
@@ -0,0 +1,393 @@
+use crate::queue::{Counters, Queues};
+use log::{error, info, warn};
+use smq_lib::enums::command::Command;
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, TryRecvError};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+#[cfg(feature = "async")]
+use std::pin::Pin;
+#[cfg(feature = "async")]
+use std::task::{Context, Poll};
+#[cfg(feature = "async")]
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// the upper bounds of the request latency buckets, in seconds
+const LATENCY_BUCKETS: [f64; 12] = [
+    0.0001, 0.0005, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0, 10.0, 30.0,
+];
+/// how long a scrape has to send its request and to read the response
+const SCRAPE_TIMEOUT: Duration = Duration::from_secs(5);
+/// how often the exporter checks whether it's stopped while there's no scrape
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// the latencies of the requests of a command
+#[derive(Default)]
+struct Histogram {
+    /// how many requests fall in each bucket, the buckets are cumulated when they're exported
+    buckets: [u64; LATENCY_BUCKETS.len()],
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn observe(&mut self, seconds: f64) {
+        if let Some(i) = LATENCY_BUCKETS.iter().position(|bound| seconds <= *bound) {
+            self.buckets[i] += 1;
+        }
+        self.sum += seconds;
+        self.count += 1;
+    }
+}
+
+/// the metrics of the connections, the queues count their own messages
+#[derive(Default)]
+pub(crate) struct Metrics {
+    connections: AtomicU64,
+    received: AtomicU64,
+    sent: AtomicU64,
+    /// keyed by the command's byte
+    latencies: Mutex<BTreeMap<u8, Histogram>>,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Metrics::default()
+    }
+
+    /// counts an open connection until it's dropped
+    pub fn connection(self: &Arc<Self>) -> Connection {
+        self.connections.fetch_add(1, Ordering::SeqCst);
+
+        Connection {
+            metrics: self.clone(),
+        }
+    }
+
+    /// records how long a request took, from reading it to writing its response
+    pub fn observe(&self, command: Command, elapsed: Duration) {
+        self.latencies
+            .lock()
+            .unwrap()
+            .entry(command as u8)
+            .or_default()
+            .observe(elapsed.as_secs_f64());
+    }
+
+    /// the metrics in the prometheus text format
+    pub fn render(&self, queues: &Queues) -> String {
+        let mut out = String::new();
+
+        // a queue deleted in between is left out, a scrape only reads the queues
+        let rows: Vec<(String, Counters, u64)> = queues
+            .names()
+            .into_iter()
+            .filter_map(|queue| {
+                let counters = queues.counters(&queue)?;
+                let depth = queues.depth(&queue)?;
+                Some((escape(&queue), counters, depth))
+            })
+            .collect();
+        family(
+            &mut out,
+            "smq_messages_enqueued_total",
+            "counter",
+            "Messages pushed to the queue.",
+            &rows,
+            |counters, _| counters.enqueued,
+        );
+        family(
+            &mut out,
+            "smq_messages_dequeued_total",
+            "counter",
+            "Messages pulled or reserved from the queue.",
+            &rows,
+            |counters, _| counters.dequeued,
+        );
+        family(
+            &mut out,
+            "smq_messages_rejected_total",
+            "counter",
+            "Messages refused because the queue is full.",
+            &rows,
+            |counters, _| counters.rejected,
+        );
+        family(
+            &mut out,
+            "smq_queue_depth",
+            "gauge",
+            "Messages ready in the queue.",
+            &rows,
+            |_, depth| depth,
+        );
+
+        let totals = [
+            (
+                "smq_connections",
+                "gauge",
+                "Open client connections.",
+                &self.connections,
+            ),
+            (
+                "smq_received_bytes_total",
+                "counter",
+                "Bytes read from the client connections.",
+                &self.received,
+            ),
+            (
+                "smq_sent_bytes_total",
+                "counter",
+                "Bytes written to the client connections.",
+                &self.sent,
+            ),
+        ];
+        for (name, kind, help, value) in totals {
+            header(&mut out, name, kind, help);
+            let _ = writeln!(out, "{} {}", name, value.load(Ordering::SeqCst));
+        }
+
+        let name = "smq_request_duration_seconds";
+        header(
+            &mut out,
+            name,
+            "histogram",
+            "Time from reading a request to writing its response.",
+        );
+        for (command, histogram) in self.latencies.lock().unwrap().iter() {
+            let command = match Command::from_byte(*command) {
+                Some(command) => format!("{:?}", command),
+                None => continue,
+            };
+            let mut cumulated = 0;
+            for (bound, count) in LATENCY_BUCKETS.iter().zip(histogram.buckets) {
+                cumulated += count;
+                let _ = writeln!(
+                    out,
+                    "{}_bucket{{command=\"{}\",le=\"{}\"}} {}",
+                    name, command, bound, cumulated
+                );
+            }
+            let _ = writeln!(
+                out,
+                "{}_bucket{{command=\"{}\",le=\"+Inf\"}} {}",
+                name, command, histogram.count
+            );
+            let _ = writeln!(
+                out,
+                "{}_sum{{command=\"{}\"}} {}",
+                name, command, histogram.sum
+            );
+            let _ = writeln!(
+                out,
+                "{}_count{{command=\"{}\"}} {}",
+                name, command, histogram.count
+            );
+        }
+
+        out
+    }
+}
+
+/// writes a metric with a sample per queue, the rows are the escaped names of the queues along
+/// with their counters and depth
+fn family<F>(
+    out: &mut String,
+    name: &str,
+    kind: &str,
+    help: &str,
+    rows: &[(String, Counters, u64)],
+    value: F,
+) where
+    F: Fn(&Counters, u64) -> u64,
+{
+    header(out, name, kind, help);
+    for (queue, counters, depth) in rows {
+        let _ = writeln!(
+            out,
+            "{}{{queue=\"{}\"}} {}",
+            name,
+            queue,
+            value(counters, *depth)
+        );
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+/// escapes a label's value, the queue names can hold any character
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// an open connection, counted until it's dropped
+pub(crate) struct Connection {
+    metrics: Arc<Metrics>,
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.metrics.connections.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// counts the bytes read from and written to a connection
+pub(crate) struct Metered<S> {
+    inner: S,
+    metrics: Arc<Metrics>,
+}
+
+impl<S> Metered<S> {
+    pub fn new(inner: S, metrics: Arc<Metrics>) -> Self {
+        Metered { inner, metrics }
+    }
+}
+
+impl<S: Read> Read for Metered<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.metrics.received.fetch_add(n as u64, Ordering::Relaxed);
+        Ok(n)
+    }
+}
+
+impl<S: Write> Write for Metered<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.metrics.sent.fetch_add(n as u64, Ordering::Relaxed);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(feature = "async")]
+impl<S: AsyncRead + Unpin> AsyncRead for Metered<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        let poll = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = poll {
+            let n = buf.filled().len() - before;
+            self.metrics.received.fetch_add(n as u64, Ordering::Relaxed);
+        }
+        poll
+    }
+}
+
+#[cfg(feature = "async")]
+impl<S: AsyncWrite + Unpin> AsyncWrite for Metered<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let poll = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = poll {
+            self.metrics.sent.fetch_add(n as u64, Ordering::Relaxed);
+        }
+        poll
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// serves the metrics over http to prometheus while the server is running, `GET /metrics` is the
+/// only request answered. Each scrape is answered from its own thread so a slow one doesn't hold
+/// the others back
+pub(crate) struct Exporter {
+    stop: mpsc::Sender<()>,
+    thread: JoinHandle<()>,
+}
+
+impl Exporter {
+    pub fn start(port: usize, queues: Arc<Queues>, metrics: Arc<Metrics>) -> io::Result<Self> {
+        let listener = TcpListener::bind(format!("0.0.0.0:{}", port))?;
+        listener.set_nonblocking(true)?;
+
+        let (stop, stopped) = mpsc::channel();
+        let thread = thread::spawn(move || {
+            info!("Serving the metrics on port {}", port);
+            let mut scrapes: Vec<JoinHandle<()>> = Vec::new();
+            while let Err(TryRecvError::Empty) = stopped.try_recv() {
+                match listener.accept() {
+                    Ok((stream, _)) => {
+                        let (queues, metrics) = (queues.clone(), metrics.clone());
+                        scrapes.retain(|scrape| !scrape.is_finished());
+                        scrapes.push(thread::spawn(move || {
+                            if let Err(e) = Exporter::scrape(stream, &queues, &metrics) {
+                                warn!("Can't serve the metrics: {}", e);
+                            }
+                        }));
+                    }
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => thread::sleep(POLL_INTERVAL),
+                    Err(e) => error!("Can't accept a scrape, error: {}", e),
+                }
+            }
+            // the scrapes give up once their timeout expires
+            for scrape in scrapes {
+                let _ = scrape.join();
+            }
+        });
+
+        Ok(Exporter { stop, thread })
+    }
+
+    pub fn stop(self) {
+        let _ = self.stop.send(());
+        let _ = self.thread.join();
+    }
+
+    /// answers a single request and closes the connection
+    fn scrape(mut stream: TcpStream, queues: &Queues, metrics: &Metrics) -> io::Result<()> {
+        stream.set_nonblocking(false)?;
+        stream.set_read_timeout(Some(SCRAPE_TIMEOUT))?;
+        stream.set_write_timeout(Some(SCRAPE_TIMEOUT))?;
+
+        let mut reader = BufReader::new(&stream);
+        let mut request_line = String::new();
+        reader.read_line(&mut request_line)?;
+        // the headers are skipped up to the blank line ending them
+        let mut line = String::new();
+        while reader.read_line(&mut line)? > 2 {
+            line.clear();
+        }
+
+        let mut parts = request_line.split_whitespace();
+        let (method, target) = (parts.next(), parts.next().unwrap_or_default());
+        let (status, body) = match (method, target.split('?').next()) {
+            (Some("GET"), Some("/metrics")) => ("200 OK", metrics.render(queues)),
+            _ => ("404 Not Found", String::new()),
+        };
+
+        write!(
+            stream,
+            "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            status,
+            body.len(),
+            body
+        )
+    }
+}
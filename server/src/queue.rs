@@ -0,0 +1,1827 @@
+use crate::group::Groups;
+use crate::wal::{Event, Wal};
+use log::{error, info, warn};
+use smq_lib::enums::errors::ServerError;
+use smq_lib::enums::overflow::Overflow;
+use smq_lib::structs::dead_letter::DeadLetter;
+use smq_lib::structs::envelope::Envelope;
+use smq_lib::structs::message::Message;
+use smq_lib::structs::queue_config::QueueConfig;
+use smq_lib::structs::queue_stats::QueueStats;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, RwLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+#[cfg(feature = "async")]
+use tokio::runtime::{Handle, RuntimeFlavor};
+
+/// a message kept in a queue, the id is unique across every queue of the server
+pub(crate) struct Entry {
+    pub id: u64,
+    pub message: Message,
+    pub priority: u8,
+    /// when the message expires, in milliseconds since the unix epoch
+    pub expires_at: Option<u64>,
+    /// how many times the message has been reserved
+    pub attempts: u32,
+}
+
+impl Entry {
+    fn new(id: u64, message: Message, priority: u8) -> Self {
+        Entry {
+            id,
+            message,
+            priority,
+            expires_at: None,
+            attempts: 0,
+        }
+    }
+
+    /// the size of the message's data, counted towards the queue's `max_bytes`
+    fn size(&self) -> usize {
+        self.message.get_data().len()
+    }
+
+    fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+
+    fn to_dead_letter(&self) -> DeadLetter {
+        DeadLetter::new(self.id, self.attempts, self.message.clone())
+    }
+
+    fn group(&self) -> Option<&str> {
+        self.message.get_message_group()
+    }
+}
+
+/// a reserved message waiting to be acknowledged
+struct InFlight {
+    entry: Entry,
+    /// the messages streamed to a subscription have no visibility timeout, the subscription
+    /// settles them
+    deadline: Option<Instant>,
+}
+
+/// where a ready message is in the order they're handed out, the highest priority first and then
+/// its position within the priority
+type Slot = (Reverse<u8>, i64);
+
+/// the messages waiting to be handed out, the highest priority first and in the order they're
+/// pushed within a priority
+#[derive(Default)]
+struct Ready {
+    entries: BTreeMap<Slot, Entry>,
+    /// where each message is, by id
+    slots: BTreeMap<u64, Slot>,
+    /// the positions of the first and of the last message pushed so far
+    front: i64,
+    back: i64,
+    len: usize,
+    bytes: usize,
+    /// the ready messages of each group by id, only the oldest one can be handed out
+    groups: HashMap<String, BTreeMap<u64, Slot>>,
+    /// how many messages of each group are reserved, the group is held back meanwhile
+    busy: HashMap<String, usize>,
+    /// the messages that can be handed out: the ones without a group and the oldest message of
+    /// each group that isn't held back
+    next: BTreeSet<Slot>,
+}
+
+impl Ready {
+    fn push_back(&mut self, entry: Entry) {
+        self.back += 1;
+        self.insert((Reverse(entry.priority), self.back), entry);
+    }
+
+    fn push_front(&mut self, entry: Entry) {
+        self.front -= 1;
+        self.insert((Reverse(entry.priority), self.front), entry);
+    }
+
+    fn pop_front(&mut self) -> Option<Entry> {
+        let slot = *self.entries.keys().next()?;
+        self.take(slot)
+    }
+
+    /// removes the next message that isn't held back by its group. A message of a group is only
+    /// handed out once the earlier messages of the group are, whatever their priority, and while
+    /// no message of the group is reserved
+    fn pop_next(&mut self) -> Option<Entry> {
+        let slot = *self.next.first()?;
+        self.take(slot)
+    }
+
+    /// removes the message that has been waiting the longest, whatever its priority
+    fn pop_oldest(&mut self) -> Option<Entry> {
+        let slot = *self.slots.values().next()?;
+        self.take(slot)
+    }
+
+    fn remove(&mut self, id: u64) -> Option<Entry> {
+        let slot = *self.slots.get(&id)?;
+        self.take(slot)
+    }
+
+    /// removes the expired messages, keeping the order of the others
+    fn remove_expired(&mut self, now: u64) -> Vec<Entry> {
+        let expired: Vec<Slot> = self
+            .entries
+            .iter()
+            .filter(|(_, entry)| entry.is_expired(now))
+            .map(|(slot, _)| *slot)
+            .collect();
+
+        expired
+            .into_iter()
+            .filter_map(|slot| self.take(slot))
+            .collect()
+    }
+
+    fn get_mut(&mut self, id: u64) -> Option<&mut Entry> {
+        let slot = self.slots.get(&id)?;
+        self.entries.get_mut(slot)
+    }
+
+    /// the messages in the order they're handed out
+    fn iter(&self) -> impl Iterator<Item = &Entry> {
+        self.entries.values()
+    }
+
+    /// holds the group back while one of its messages is reserved
+    fn reserve_group(&mut self, group: &str) {
+        let reserved = self.busy.entry(group.to_string()).or_default();
+        *reserved += 1;
+        if *reserved == 1 {
+            self.unlist(group);
+        }
+    }
+
+    /// lets the group's next message be handed out once none of its messages is reserved
+    fn settle_group(&mut self, group: &str) {
+        let reserved = match self.busy.get_mut(group) {
+            Some(reserved) => reserved,
+            None => return,
+        };
+        *reserved -= 1;
+        if *reserved == 0 {
+            self.busy.remove(group);
+            self.list(group);
+        }
+    }
+
+    fn insert(&mut self, slot: Slot, entry: Entry) {
+        self.len += 1;
+        self.bytes += entry.size();
+        self.slots.insert(entry.id, slot);
+        match entry.group() {
+            Some(group) => {
+                // the message can be older than the group's oldest one when it's put back
+                self.unlist(group);
+                self.groups
+                    .entry(group.to_string())
+                    .or_default()
+                    .insert(entry.id, slot);
+                self.list(group);
+            }
+            None => {
+                self.next.insert(slot);
+            }
+        }
+        self.entries.insert(slot, entry);
+    }
+
+    fn take(&mut self, slot: Slot) -> Option<Entry> {
+        let entry = self.entries.remove(&slot)?;
+        self.len -= 1;
+        self.bytes -= entry.size();
+        self.slots.remove(&entry.id);
+        self.next.remove(&slot);
+        if let Some(group) = entry.group() {
+            if let Some(ids) = self.groups.get_mut(group) {
+                ids.remove(&entry.id);
+                if ids.is_empty() {
+                    self.groups.remove(group);
+                }
+            }
+            self.list(group);
+        }
+
+        Some(entry)
+    }
+
+    /// the slot of the group's oldest message
+    fn head(&self, group: &str) -> Option<Slot> {
+        self.groups.get(group)?.values().next().copied()
+    }
+
+    /// makes the group's oldest message the next one handed out of the group, unless the group
+    /// is held back
+    fn list(&mut self, group: &str) {
+        if self.busy.contains_key(group) {
+            return;
+        }
+        if let Some(head) = self.head(group) {
+            self.next.insert(head);
+        }
+    }
+
+    fn unlist(&mut self, group: &str) {
+        if let Some(head) = self.head(group) {
+            self.next.remove(&head);
+        }
+    }
+}
+
+/// the message ids recently pushed to a queue, a message whose id is still remembered isn't
+/// enqueued again
+#[derive(Default)]
+struct Dedup {
+    /// when each id has been pushed, in milliseconds since the unix epoch
+    seen: HashMap<String, u64>,
+    /// the ids in the order they're pushed, the oldest are forgotten first
+    order: VecDeque<(u64, String)>,
+}
+
+impl Dedup {
+    fn contains(&self, id: &str) -> bool {
+        self.seen.contains_key(id)
+    }
+
+    fn insert(&mut self, id: &str, seen_at: u64) {
+        if self.seen.insert(id.to_string(), seen_at).is_none() {
+            self.order.push_back((seen_at, id.to_string()));
+        }
+    }
+
+    /// forgets the ids pushed before `since`
+    fn forget(&mut self, since: u64) {
+        while let Some((seen_at, _)) = self.order.front() {
+            if *seen_at >= since {
+                break;
+            }
+            let (_, id) = self.order.pop_front().unwrap();
+            self.seen.remove(&id);
+        }
+    }
+
+    /// the ids along with when they're pushed, the oldest first
+    fn iter(&self) -> impl Iterator<Item = (u64, &str)> {
+        self.order
+            .iter()
+            .map(|(seen_at, id)| (*seen_at, id.as_str()))
+    }
+}
+
+/// the messages of a single queue, reserved messages are kept aside until they're acknowledged
+/// and messages that are reserved too many times are moved to the dead letters. Scheduled
+/// messages are kept aside until they're due
+pub(crate) struct Queue {
+    config: QueueConfig,
+    ready: Ready,
+    /// keyed by when the message is due (in milliseconds since the unix epoch) and its id
+    scheduled: BTreeMap<(u64, u64), Entry>,
+    scheduled_bytes: usize,
+    in_flight: HashMap<u64, InFlight>,
+    dead_letters: VecDeque<Entry>,
+    dedup: Dedup,
+    counters: Counters,
+}
+
+impl Default for Queue {
+    fn default() -> Self {
+        Queue::new(QueueConfig::default())
+    }
+}
+
+impl Queue {
+    fn new(config: QueueConfig) -> Self {
+        Queue {
+            config,
+            ready: Ready::default(),
+            scheduled: BTreeMap::new(),
+            scheduled_bytes: 0,
+            in_flight: HashMap::new(),
+            dead_letters: VecDeque::new(),
+            dedup: Dedup::default(),
+            counters: Counters::default(),
+        }
+    }
+
+    pub fn config(&self) -> &QueueConfig {
+        &self.config
+    }
+
+    pub fn ready(&self) -> impl Iterator<Item = &Entry> {
+        self.ready.iter()
+    }
+
+    /// the scheduled messages along with when they're due
+    pub fn scheduled(&self) -> impl Iterator<Item = (u64, &Entry)> {
+        self.scheduled
+            .iter()
+            .map(|((deliver_at, _), entry)| (*deliver_at, entry))
+    }
+
+    pub fn dead_letters(&self) -> impl Iterator<Item = &Entry> {
+        self.dead_letters.iter()
+    }
+
+    /// the message ids remembered to drop duplicates, along with when they're pushed
+    pub fn dedup(&self) -> impl Iterator<Item = (u64, &str)> {
+        self.dedup.iter()
+    }
+
+    /// when a message visible from `from` expires, messages without a ttl get the queue's
+    fn expires_at(&self, ttl: Option<Duration>, from: u64) -> Option<u64> {
+        ttl.or(self.config.ttl)
+            .map(|ttl| from.saturating_add(ttl.as_millis() as u64))
+    }
+
+    /// whether a message of `size` bytes goes over the retention limits, the ready and the
+    /// scheduled messages count towards them
+    fn is_full(&self, size: usize) -> bool {
+        let len = (self.ready.len + self.scheduled.len()) as u64;
+        let bytes = (self.ready.bytes + self.scheduled_bytes + size) as u64;
+
+        self.config.max_length.is_some_and(|max| len >= max)
+            || self.config.max_bytes.is_some_and(|max| bytes > max)
+    }
+
+    /// pops the next message, the expired messages popped on the way are added to `expired`. The
+    /// groups of the reserved messages are held back until the messages are settled
+    fn pop_ready(&mut self, now: u64, expired: &mut Vec<Entry>) -> Option<Entry> {
+        while let Some(entry) = self.ready.pop_next() {
+            if !entry.is_expired(now) {
+                return Some(entry);
+            }
+            expired.push(entry);
+        }
+        None
+    }
+
+    fn reserve(
+        &mut self,
+        mut entry: Entry,
+        delivery_id: u64,
+        timeout: Option<Duration>,
+    ) -> (u32, Message) {
+        entry.attempts += 1;
+        let attempts = entry.attempts;
+        let message = entry.message.clone();
+
+        if let Some(group) = entry.group() {
+            self.ready.reserve_group(group);
+        }
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        self.in_flight
+            .insert(delivery_id, InFlight { entry, deadline });
+
+        (attempts, message)
+    }
+
+    /// the earliest visibility timeout of the reserved messages or the earliest scheduled
+    /// message, whichever comes first
+    fn next_deadline(&self) -> Option<Instant> {
+        let reserved = self.in_flight.values().filter_map(|i| i.deadline).min();
+        let scheduled = self.scheduled.keys().next().map(|(deliver_at, _)| {
+            let delay = deliver_at.saturating_sub(now_millis());
+            Instant::now() + Duration::from_millis(delay)
+        });
+
+        reserved.into_iter().chain(scheduled).min()
+    }
+
+    fn schedule(&mut self, deliver_at: u64, entry: Entry) {
+        self.scheduled_bytes += entry.size();
+        self.scheduled.insert((deliver_at, entry.id), entry);
+    }
+
+    /// moves the scheduled messages that are due to the tail of the queue
+    fn promote_scheduled(&mut self, now: u64) {
+        while let Some(first) = self.scheduled.first_entry() {
+            if first.key().0 > now {
+                break;
+            }
+            let entry = first.remove();
+            self.scheduled_bytes -= entry.size();
+            self.ready.push_back(entry);
+        }
+    }
+
+    /// removes a message that's either ready or scheduled
+    fn remove(&mut self, id: u64) -> Option<Entry> {
+        if let Some(entry) = self.ready.remove(id) {
+            return Some(entry);
+        }
+
+        let key = *self
+            .scheduled
+            .keys()
+            .find(|(_, entry_id)| *entry_id == id)?;
+        let entry = self.scheduled.remove(&key)?;
+        self.scheduled_bytes -= entry.size();
+        Some(entry)
+    }
+
+    /// a message that's either ready or scheduled
+    fn get_mut(&mut self, id: u64) -> Option<&mut Entry> {
+        match self.ready.get_mut(id) {
+            Some(entry) => Some(entry),
+            None => self.scheduled.values_mut().find(|entry| entry.id == id),
+        }
+    }
+
+    fn take_in_flight(&mut self, delivery_id: u64) -> Option<Entry> {
+        let entry = self.in_flight.remove(&delivery_id)?.entry;
+        if let Some(group) = entry.group() {
+            self.ready.settle_group(group);
+        }
+        Some(entry)
+    }
+
+    /// puts an unacknowledged message back to the head of the queue, or to the dead letters if
+    /// it has been reserved too many times. Returns `true` if the message is dead-lettered
+    fn release(&mut self, entry: Entry, max_attempts: Option<u32>) -> bool {
+        match max_attempts {
+            Some(max) if entry.attempts >= max => {
+                self.dead_letters.push_back(entry);
+                true
+            }
+            _ => {
+                self.ready.push_front(entry);
+                false
+            }
+        }
+    }
+
+    /// releases the reserved messages whose visibility timeout has expired, keeping their
+    /// original order. Returns the ids of the dead-lettered messages
+    fn release_expired(&mut self, now: Instant, max_attempts: Option<u32>) -> Vec<u64> {
+        let expired: Vec<u64> = self
+            .in_flight
+            .iter()
+            .filter(|(_, i)| i.deadline.is_some_and(|deadline| deadline <= now))
+            .map(|(id, _)| *id)
+            .collect();
+
+        let mut entries: Vec<Entry> = expired
+            .iter()
+            .filter_map(|id| self.take_in_flight(*id))
+            .collect();
+        entries.sort_by_key(|entry| std::cmp::Reverse(entry.id));
+
+        let mut dead = vec![];
+        for entry in entries {
+            let id = entry.id;
+            if self.release(entry, max_attempts) {
+                dead.push(id);
+            }
+        }
+
+        dead
+    }
+
+    /// moves a dead letter (or every dead letter) back to the tail of the queue, returns the ids
+    /// of the moved messages. The redriven messages don't expire anymore
+    fn redrive(&mut self, id: Option<u64>) -> Vec<u64> {
+        let (redriven, kept): (VecDeque<Entry>, VecDeque<Entry>) = self
+            .dead_letters
+            .drain(..)
+            .partition(|entry| id.is_none_or(|id| entry.id == id));
+        self.dead_letters = kept;
+
+        let ids = redriven.iter().map(|entry| entry.id).collect();
+        for mut entry in redriven {
+            entry.attempts = 0;
+            entry.expires_at = None;
+            self.ready.push_back(entry);
+        }
+
+        ids
+    }
+}
+
+/// how many messages a queue took in, handed out and refused since the server started
+#[derive(Clone, Copy, Default)]
+pub(crate) struct Counters {
+    pub enqueued: u64,
+    pub dequeued: u64,
+    pub rejected: u64,
+}
+
+/// a queue along with the condition variables pullers wait on until a message is available and
+/// producers wait on until there's room in a full queue
+pub(crate) struct LockedQueue {
+    queue: Mutex<Queue>,
+    available: Condvar,
+    room: Condvar,
+    /// how many subscriptions stream the queue
+    consumers: AtomicU32,
+    /// wakes the pullers of the async server up, they can't block on the condition variable
+    #[cfg(feature = "async")]
+    pushed: tokio::sync::Notify,
+    /// wakes the producers of the async server up
+    #[cfg(feature = "async")]
+    pulled: tokio::sync::Notify,
+}
+
+impl LockedQueue {
+    fn new(queue: Queue) -> Self {
+        LockedQueue {
+            queue: Mutex::new(queue),
+            available: Condvar::new(),
+            room: Condvar::new(),
+            consumers: AtomicU32::new(0),
+            #[cfg(feature = "async")]
+            pushed: tokio::sync::Notify::new(),
+            #[cfg(feature = "async")]
+            pulled: tokio::sync::Notify::new(),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Queue> {
+        self.queue.lock().unwrap()
+    }
+
+    /// wakes a single puller up
+    fn notify_one(&self) {
+        self.available.notify_one();
+        #[cfg(feature = "async")]
+        self.pushed.notify_one();
+    }
+
+    /// wakes every puller up
+    fn notify_all(&self) {
+        self.available.notify_all();
+        #[cfg(feature = "async")]
+        self.pushed.notify_waiters();
+    }
+
+    /// wakes every waiting producer up, each of them checks whether its message fits
+    fn notify_room(&self) {
+        self.room.notify_all();
+        #[cfg(feature = "async")]
+        self.pulled.notify_waiters();
+    }
+}
+
+/// a consumer of a queue, counted until it's dropped
+pub(crate) struct Consumer {
+    queue: Arc<LockedQueue>,
+}
+
+impl Drop for Consumer {
+    fn drop(&mut self) {
+        self.queue.consumers.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// wakes the pullers waiting for a queue up once it's created
+#[derive(Default)]
+struct Creation {
+    created: Condvar,
+    #[cfg(feature = "async")]
+    notify: tokio::sync::Notify,
+}
+
+/// a puller waiting for a queue to be created, its creation is forgotten once nobody waits for
+/// the queue anymore
+struct Awaiting<'a> {
+    queues: &'a Queues,
+    queue: &'a str,
+    creation: Arc<Creation>,
+}
+
+impl<'a> Awaiting<'a> {
+    fn new(queues: &'a Queues, queue: &'a str) -> Self {
+        let creation = queues
+            .creations
+            .lock()
+            .unwrap()
+            .entry(queue.to_string())
+            .or_default()
+            .clone();
+
+        Awaiting {
+            queues,
+            queue,
+            creation,
+        }
+    }
+}
+
+impl Drop for Awaiting<'_> {
+    fn drop(&mut self) {
+        let mut creations = self.queues.creations.lock().unwrap();
+        // the other reference is the map's
+        if Arc::strong_count(&self.creation) == 2 {
+            creations.remove(self.queue);
+        }
+    }
+}
+
+/// the named queues kept by the server and the topics copying messages to them, every change
+/// made to the queues and topics is written to the log (if there's any) before it's applied
+pub(crate) struct Queues {
+    queues: RwLock<HashMap<String, Arc<LockedQueue>>>,
+    /// the queues subscribed to each topic
+    topics: RwLock<HashMap<String, BTreeSet<String>>>,
+    /// the creations of the queues the pullers wait for
+    creations: Mutex<HashMap<String, Arc<Creation>>>,
+    wal: Option<Mutex<Wal>>,
+    next_id: AtomicU64,
+    next_delivery_id: AtomicU64,
+    visibility_timeout: Duration,
+    max_attempts: Option<u32>,
+    /// how long a push to a full queue that blocks its producers waits for room
+    block_timeout: Duration,
+    /// how long the message ids are remembered, duplicates aren't dropped if it's zero
+    dedup_window: Duration,
+}
+
+impl Queues {
+    pub fn new(
+        visibility_timeout: Duration,
+        max_attempts: Option<u32>,
+        block_timeout: Duration,
+        dedup_window: Duration,
+    ) -> Self {
+        Queues {
+            queues: RwLock::new(HashMap::new()),
+            topics: RwLock::new(HashMap::new()),
+            creations: Mutex::new(HashMap::new()),
+            wal: None,
+            next_id: AtomicU64::new(0),
+            next_delivery_id: AtomicU64::new(1),
+            visibility_timeout,
+            max_attempts,
+            block_timeout,
+            dedup_window,
+        }
+    }
+
+    /// rebuilds the queues from the events read from the log, the log is compacted afterwards
+    pub fn restore(
+        mut wal: Wal,
+        events: Vec<Event>,
+        visibility_timeout: Duration,
+        max_attempts: Option<u32>,
+        block_timeout: Duration,
+        dedup_window: Duration,
+    ) -> Result<Self, ServerError> {
+        let mut queues: HashMap<String, Queue> = HashMap::new();
+        let mut topics: HashMap<String, BTreeSet<String>> = HashMap::new();
+        // their connections are gone, they're dropped after the replay
+        let mut reply_queues: HashSet<String> = HashSet::new();
+        let mut next_id = 0;
+
+        for event in events {
+            match event {
+                Event::CreateQueue { queue, config } => {
+                    queues.entry(queue).or_insert_with(|| Queue::new(config));
+                }
+                Event::CreateReplyQueue { queue } => {
+                    queues.entry(queue.clone()).or_default();
+                    reply_queues.insert(queue);
+                }
+                Event::DeleteQueue { queue } => {
+                    queues.remove(&queue);
+                    reply_queues.remove(&queue);
+                    for subscribers in topics.values_mut() {
+                        subscribers.remove(&queue);
+                    }
+                }
+                Event::Enqueue {
+                    queue,
+                    id,
+                    message,
+                    priority,
+                } => {
+                    next_id = next_id.max(id + 1);
+                    queues
+                        .entry(queue)
+                        .or_default()
+                        .ready
+                        .push_back(Entry::new(id, message, priority));
+                }
+                Event::Schedule {
+                    queue,
+                    id,
+                    message,
+                    priority,
+                    deliver_at,
+                } => {
+                    next_id = next_id.max(id + 1);
+                    queues
+                        .entry(queue)
+                        .or_default()
+                        .schedule(deliver_at, Entry::new(id, message, priority));
+                }
+                // scheduled messages are due by the time they're dequeued but that isn't logged
+                Event::Dequeue { queue, id } => {
+                    if let Some(q) = queues.get_mut(&queue) {
+                        q.remove(id);
+                    }
+                }
+                Event::DeadLetter { queue, id } => {
+                    if let Some(q) = queues.get_mut(&queue) {
+                        if let Some(entry) = q.remove(id) {
+                            q.dead_letters.push_back(entry);
+                        }
+                    }
+                }
+                Event::Expire {
+                    queue,
+                    id,
+                    expires_at,
+                } => {
+                    if let Some(entry) = queues.get_mut(&queue).and_then(|q| q.get_mut(id)) {
+                        entry.expires_at = Some(expires_at);
+                    }
+                }
+                Event::Redrive { queue, id } => {
+                    if let Some(q) = queues.get_mut(&queue) {
+                        q.redrive(Some(id));
+                    }
+                }
+                Event::Dedup {
+                    queue,
+                    message_id,
+                    seen_at,
+                } => {
+                    queues
+                        .entry(queue)
+                        .or_default()
+                        .dedup
+                        .insert(&message_id, seen_at);
+                }
+                Event::SubscribeTopic { topic, queue } => {
+                    topics.entry(topic).or_default().insert(queue);
+                }
+                Event::UnsubscribeTopic { topic, queue } => {
+                    if let Some(subscribers) = topics.get_mut(&topic) {
+                        subscribers.remove(&queue);
+                    }
+                }
+            }
+        }
+        // the groups are gone along with their members
+        let groups = queues.keys().filter(|queue| Groups::is_group_queue(queue));
+        let orphans: Vec<String> = groups.chain(&reply_queues).cloned().collect();
+        for queue in &orphans {
+            queues.remove(queue);
+            for subscribers in topics.values_mut() {
+                subscribers.remove(queue);
+            }
+        }
+        topics.retain(|_, subscribers| !subscribers.is_empty());
+        let since = dedup_since(dedup_window);
+        for q in queues.values_mut() {
+            q.dedup.forget(since);
+        }
+
+        if let Err(e) = wal.compact(&queues, &topics) {
+            return Err(ServerError::LogError(e.to_string()));
+        }
+        info!("Restored {} queues from the message log", queues.len());
+
+        let queues = queues
+            .into_iter()
+            .map(|(name, q)| (name, Arc::new(LockedQueue::new(q))))
+            .collect();
+
+        Ok(Queues {
+            queues: RwLock::new(queues),
+            topics: RwLock::new(topics),
+            creations: Mutex::new(HashMap::new()),
+            wal: Some(Mutex::new(wal)),
+            next_id: AtomicU64::new(next_id),
+            next_delivery_id: AtomicU64::new(1),
+            visibility_timeout,
+            max_attempts,
+            block_timeout,
+            dedup_window,
+        })
+    }
+
+    pub fn get(&self, queue: &str) -> Option<Arc<LockedQueue>> {
+        self.queues.read().unwrap().get(queue).cloned()
+    }
+
+    /// returns `false` if the queue already exists, its config is kept as it is
+    pub fn create(&self, queue: &str, config: QueueConfig) -> Result<bool, ServerError> {
+        Ok(self.get_or_insert(queue, config)?.1)
+    }
+
+    /// gets the queue or creates it under the same write lock, so a queue deleted in between
+    /// isn't missed. Tells whether the queue has been created
+    fn get_or_insert(
+        &self,
+        queue: &str,
+        config: QueueConfig,
+    ) -> Result<(Arc<LockedQueue>, bool), ServerError> {
+        let q = {
+            let mut queues = self.queues.write().unwrap();
+            if let Some(q) = queues.get(queue) {
+                return Ok((q.clone(), false));
+            }
+
+            self.log(|wal| wal.log_create_queue(queue, &config))?;
+            let q = Arc::new(LockedQueue::new(Queue::new(config)));
+            queues.insert(queue.to_string(), q.clone());
+            q
+        };
+        info!("Created queue {} with {:?}", queue, config);
+
+        // the pullers look the queue up while holding the lock of the creations
+        if let Some(creation) = self.creations.lock().unwrap().get(queue) {
+            creation.created.notify_all();
+            #[cfg(feature = "async")]
+            creation.notify.notify_waiters();
+        }
+
+        Ok((q, true))
+    }
+
+    /// waits until the queue exists, `None` if it still doesn't by the deadline or once
+    /// `waiting` turns false
+    fn wait_created(
+        &self,
+        queue: &str,
+        deadline: Instant,
+        waiting: &dyn Fn() -> bool,
+    ) -> Option<Arc<LockedQueue>> {
+        if let Some(lq) = self.get(queue) {
+            return Some(lq);
+        }
+
+        let awaiting = Awaiting::new(self, queue);
+        let mut creations = self.creations.lock().unwrap();
+        loop {
+            if let Some(lq) = self.get(queue) {
+                return Some(lq);
+            }
+
+            let now = Instant::now();
+            if now >= deadline || !waiting() {
+                return None;
+            }
+            creations = awaiting
+                .creation
+                .created
+                .wait_timeout(creations, (deadline - now).min(CHECK_INTERVAL))
+                .unwrap()
+                .0;
+        }
+    }
+
+    /// the async version of `wait_created`
+    #[cfg(feature = "async")]
+    async fn wait_created_async(&self, queue: &str, deadline: Instant) -> Option<Arc<LockedQueue>> {
+        if let Some(lq) = self.get(queue) {
+            return Some(lq);
+        }
+
+        let awaiting = Awaiting::new(self, queue);
+        loop {
+            // registered before looking the queue up so a creation in between isn't missed
+            let created = awaiting.creation.notify.notified();
+            tokio::pin!(created);
+            created.as_mut().enable();
+
+            if let Some(lq) = self.get(queue) {
+                return Some(lq);
+            }
+            if Instant::now() >= deadline {
+                return None;
+            }
+            let _ = tokio::time::timeout_at(deadline.into(), created).await;
+        }
+    }
+
+    /// creates a queue that isn't kept after a restart, the connection consuming it is gone by
+    /// then
+    pub fn create_reply_queue(&self, queue: &str) -> Result<(), ServerError> {
+        let mut queues = self.queues.write().unwrap();
+
+        self.log(|wal| wal.log_create_reply_queue(queue))?;
+        let q = Arc::new(LockedQueue::new(Queue::default()));
+        queues.insert(queue.to_string(), q);
+        info!("Created reply queue {}", queue);
+
+        Ok(())
+    }
+
+    /// removes a queue along with its messages and its topic subscriptions, returns `false` if
+    /// there's no such queue
+    pub fn delete(&self, queue: &str) -> Result<bool, ServerError> {
+        self.remove(queue, false)
+    }
+
+    /// deletes the queue like `delete` unless it has consumers, the subscriptions would go on
+    /// streaming a queue that's gone
+    pub fn delete_unused(&self, queue: &str) -> Result<bool, ServerError> {
+        self.remove(queue, true)
+    }
+
+    fn remove(&self, queue: &str, unused: bool) -> Result<bool, ServerError> {
+        let lq = {
+            let mut queues = self.queues.write().unwrap();
+            let consumers = match queues.get(queue) {
+                Some(lq) => lq.consumers.load(Ordering::SeqCst),
+                None => return Ok(false),
+            };
+            if unused && consumers > 0 {
+                return Err(ServerError::QueueInUse);
+            }
+
+            self.log(|wal| wal.log_delete_queue(queue))?;
+            queues.remove(queue).unwrap()
+        };
+
+        let mut topics = self.topics.write().unwrap();
+        for subscribers in topics.values_mut() {
+            subscribers.remove(queue);
+        }
+        topics.retain(|_, subscribers| !subscribers.is_empty());
+        // the pullers and the producers waiting on the queue give up once their timeout expires
+        lq.notify_all();
+        lq.notify_room();
+        info!("Deleted queue {}", queue);
+
+        Ok(true)
+    }
+
+    pub fn get_or_create(&self, queue: &str) -> Result<Arc<LockedQueue>, ServerError> {
+        if let Some(q) = self.get(queue) {
+            return Ok(q);
+        }
+
+        Ok(self.get_or_insert(queue, QueueConfig::default())?.0)
+    }
+
+    pub fn push(&self, queue: &str, envelope: Envelope) -> Result<(), ServerError> {
+        let lq = self.get_or_create(queue)?;
+        let mut q = lq.lock();
+
+        self.push_locked(queue, &lq, &mut q, envelope)
+    }
+
+    /// pushes the messages in order while holding the queue's lock once, each message gets its
+    /// own result. The batch never waits for room
+    pub fn push_batch(
+        &self,
+        queue: &str,
+        envelopes: Vec<Envelope>,
+    ) -> Result<Vec<Result<(), ServerError>>, ServerError> {
+        let lq = self.get_or_create(queue)?;
+        let mut q = lq.lock();
+
+        Ok(envelopes
+            .into_iter()
+            .map(|envelope| self.push_locked(queue, &lq, &mut q, envelope))
+            .collect())
+    }
+
+    /// whether the pushes to the queue wait for room when it's full
+    pub fn blocks(&self, queue: &str) -> bool {
+        self.get(queue)
+            .is_some_and(|lq| lq.lock().config.overflow == Overflow::Block)
+    }
+
+    /// pushes a message, waiting for room until the block timeout expires if the queue is full
+    /// and blocks its producers
+    pub fn push_wait(&self, queue: &str, envelope: Envelope) -> Result<(), ServerError> {
+        self.push_wait_while(queue, envelope, || true)
+    }
+
+    /// pushes like `push_wait` but gives up on the push once `waiting` turns false, it's checked
+    /// at least every `CHECK_INTERVAL`
+    pub fn push_wait_while<W>(
+        &self,
+        queue: &str,
+        envelope: Envelope,
+        waiting: W,
+    ) -> Result<(), ServerError>
+    where
+        W: Fn() -> bool,
+    {
+        let lq = self.get_or_create(queue)?;
+        let deadline = Instant::now() + self.block_timeout;
+        let mut q = lq.lock();
+
+        loop {
+            match self.push_locked(queue, &lq, &mut q, envelope.clone()) {
+                Err(ServerError::QueueFull) if q.config.overflow == Overflow::Block => {}
+                result => return result,
+            }
+
+            let now = Instant::now();
+            if now >= deadline {
+                q.counters.rejected += 1;
+                return Err(ServerError::QueueFull);
+            }
+            if !waiting() {
+                return Err(ServerError::QueueFull);
+            }
+            q = lq
+                .room
+                .wait_timeout(q, (deadline - now).min(CHECK_INTERVAL))
+                .unwrap()
+                .0;
+        }
+    }
+
+    /// the async version of `push_wait`, the runtime's threads are not blocked while waiting
+    #[cfg(feature = "async")]
+    pub async fn push_wait_async(
+        &self,
+        queue: &str,
+        envelope: Envelope,
+    ) -> Result<(), ServerError> {
+        let lq = self.get_or_create(queue)?;
+        let deadline = Instant::now() + self.block_timeout;
+
+        loop {
+            // registered before looking at the queue so a pull in between isn't missed
+            let pulled = lq.pulled.notified();
+            tokio::pin!(pulled);
+            pulled.as_mut().enable();
+
+            {
+                let mut q = lq.lock();
+                match self.push_locked(queue, &lq, &mut q, envelope.clone()) {
+                    Err(ServerError::QueueFull) if q.config.overflow == Overflow::Block => {}
+                    result => return result,
+                }
+            }
+
+            if Instant::now() >= deadline {
+                lq.lock().counters.rejected += 1;
+                return Err(ServerError::QueueFull);
+            }
+            let _ = tokio::time::timeout_at(deadline.into(), pulled).await;
+        }
+    }
+
+    fn push_locked(
+        &self,
+        queue: &str,
+        lq: &LockedQueue,
+        q: &mut Queue,
+        envelope: Envelope,
+    ) -> Result<(), ServerError> {
+        if self.is_duplicate(queue, q, &envelope) {
+            return Ok(());
+        }
+
+        let now = now_millis();
+        let entry = self.entry(q, envelope, now);
+        if let Err(e) = self.make_room(queue, q, entry.size()) {
+            // the pushes waiting for room are counted once they give up
+            if q.config.overflow != Overflow::Block {
+                q.counters.rejected += 1;
+            }
+            return Err(e);
+        }
+        let message_id = self.dedup_id(&entry.message);
+        self.log(|wal| wal.log_push(queue, &entry, None, message_id.map(|id| (id, now))))?;
+        if let Some(message_id) = message_id {
+            q.dedup.insert(message_id, now);
+        }
+        q.ready.push_back(entry);
+        q.counters.enqueued += 1;
+        lq.notify_one();
+
+        Ok(())
+    }
+
+    /// the message is pushed right away if it's already due, its ttl is counted from when it's
+    /// due
+    pub fn schedule(
+        &self,
+        queue: &str,
+        envelope: Envelope,
+        deliver_at: u64,
+    ) -> Result<(), ServerError> {
+        if deliver_at <= now_millis() {
+            return self.push(queue, envelope);
+        }
+
+        let lq = self.get_or_create(queue)?;
+        let mut q = lq.lock();
+        if self.is_duplicate(queue, &mut q, &envelope) {
+            return Ok(());
+        }
+
+        let now = now_millis();
+        let entry = self.entry(&q, envelope, deliver_at);
+        if let Err(e) = self.make_room(queue, &mut q, entry.size()) {
+            q.counters.rejected += 1;
+            return Err(e);
+        }
+        let message_id = self.dedup_id(&entry.message);
+        self.log(|wal| {
+            wal.log_push(
+                queue,
+                &entry,
+                Some(deliver_at),
+                message_id.map(|id| (id, now)),
+            )
+        })?;
+        if let Some(message_id) = message_id {
+            q.dedup.insert(message_id, now);
+        }
+        q.schedule(deliver_at, entry);
+        q.counters.enqueued += 1;
+        // the pullers have to wake up earlier if it's the next message due
+        lq.notify_all();
+
+        Ok(())
+    }
+
+    pub fn pop(&self, queue: &str) -> Option<Message> {
+        let lq = self.get(queue)?;
+        let mut q = lq.lock();
+
+        self.pop_locked(queue, &lq, &mut q)
+    }
+
+    /// pops up to `max` messages while holding the queue's lock once, the messages stop before
+    /// their list takes more than `max_bytes`
+    pub fn pop_batch(&self, queue: &str, max: usize, max_bytes: usize) -> Vec<Message> {
+        let lq = match self.get(queue) {
+            Some(lq) => lq,
+            None => return vec![],
+        };
+        let mut q = lq.lock();
+
+        let mut messages = vec![];
+        let mut size = LIST_HEADER_SIZE;
+        while messages.len() < max {
+            let entry = match self.pop_ready(queue, &mut q) {
+                Some(entry) => entry,
+                None => break,
+            };
+            // the first message is handed out whatever its size, like a pull does
+            size += list_item_size(&entry.message);
+            if size > max_bytes && !messages.is_empty() {
+                q.ready.push_front(entry);
+                break;
+            }
+            messages.push(self.hand_out(queue, &lq, &mut q, entry));
+        }
+
+        messages
+    }
+
+    /// waits until a message is available or the timeout expires, a missing queue isn't created
+    /// but waited for until a push creates it
+    pub fn pop_wait(&self, queue: &str, timeout: Duration) -> Option<Message> {
+        self.pop_wait_while(queue, timeout, || true)
+    }
+
+    /// waits like `pop_wait` but stops waiting once `waiting` turns false, it's checked at least
+    /// every `CHECK_INTERVAL`
+    pub fn pop_wait_while<W>(&self, queue: &str, timeout: Duration, waiting: W) -> Option<Message>
+    where
+        W: Fn() -> bool,
+    {
+        self.wait(queue, timeout, &waiting, |lq, q| {
+            self.pop_locked(queue, lq, q)
+        })
+    }
+
+    /// waits like `pop_wait` and reserves the message for a subscription, returns the delivery
+    /// id along with it. The message stays reserved until the subscription acknowledges or
+    /// requeues it
+    pub fn hold_wait(&self, queue: &str, timeout: Duration) -> Option<(u64, Message)> {
+        self.wait(queue, timeout, &|| true, |lq, q| {
+            let (delivery_id, _, message) = self.reserve_locked(queue, lq, q, None)?;
+            Some((delivery_id, message))
+        })
+    }
+
+    /// waits until `take` gets something out of the queue, the timeout expires or `waiting`
+    /// turns false
+    fn wait<T, F>(
+        &self,
+        queue: &str,
+        timeout: Duration,
+        waiting: &dyn Fn() -> bool,
+        mut take: F,
+    ) -> Option<T>
+    where
+        F: FnMut(&LockedQueue, &mut Queue) -> Option<T>,
+    {
+        let deadline = Instant::now() + timeout;
+        let lq = self.wait_created(queue, deadline, waiting)?;
+        let mut q = lq.lock();
+
+        loop {
+            if let Some(taken) = take(&lq, &mut q) {
+                return Some(taken);
+            }
+
+            let now = Instant::now();
+            if now >= deadline || !waiting() {
+                return None;
+            }
+
+            // reserved messages can expire while waiting, nobody notifies about it
+            let wake_at = match q.next_deadline() {
+                Some(next) => next.min(deadline),
+                None => deadline,
+            }
+            .min(now + CHECK_INTERVAL);
+            q = lq
+                .available
+                .wait_timeout(q, wake_at.saturating_duration_since(now))
+                .unwrap()
+                .0;
+        }
+    }
+
+    /// the async version of `pop_wait`, the runtime's threads are not blocked while waiting
+    #[cfg(feature = "async")]
+    pub async fn pop_wait_async(&self, queue: &str, timeout: Duration) -> Option<Message> {
+        self.wait_async(queue, timeout, |lq, q| self.pop_locked(queue, lq, q))
+            .await
+    }
+
+    /// the async version of `hold_wait`
+    #[cfg(feature = "async")]
+    pub async fn hold_wait_async(&self, queue: &str, timeout: Duration) -> Option<(u64, Message)> {
+        self.wait_async(queue, timeout, |lq, q| {
+            let (delivery_id, _, message) = self.reserve_locked(queue, lq, q, None)?;
+            Some((delivery_id, message))
+        })
+        .await
+    }
+
+    /// the async version of `wait`
+    #[cfg(feature = "async")]
+    async fn wait_async<T, F>(&self, queue: &str, timeout: Duration, mut take: F) -> Option<T>
+    where
+        F: FnMut(&LockedQueue, &mut Queue) -> Option<T>,
+    {
+        let deadline = Instant::now() + timeout;
+        let lq = self.wait_created_async(queue, deadline).await?;
+
+        loop {
+            // registered before looking at the queue so a push in between isn't missed
+            let pushed = lq.pushed.notified();
+            tokio::pin!(pushed);
+            pushed.as_mut().enable();
+
+            let next_deadline = {
+                let mut q = lq.lock();
+                if let Some(taken) = take(&lq, &mut q) {
+                    return Some(taken);
+                }
+                q.next_deadline()
+            };
+
+            if Instant::now() >= deadline {
+                return None;
+            }
+
+            // reserved messages can expire while waiting, nobody notifies about it
+            let wake_at = match next_deadline {
+                Some(next) => next.min(deadline),
+                None => deadline,
+            };
+            let _ = tokio::time::timeout_at(wake_at.into(), pushed).await;
+        }
+    }
+
+    fn pop_locked(&self, queue: &str, lq: &LockedQueue, q: &mut Queue) -> Option<Message> {
+        let entry = self.pop_ready(queue, q)?;
+        Some(self.hand_out(queue, lq, q, entry))
+    }
+
+    /// removes a popped message from the queue for good
+    fn hand_out(&self, queue: &str, lq: &LockedQueue, q: &mut Queue, entry: Entry) -> Message {
+        q.counters.dequeued += 1;
+        lq.notify_room();
+        // the message is handed out anyway, at worst it's delivered again after a restart
+        if let Err(e) = self.log(|wal| wal.log_dequeue(queue, entry.id)) {
+            error!("Can't log dequeue of message {}: {:?}", entry.id, e);
+        }
+
+        entry.message
+    }
+
+    /// returns the delivery id and the delivery attempts along with the message, the timeout
+    /// defaults to the server's visibility timeout
+    pub fn reserve(&self, queue: &str, timeout: Option<Duration>) -> Option<(u64, u32, Message)> {
+        let lq = self.get(queue)?;
+        let mut q = lq.lock();
+        let timeout = timeout.unwrap_or(self.visibility_timeout);
+
+        self.reserve_locked(queue, &lq, &mut q, Some(timeout))
+    }
+
+    /// reserves the next message, without a timeout it's only settled by an ack, a nack or a
+    /// requeue
+    fn reserve_locked(
+        &self,
+        queue: &str,
+        lq: &LockedQueue,
+        q: &mut Queue,
+        timeout: Option<Duration>,
+    ) -> Option<(u64, u32, Message)> {
+        let entry = self.pop_ready(queue, q)?;
+        q.counters.dequeued += 1;
+        lq.notify_room();
+
+        let delivery_id = self.next_delivery_id.fetch_add(1, Ordering::SeqCst);
+        let (attempts, message) = q.reserve(entry, delivery_id, timeout);
+
+        Some((delivery_id, attempts, message))
+    }
+
+    /// removes a reserved message from the queue, returns `false` if the delivery is unknown
+    pub fn ack(&self, queue: &str, delivery_id: u64) -> Result<bool, ServerError> {
+        let lq = match self.get(queue) {
+            Some(lq) => lq,
+            None => return Ok(false),
+        };
+        let mut q = lq.lock();
+        self.release_expired(queue, &mut q);
+
+        let entry = match q.take_in_flight(delivery_id) {
+            Some(entry) => entry,
+            None => return Ok(false),
+        };
+        if let Err(e) = self.log(|wal| wal.log_dequeue(queue, entry.id)) {
+            q.ready.push_front(entry);
+            return Err(e);
+        }
+        // the next message of the group can be handed out
+        if entry.group().is_some() {
+            lq.notify_all();
+        }
+
+        Ok(true)
+    }
+
+    /// puts a reserved message back to the head of the queue (or to the dead letters), returns
+    /// `false` if the delivery is unknown
+    pub fn nack(&self, queue: &str, delivery_id: u64) -> bool {
+        let lq = match self.get(queue) {
+            Some(lq) => lq,
+            None => return false,
+        };
+        let mut q = lq.lock();
+        self.release_expired(queue, &mut q);
+
+        let entry = match q.take_in_flight(delivery_id) {
+            Some(entry) => entry,
+            None => return false,
+        };
+        let id = entry.id;
+        let grouped = entry.group().is_some();
+        if q.release(entry, self.max_attempts) {
+            self.log_dead_letter(queue, id);
+            // the next message of the group can be handed out
+            if grouped {
+                lq.notify_all();
+            }
+        } else {
+            lq.notify_one();
+        }
+
+        true
+    }
+
+    /// puts reserved messages that never reached their consumer back to the head of the queue, in
+    /// the order they were reserved. Their delivery isn't counted as an attempt
+    pub fn requeue(&self, queue: &str, delivery_ids: &[u64]) {
+        let lq = match self.get(queue) {
+            Some(lq) => lq,
+            None => return,
+        };
+        let mut q = lq.lock();
+
+        for delivery_id in delivery_ids.iter().rev() {
+            if let Some(mut entry) = q.take_in_flight(*delivery_id) {
+                entry.attempts -= 1;
+                q.ready.push_front(entry);
+            }
+        }
+        lq.notify_all();
+    }
+
+    pub fn dead_letters(&self, queue: &str) -> Vec<DeadLetter> {
+        let lq = match self.get(queue) {
+            Some(lq) => lq,
+            None => return vec![],
+        };
+        let mut q = lq.lock();
+        self.release_expired(queue, &mut q);
+
+        q.dead_letters.iter().map(Entry::to_dead_letter).collect()
+    }
+
+    pub fn dead_letter(&self, queue: &str, id: u64) -> Option<DeadLetter> {
+        let lq = self.get(queue)?;
+        let q = lq.lock();
+
+        q.dead_letters
+            .iter()
+            .find(|entry| entry.id == id)
+            .map(Entry::to_dead_letter)
+    }
+
+    /// moves a dead letter (or every dead letter) back to the tail of the queue, returns how many
+    /// messages are moved
+    pub fn redrive(&self, queue: &str, id: Option<u64>) -> Result<usize, ServerError> {
+        let lq = match self.get(queue) {
+            Some(lq) => lq,
+            None => return Ok(0),
+        };
+        let mut q = lq.lock();
+
+        let ids = q.redrive(id);
+        lq.notify_all();
+        for id in &ids {
+            self.log(|wal| wal.log_redrive(queue, *id))?;
+        }
+        info!("Redrove {} messages of queue {}", ids.len(), queue);
+
+        Ok(ids.len())
+    }
+
+    /// the queue is created if it doesn't exist, returns `false` if the queue is already
+    /// subscribed
+    pub fn subscribe_topic(&self, topic: &str, queue: &str) -> Result<bool, ServerError> {
+        self.get_or_create(queue)?;
+
+        let mut topics = self.topics.write().unwrap();
+        if topics.get(topic).is_some_and(|s| s.contains(queue)) {
+            return Ok(false);
+        }
+
+        self.log(|wal| wal.log_subscribe_topic(topic, queue))?;
+        topics
+            .entry(topic.to_string())
+            .or_default()
+            .insert(queue.to_string());
+        info!("Subscribed queue {} to topic {}", queue, topic);
+
+        Ok(true)
+    }
+
+    /// returns `false` if the queue isn't subscribed
+    pub fn unsubscribe_topic(&self, topic: &str, queue: &str) -> Result<bool, ServerError> {
+        let mut topics = self.topics.write().unwrap();
+        let subscribers = match topics.get_mut(topic) {
+            Some(subscribers) if subscribers.contains(queue) => subscribers,
+            _ => return Ok(false),
+        };
+
+        self.log(|wal| wal.log_unsubscribe_topic(topic, queue))?;
+        subscribers.remove(queue);
+        if subscribers.is_empty() {
+            topics.remove(topic);
+        }
+        info!("Unsubscribed queue {} from topic {}", queue, topic);
+
+        Ok(true)
+    }
+
+    /// copies the message to every queue subscribed to the topic, returns how many queues the
+    /// message is copied to. The queues that are full are skipped, publishing never waits
+    pub fn publish(&self, topic: &str, message: Message) -> Result<usize, ServerError> {
+        let subscribers: Vec<String> = match self.topics.read().unwrap().get(topic) {
+            Some(subscribers) => subscribers.iter().cloned().collect(),
+            None => return Ok(0),
+        };
+
+        // the message's data is shared between the copies
+        let mut copies = 0;
+        for queue in &subscribers {
+            match self.push(queue, Envelope::new(message.clone(), 0, None)) {
+                Ok(_) => copies += 1,
+                Err(ServerError::QueueFull) => {
+                    warn!("Queue {} missed a message of topic {}", queue, topic);
+                    // the copies never wait for room
+                    if let Some(lq) = self.get(queue) {
+                        let mut q = lq.lock();
+                        if q.config.overflow == Overflow::Block {
+                            q.counters.rejected += 1;
+                        }
+                    }
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(copies)
+    }
+
+    /// drops (or dead-letters) the expired messages of every queue, returns how many messages
+    /// have expired
+    pub fn reap(&self) -> usize {
+        let queues: Vec<(String, Arc<LockedQueue>)> = self
+            .queues
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(queue, lq)| (queue.clone(), lq.clone()))
+            .collect();
+
+        let mut reaped = 0;
+        for (queue, lq) in queues {
+            let mut q = lq.lock();
+            q.dedup.forget(dedup_since(self.dedup_window));
+            self.release_expired(&queue, &mut q);
+            let expired = q.ready.remove_expired(now_millis());
+            if expired.is_empty() {
+                continue;
+            }
+            reaped += expired.len();
+            self.expire(&queue, &mut q, expired);
+            lq.notify_room();
+        }
+
+        reaped
+    }
+
+    /// the names of the queues, sorted
+    pub fn names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.queues.read().unwrap().keys().cloned().collect();
+        names.sort();
+        names
+    }
+
+    pub fn stats(&self, queue: &str) -> Option<QueueStats> {
+        let lq = self.get(queue)?;
+        let mut q = lq.lock();
+        self.release_expired(queue, &mut q);
+
+        Some(QueueStats {
+            depth: q.ready.len as u64,
+            scheduled: q.scheduled.len() as u64,
+            bytes: (q.ready.bytes + q.scheduled_bytes) as u64,
+            in_flight: q.in_flight.len() as u64,
+            dead_letters: q.dead_letters.len() as u64,
+            consumers: lq.consumers.load(Ordering::SeqCst),
+        })
+    }
+
+    pub fn counters(&self, queue: &str) -> Option<Counters> {
+        Some(self.get(queue)?.lock().counters)
+    }
+
+    /// how many messages are ready, as they are: unlike `stats` the expired reservations aren't
+    /// released first, so nothing is written to the log
+    pub fn depth(&self, queue: &str) -> Option<u64> {
+        Some(self.get(queue)?.lock().ready.len as u64)
+    }
+
+    /// counts a consumer of the queue until the consumer is dropped, `None` if there's no such
+    /// queue
+    pub fn consumer(&self, queue: &str) -> Option<Consumer> {
+        // counted while the queues are locked so a deletion doesn't miss it
+        let queues = self.queues.read().unwrap();
+        let lq = queues.get(queue)?.clone();
+        lq.consumers.fetch_add(1, Ordering::SeqCst);
+
+        Some(Consumer { queue: lq })
+    }
+
+    /// the next messages that haven't expired without removing them, the groups aren't taken into
+    /// account. The messages stop before their list takes more than `max_bytes`
+    pub fn peek(&self, queue: &str, max: usize, max_bytes: usize) -> Vec<Message> {
+        let lq = match self.get(queue) {
+            Some(lq) => lq,
+            None => return vec![],
+        };
+        let mut q = lq.lock();
+        self.release_expired(queue, &mut q);
+
+        let now = now_millis();
+        let mut size = LIST_HEADER_SIZE;
+        q.ready
+            .iter()
+            .filter(|entry| !entry.is_expired(now))
+            .take(max)
+            .enumerate()
+            .take_while(|(i, entry)| {
+                size += list_item_size(&entry.message);
+                *i == 0 || size <= max_bytes
+            })
+            .map(|(_, entry)| entry.message.clone())
+            .collect()
+    }
+
+    /// removes the ready and the scheduled messages, returns how many messages are removed
+    pub fn purge(&self, queue: &str) -> usize {
+        let lq = match self.get(queue) {
+            Some(lq) => lq,
+            None => return 0,
+        };
+        let mut q = lq.lock();
+        self.release_expired(queue, &mut q);
+
+        let mut purged: Vec<u64> = std::iter::from_fn(|| q.ready.pop_front())
+            .map(|entry| entry.id)
+            .collect();
+        purged.extend(q.scheduled.values().map(|entry| entry.id));
+        q.scheduled.clear();
+        q.scheduled_bytes = 0;
+        lq.notify_room();
+
+        // the messages are gone anyway, at worst they're back after a restart
+        if let Err(e) = self.log(|wal| {
+            for id in &purged {
+                wal.log_dequeue(queue, *id)?;
+            }
+            Ok(())
+        }) {
+            error!("Can't log purge of queue {}: {:?}", queue, e);
+        }
+        info!("Purged {} messages of queue {}", purged.len(), queue);
+
+        purged.len()
+    }
+
+    /// flushes the log to the disk
+    pub fn sync(&self) -> Result<(), ServerError> {
+        self.log(|wal| wal.sync())
+    }
+
+    /// releases the expired reservations and moves the scheduled messages that are due to the
+    /// tail of the queue
+    fn release_expired(&self, queue: &str, q: &mut Queue) {
+        for id in q.release_expired(Instant::now(), self.max_attempts) {
+            self.log_dead_letter(queue, id);
+        }
+        q.promote_scheduled(now_millis());
+    }
+
+    /// whether the message's id has been pushed to the queue within the dedup window, the
+    /// duplicate is acknowledged without being enqueued
+    fn is_duplicate(&self, queue: &str, q: &mut Queue, envelope: &Envelope) -> bool {
+        let message_id = match self.dedup_id(envelope.get_message()) {
+            Some(message_id) => message_id,
+            None => return false,
+        };
+
+        q.dedup.forget(dedup_since(self.dedup_window));
+        if !q.dedup.contains(message_id) {
+            return false;
+        }
+        info!(
+            "Dropped duplicate message {} of queue {}",
+            message_id, queue
+        );
+        true
+    }
+
+    /// the id duplicates of the message are dropped by, if deduplication is enabled
+    fn dedup_id<'a>(&self, message: &'a Message) -> Option<&'a str> {
+        if self.dedup_window.is_zero() {
+            return None;
+        }
+        message.get_message_id()
+    }
+
+    /// a new message of the queue, the ttl is counted from `from`
+    fn entry(&self, q: &Queue, envelope: Envelope, from: u64) -> Entry {
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        let priority = envelope.get_priority();
+        let expires_at = q.expires_at(envelope.get_ttl(), from);
+
+        let mut entry = Entry::new(id, envelope.into_message(), priority);
+        entry.expires_at = expires_at;
+        entry
+    }
+
+    /// makes room for a message of `size` bytes, the oldest messages are dropped if that's the
+    /// queue's overflow policy
+    fn make_room(&self, queue: &str, q: &mut Queue, size: usize) -> Result<(), ServerError> {
+        // no amount of dropped messages makes room for it
+        if q.config.max_bytes.is_some_and(|max| size as u64 > max) {
+            return Err(ServerError::QueueFull);
+        }
+
+        while q.is_full(size) {
+            let oldest = match q.config.overflow {
+                Overflow::DropOldest => q.ready.pop_oldest(),
+                Overflow::RejectNew | Overflow::Block => None,
+            };
+            let entry = match oldest {
+                Some(entry) => entry,
+                None => {
+                    warn!("Queue {} is full", queue);
+                    return Err(ServerError::QueueFull);
+                }
+            };
+
+            if let Err(e) = self.log(|wal| wal.log_dequeue(queue, entry.id)) {
+                q.ready.push_front(entry);
+                return Err(e);
+            }
+            warn!(
+                "Dropped message {} of queue {} to make room",
+                entry.id, queue
+            );
+        }
+
+        Ok(())
+    }
+
+    /// pops the next message that hasn't expired, the expired messages are dropped or
+    /// dead-lettered on the way
+    fn pop_ready(&self, queue: &str, q: &mut Queue) -> Option<Entry> {
+        self.release_expired(queue, q);
+
+        let mut expired = vec![];
+        let entry = q.pop_ready(now_millis(), &mut expired);
+        self.expire(queue, q, expired);
+
+        entry
+    }
+
+    /// drops the expired messages, or moves them to the dead letters if the queue keeps them
+    fn expire(&self, queue: &str, q: &mut Queue, expired: Vec<Entry>) {
+        for entry in expired {
+            let id = entry.id;
+            if q.config.dead_letter_expired {
+                q.dead_letters.push_back(entry);
+                self.log_dead_letter(queue, id);
+                continue;
+            }
+
+            info!("Message {} of queue {} has expired", id, queue);
+            // the message is gone anyway, at worst it expires again after a restart
+            if let Err(e) = self.log(|wal| wal.log_dequeue(queue, id)) {
+                error!("Can't log expiry of message {}: {:?}", id, e);
+            }
+        }
+    }
+
+    fn log_dead_letter(&self, queue: &str, id: u64) {
+        warn!("Message {} of queue {} is dead-lettered", id, queue);
+        // the message is kept in memory anyway, at worst it's delivered again after a restart
+        if let Err(e) = self.log(|wal| wal.log_dead_letter(queue, id)) {
+            error!("Can't log dead letter of message {}: {:?}", id, e);
+        }
+    }
+
+    fn log<F>(&self, f: F) -> Result<(), ServerError>
+    where
+        F: FnOnce(&mut Wal) -> std::io::Result<()>,
+    {
+        let wal = match &self.wal {
+            Some(wal) => wal,
+            None => return Ok(()),
+        };
+        let write =
+            || f(&mut wal.lock().unwrap()).map_err(|e| ServerError::LogError(e.to_string()));
+
+        // the async server's tasks are moved to the other threads while the log is written
+        #[cfg(feature = "async")]
+        if on_runtime() {
+            return tokio::task::block_in_place(write);
+        }
+        write()
+    }
+}
+
+/// whether the thread runs on the async server's runtime
+#[cfg(feature = "async")]
+fn on_runtime() -> bool {
+    Handle::try_current().is_ok_and(|handle| handle.runtime_flavor() == RuntimeFlavor::MultiThread)
+}
+
+/// how often the waits check whether their requester still waits, nobody notifies them when it
+/// stops
+const CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+/// the size of a list's count, see `Message::serialize_list`
+const LIST_HEADER_SIZE: usize = 4;
+
+/// the size a message takes in a list, its own size comes first
+fn list_item_size(message: &Message) -> usize {
+    8 + message.serialize().len()
+}
+
+/// the ids pushed before it are forgotten
+fn dedup_since(window: Duration) -> u64 {
+    now_millis().saturating_sub(window.as_millis() as u64)
+}
+
+/// the current time in milliseconds since the unix epoch
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
@@ -0,0 +1,34 @@
+use crate::queue::Queues;
+use log::info;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// drops (or dead-letters) the expired messages of the queues every interval, so they don't
+/// count towards the retention limits until they're pulled
+pub(crate) struct Reaper {
+    stop: mpsc::Sender<()>,
+    thread: JoinHandle<()>,
+}
+
+impl Reaper {
+    pub fn start(queues: Arc<Queues>, interval: Duration) -> Self {
+        let (stop, stopped) = mpsc::channel();
+        let thread = thread::spawn(move || {
+            while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                let reaped = queues.reap();
+                if reaped > 0 {
+                    info!("Reaped {} expired messages", reaped);
+                }
+            }
+        });
+
+        Reaper { stop, thread }
+    }
+
+    pub fn stop(self) {
+        let _ = self.stop.send(());
+        let _ = self.thread.join();
+    }
+}
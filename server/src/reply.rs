@@ -0,0 +1,125 @@
+use crate::queue::Queues;
+use crate::server::ServerImpl;
+use log::{error, info};
+use smq_lib::enums::command::Command;
+use smq_lib::enums::errors::ServerError;
+use smq_lib::enums::status::Status;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use uuid::Uuid;
+
+/// the names of the reply queues start with it, the requests to a reply queue that doesn't exist
+/// are refused so no other queue is named like that
+const REPLY_QUEUE_PREFIX: &str = "#reply-";
+
+/// the temporary reply queues along with the connection owning each of them. Only its owner
+/// consumes a reply queue, the other connections can only push to it. A reply queue is deleted
+/// once its owner's connection is closed
+pub(crate) struct ReplyQueues {
+    owners: Mutex<HashMap<String, Uuid>>,
+}
+
+impl ReplyQueues {
+    pub fn new() -> Self {
+        ReplyQueues {
+            owners: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// creates a reply queue owned by a connection, returns the queue's name
+    pub fn create(&self, queues: &Queues, owner: Uuid) -> Result<String, ServerError> {
+        let queue = format!("{}{}", REPLY_QUEUE_PREFIX, Uuid::new_v4());
+        queues.create_reply_queue(&queue)?;
+        self.owners.lock().unwrap().insert(queue.clone(), owner);
+
+        Ok(queue)
+    }
+
+    /// the error is the response to a request a connection isn't allowed to make, the requests
+    /// to the reply queues that are gone are refused so the queues aren't created again
+    pub fn check(&self, command: Command, queue: &str, connection: Uuid) -> Result<(), Vec<u8>> {
+        // the queue name of these requests isn't a queue
+        if !queue.starts_with(REPLY_QUEUE_PREFIX)
+            || matches!(
+                command,
+                Command::Hello | Command::CreateReplyQueue | Command::ListQueues
+            )
+        {
+            return Ok(());
+        }
+
+        let owner = match self.owners.lock().unwrap().get(queue) {
+            Some(owner) => *owner,
+            None => return Err(ServerImpl::error(Status::Failed, "no such reply queue")),
+        };
+        let pushes = matches!(
+            command,
+            Command::Push | Command::PushBatch | Command::PushScheduled
+        );
+        if owner != connection && !pushes {
+            return Err(ServerImpl::error(
+                Status::Unauthorized,
+                "the reply queue belongs to another connection",
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// forgets a reply queue deleted by its owner
+    pub fn forget(&self, queue: &str) {
+        self.owners.lock().unwrap().remove(queue);
+    }
+
+    /// deletes every reply queue owned by a connection
+    pub fn delete_all(&self, queues: &Queues, owner: Uuid) {
+        let mut owners = self.owners.lock().unwrap();
+        owners.retain(|queue, queue_owner| {
+            if *queue_owner != owner {
+                return true;
+            }
+            // nobody can consume the queue anymore, it's dropped when the log is replayed
+            match queues.delete(queue) {
+                Ok(_) => info!("Deleted reply queue {} of connection {}", queue, owner),
+                Err(e) => error!("Can't delete reply queue {}: {:?}", queue, e),
+            }
+            false
+        });
+    }
+}
+
+/// the reply queues created by a connection, they're deleted when the connection's handler ends
+pub(crate) struct Ownership {
+    reply_queues: Arc<ReplyQueues>,
+    queues: Arc<Queues>,
+    owner: Uuid,
+}
+
+impl Ownership {
+    pub fn new(reply_queues: Arc<ReplyQueues>, queues: Arc<Queues>, owner: Uuid) -> Self {
+        Ownership {
+            reply_queues,
+            queues,
+            owner,
+        }
+    }
+
+    pub fn create(&self) -> Result<String, ServerError> {
+        self.reply_queues.create(&self.queues, self.owner)
+    }
+
+    pub fn check(&self, command: Command, queue: &str) -> Result<(), Vec<u8>> {
+        self.reply_queues.check(command, queue, self.owner)
+    }
+
+    /// forgets a queue if it's one of the reply queues, once it's deleted
+    pub fn forget(&self, queue: &str) {
+        self.reply_queues.forget(queue);
+    }
+}
+
+impl Drop for Ownership {
+    fn drop(&mut self) {
+        self.reply_queues.delete_all(&self.queues, self.owner);
+    }
+}
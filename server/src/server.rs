@@ -1,96 +1,869 @@
-use bytes::Bytes;
-use log::{error, info};
-use smq_lib::enums::errors::{MessageError, ServerError};
+use crate::config::Config;
+use crate::group::{Groups, Membership};
+use crate::metrics::{Exporter, Metered, Metrics};
+use crate::queue::Queues;
+use crate::reaper::Reaper;
+use crate::reply::{Ownership, ReplyQueues};
+use crate::subscription::Subscription;
+use crate::wal::Wal;
+use log::{error, info, warn};
+use smq_lib::enums::command::Command;
+use smq_lib::enums::errors::ServerError;
+use smq_lib::enums::feature::Feature;
+use smq_lib::enums::schedule::{Schedule, SCHEDULE_SIZE};
+use smq_lib::enums::status::Status;
+use smq_lib::structs::dead_letter::DeadLetter;
+use smq_lib::structs::delivery::Delivery;
+use smq_lib::structs::envelope::Envelope;
+use smq_lib::structs::hello::{Hello, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION};
 use smq_lib::structs::message::Message;
+use smq_lib::structs::queue_config::QueueConfig;
+use smq_lib::structs::queue_stats::QueueStats;
+use smq_lib::structs::request::{
+    decode_name, encode_names, tag_frame, untag_body, Request, HEADER_SIZE, REQUEST_ID_SIZE,
+};
 use smq_lib::traits::server::Server;
-use std::collections::{HashMap, VecDeque};
+use std::collections::HashMap;
 use std::io::{self, Read, Write};
 use std::net::{TcpListener, TcpStream};
-use std::sync::{mpsc, Arc, Mutex, RwLock};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
 use std::thread;
 use std::thread::JoinHandle;
-use std::time::Duration;
+use std::time::{Duration, Instant, SystemTime};
 use uuid::Uuid;
 
+/// what the server shares with its connections
+#[derive(Clone)]
+pub(crate) struct Shared {
+    pub queues: Arc<Queues>,
+    /// the consumer groups joined by the connections
+    pub groups: Arc<Groups>,
+    /// the temporary reply queues created by the connections
+    pub reply_queues: Arc<ReplyQueues>,
+    /// the metrics of the connections
+    pub metrics: Arc<Metrics>,
+}
+
 pub(crate) struct ServerImpl {
-    queue: Arc<RwLock<VecDeque<Message>>>,
+    shared: Shared,
     threads: Arc<Mutex<HashMap<Uuid, JoinHandle<()>>>>,
     listener: Option<TcpListener>,
+    /// drops the expired messages while the server is running
+    reaper: Option<Reaper>,
+    reap_interval: Duration,
+    /// serves the metrics while the server is running, if there's a metrics port
+    exporter: Option<Exporter>,
+    metrics_port: Option<usize>,
+    max_frame_size: u64,
+    #[cfg(feature = "async")]
+    async_runtime: bool,
 }
 
 impl ServerImpl {
-    pub fn new() -> Self {
-        ServerImpl {
-            queue: Arc::new(RwLock::new(VecDeque::new())),
+    /// replays the message log if it's configured
+    pub fn new(config: &Config) -> Result<Self, ServerError> {
+        let queues = match &config.wal_path {
+            Some(path) => match Wal::open(path, config.fsync) {
+                Ok((wal, events)) => Queues::restore(
+                    wal,
+                    events,
+                    config.visibility_timeout,
+                    config.max_delivery_attempts,
+                    config.block_timeout,
+                    config.dedup_window,
+                )?,
+                Err(e) => return Err(ServerError::LogError(e.to_string())),
+            },
+            None => Queues::new(
+                config.visibility_timeout,
+                config.max_delivery_attempts,
+                config.block_timeout,
+                config.dedup_window,
+            ),
+        };
+
+        Ok(ServerImpl {
+            shared: Shared {
+                queues: Arc::new(queues),
+                groups: Arc::new(Groups::new()),
+                reply_queues: Arc::new(ReplyQueues::new()),
+                metrics: Arc::new(Metrics::new()),
+            },
             threads: Arc::new(Mutex::new(HashMap::new())),
             listener: None,
+            reaper: None,
+            reap_interval: config.reap_interval,
+            exporter: None,
+            metrics_port: config.metrics_port,
+            max_frame_size: config.max_frame_size,
+            #[cfg(feature = "async")]
+            async_runtime: config.async_runtime,
+        })
+    }
+}
+
+/// how many requests of a pipelined connection can wait for a message or for room at once
+pub(crate) const MAX_WAITING_REQUESTS: usize = 64;
+
+/// the threads answering the waiting requests of a pipelined connection, they're stopped and
+/// joined when it's dropped along with the connection
+struct Waiting {
+    /// cleared when the connection is closed, the waits check it while waiting
+    open: Arc<AtomicBool>,
+    threads: Vec<JoinHandle<()>>,
+}
+
+impl Waiting {
+    fn new() -> Self {
+        Waiting {
+            open: Arc::new(AtomicBool::new(true)),
+            threads: Vec::new(),
+        }
+    }
+
+    /// answers the request from its own thread, false if `MAX_WAITING_REQUESTS` are already
+    /// waiting. The request is given whether the connection is still open
+    fn spawn<F>(&mut self, request: F) -> bool
+    where
+        F: FnOnce(&dyn Fn() -> bool) + Send + 'static,
+    {
+        self.threads.retain(|thread| !thread.is_finished());
+        if self.threads.len() >= MAX_WAITING_REQUESTS {
+            return false;
         }
+
+        let open = self.open.clone();
+        self.threads.push(thread::spawn(move || {
+            request(&|| open.load(Ordering::SeqCst))
+        }));
+        true
     }
 }
 
-const SUCCESS_HEADER: [u8; 1] = [0];
-const FAILED_HEADER: [u8; 1] = [1];
+impl Drop for Waiting {
+    fn drop(&mut self) {
+        self.open.store(false, Ordering::SeqCst);
+        for thread in self.threads.drain(..) {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// what the connection does after a request is dispatched
+pub(crate) enum Action {
+    /// writes the response
+    Respond(Vec<u8>),
+    /// waits for a message of the request's queue, the message is the response
+    PullWait(Duration),
+    /// waits for room in the request's queue to push the message
+    PushWait(Envelope),
+    /// starts streaming the request's queue with the given credits
+    Subscribe(u32),
+    /// gives credits to the subscription, there's no response
+    Credit(u32),
+    /// stops the subscription
+    Unsubscribe,
+    /// answers with the negotiated protocol, the next frames are read the way it says
+    Hello(Hello),
+}
 
 impl ServerImpl {
+    /// builds a response from its status and body
+    pub(crate) fn response(status: Status, body: &[u8]) -> Vec<u8> {
+        [
+            &[status as u8][..],
+            &(body.len() as u64).to_be_bytes(),
+            body,
+        ]
+        .concat()
+    }
+
+    /// builds a failed response, the body is a message explaining the failure
+    pub(crate) fn error(status: Status, message: &str) -> Vec<u8> {
+        ServerImpl::response(status, message.as_bytes())
+    }
+
+    /// builds the response of a request that failed with a server error
+    pub(crate) fn failure(e: &ServerError) -> Vec<u8> {
+        let (status, message) = ServerImpl::explain(e);
+        ServerImpl::error(status, &message)
+    }
+
+    /// the status and the message a server error is reported with
+    fn explain(e: &ServerError) -> (Status, String) {
+        match e {
+            ServerError::QueueFull => (Status::QueueFull, String::from("queue is full")),
+            ServerError::QueueInUse => (Status::Failed, String::from("queue has consumers")),
+            ServerError::MessageError(e) => {
+                (Status::InvalidBody, format!("invalid message: {:?}", e))
+            }
+            ServerError::LogError(e) => {
+                error!("Can't write the message log: {}", e);
+                (
+                    Status::InternalError,
+                    String::from("can't write the message log"),
+                )
+            }
+            e => (Status::InternalError, format!("{:?}", e)),
+        }
+    }
+
+    /// the response of a push
+    pub(crate) fn pushed(result: Result<(), ServerError>) -> Vec<u8> {
+        match result {
+            Ok(_) => ServerImpl::response(Status::Success, &[]),
+            Err(e) => ServerImpl::failure(&e),
+        }
+    }
+
+    /// responds to a request with an invalid body, the connection is kept open
+    fn invalid(message: &str) -> Action {
+        Action::Respond(ServerImpl::error(Status::InvalidBody, message))
+    }
+
+    /// parses an optional 8 bytes payload
+    fn parse_u64(payload: &[u8]) -> Result<Option<u64>, ()> {
+        match payload.len() {
+            0 => Ok(None),
+            8 => Ok(Some(u64::from_be_bytes(payload.try_into().unwrap()))),
+            _ => Err(()),
+        }
+    }
+
+    /// parses a 4 bytes payload
+    fn parse_u32(payload: &[u8]) -> Result<u32, ()> {
+        match payload.try_into() {
+            Ok(bytes) => Ok(u32::from_be_bytes(bytes)),
+            Err(_) => Err(()),
+        }
+    }
+
     fn handle_incoming(
-        queue: Arc<RwLock<VecDeque<Message>>>,
-        mut stream: TcpStream,
+        shared: Shared,
+        stream: TcpStream,
         id: Uuid,
         tx: mpsc::Sender<Uuid>,
+        max_frame_size: u64,
     ) {
         info!("Started a TCP handler");
+        let Shared {
+            queues,
+            groups,
+            reply_queues,
+            metrics,
+        } = shared;
+        let _connection = metrics.connection();
+        let membership = Membership::new(groups, queues.clone(), id);
+        let ownership = Ownership::new(reply_queues, queues.clone(), id);
+        let mut subscription: Option<Subscription> = None;
+
+        let result = ServerImpl::serve(
+            &queues,
+            &membership,
+            &ownership,
+            &metrics,
+            stream,
+            &mut subscription,
+            max_frame_size,
+        );
+        match result {
+            Ok(_) => info!("Connection {} disconnected", id),
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                info!("Connection {} closed by the client", id)
+            }
+            Err(e) => warn!("Connection {} dropped: {}", id, e),
+        }
+
+        if let Some(subscription) = subscription.take() {
+            subscription.stop();
+        }
+        // the thread is joined and removed from the running threads
+        let _ = tx.send(id);
+    }
+
+    /// handles the requests of a connection until the client disconnects, the connection is
+    /// dropped on the first read or write error or on a frame bigger than `max_frame_size`. The
+    /// subscription is left to the caller. Once the connection is pipelined, the requests waiting
+    /// for a message or for room are answered from their own thread, up to
+    /// `MAX_WAITING_REQUESTS` at once. They stop waiting when the connection is closed
+    fn serve(
+        queues: &Arc<Queues>,
+        membership: &Membership,
+        ownership: &Ownership,
+        metrics: &Arc<Metrics>,
+        stream: TcpStream,
+        subscription: &mut Option<Subscription>,
+        max_frame_size: u64,
+    ) -> io::Result<()> {
+        // subscriptions write to the stream from their own thread
+        let writer = Arc::new(Mutex::new(Metered::new(
+            stream.try_clone()?,
+            metrics.clone(),
+        )));
+        let mut stream = Metered::new(stream, metrics.clone());
+        // the bodies start with a request id once the client negotiated pipelining
+        let mut pipelined = false;
+        let mut waiting = Waiting::new();
+
         loop {
-            let mut header: [u8; 9] = [0; 9];
-            // TODO: handle this expect
-            stream.read_exact(&mut header).expect("Can't read headers");
-            let first_byte = header[0];
-            let size = u64::from_be_bytes([
-                header[1], header[2], header[3], header[4], header[5], header[6], header[7],
-                header[8],
-            ]);
-
-            let response = {
-                if first_byte == 0 {
-                    let mut body = vec![0_u8; size as usize];
-                    // TODO: handle this expect
-                    stream.read_exact(&mut body).expect("Can't read body");
-                    info!("Got a push message");
-                    // push
-                    let msg = match Message::deserialize(&body) {
-                        Ok(m) => m,
-                        Err(_) => return stream.write_all(&FAILED_HEADER).unwrap(),
-                    };
-
-                    let queue = &mut queue.write().unwrap();
-                    let response = match ServerImpl::enqueue(queue, msg) {
-                        Ok(_) => SUCCESS_HEADER,
-                        Err(_) => FAILED_HEADER,
-                    };
-                    [response.to_vec(), vec![0; 8]].concat()
-                } else if first_byte == 1 {
-                    info!("Got a pull message");
-                    // pull
-                    let queue = &mut queue.write().unwrap();
-                    let msg = ServerImpl::dequeue(queue).serialize();
-                    vec![
-                        Bytes::from(SUCCESS_HEADER.to_vec()),
-                        Bytes::from(msg.len().to_be_bytes().to_vec()),
-                        msg,
-                    ]
-                    .concat()
-                } else {
-                    let _ = tx.send(id);
-                    break;
+            let mut header: [u8; HEADER_SIZE] = [0; HEADER_SIZE];
+            stream.read_exact(&mut header)?;
+            let command = match Command::from_byte(header[0]) {
+                Some(Command::Disconnect) => return Ok(()),
+                command => command,
+            };
+            let size = u64::from_be_bytes(header[1..].try_into().unwrap());
+            if let Err(response) = ServerImpl::check_frame_size(size, max_frame_size) {
+                let response = ServerImpl::tag(response, pipelined.then_some(0));
+                writer.lock().unwrap().write_all(&response)?;
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("frame of {} bytes is too large", size),
+                ));
+            }
+
+            let mut body = vec![0_u8; size as usize];
+            stream.read_exact(&mut body)?;
+            let started = Instant::now();
+            let (id, body) = match ServerImpl::untag(pipelined, &body) {
+                Ok(untagged) => untagged,
+                Err(response) => {
+                    writer.lock().unwrap().write_all(&response)?;
+                    continue;
                 }
             };
-            stream
-                .write_all(&response)
-                .expect("Failed to send response");
+            let request = match ServerImpl::parse(command, body) {
+                Ok(request) => request,
+                Err(response) => {
+                    writer
+                        .lock()
+                        .unwrap()
+                        .write_all(&ServerImpl::tag(response, id))?;
+                    continue;
+                }
+            };
+            let (command, queue) = (request.get_command(), request.get_queue());
+
+            let action =
+                ServerImpl::dispatch(queues, membership, ownership, &request, max_frame_size);
+            let response = match action {
+                Action::Respond(response) => response,
+                Action::PullWait(timeout) if pipelined => {
+                    let (queues, queue, writer) =
+                        (queues.clone(), queue.to_string(), writer.clone());
+                    let metrics = metrics.clone();
+                    let spawned = waiting.spawn(move |open| {
+                        let msg = queues
+                            .pop_wait_while(&queue, timeout, open)
+                            .unwrap_or_else(Message::empty_message)
+                            .serialize();
+                        let response = ServerImpl::response(Status::Success, &msg);
+                        let _ = writer
+                            .lock()
+                            .unwrap()
+                            .write_all(&ServerImpl::tag(response, id));
+                        metrics.observe(command, started.elapsed());
+                    });
+                    if spawned {
+                        continue;
+                    }
+                    ServerImpl::error(Status::Failed, "too many waiting requests")
+                }
+                Action::PullWait(timeout) => {
+                    let msg = ServerImpl::dequeue_wait(queues, queue, timeout).serialize();
+                    ServerImpl::response(Status::Success, &msg)
+                }
+                Action::PushWait(envelope) if pipelined => {
+                    let (queues, queue, writer) =
+                        (queues.clone(), queue.to_string(), writer.clone());
+                    let metrics = metrics.clone();
+                    let spawned = waiting.spawn(move |open| {
+                        let result = queues.push_wait_while(&queue, envelope, open);
+                        let response = ServerImpl::pushed(result);
+                        let _ = writer
+                            .lock()
+                            .unwrap()
+                            .write_all(&ServerImpl::tag(response, id));
+                        metrics.observe(command, started.elapsed());
+                    });
+                    if spawned {
+                        continue;
+                    }
+                    ServerImpl::error(Status::Failed, "too many waiting requests")
+                }
+                Action::PushWait(envelope) => ServerImpl::pushed(queues.push_wait(queue, envelope)),
+                Action::Hello(hello) => {
+                    // the hello is answered the way it's been sent
+                    let response = ServerImpl::response(Status::Success, &hello.serialize());
+                    writer
+                        .lock()
+                        .unwrap()
+                        .write_all(&ServerImpl::tag(response, id))?;
+                    metrics.observe(command, started.elapsed());
+                    pipelined = hello.supports(Feature::Pipelining);
+                    continue;
+                }
+                Action::Subscribe(prefetch) => {
+                    if subscription.is_some() {
+                        ServerImpl::error(Status::Failed, "already subscribed")
+                    } else {
+                        // the response has to be written before the first message is streamed
+                        let response = ServerImpl::response(Status::Success, &[]);
+                        writer
+                            .lock()
+                            .unwrap()
+                            .write_all(&ServerImpl::tag(response, id))?;
+                        metrics.observe(command, started.elapsed());
+                        *subscription = Some(Subscription::start(
+                            queues.clone(),
+                            queue,
+                            prefetch,
+                            writer.clone(),
+                            id,
+                        ));
+                        continue;
+                    }
+                }
+                Action::Credit(credits) => {
+                    if let Some(subscription) = subscription {
+                        subscription.add_credits(credits);
+                    }
+                    // credits don't have a response
+                    continue;
+                }
+                Action::Unsubscribe => {
+                    if let Some(subscription) = subscription.take() {
+                        subscription.stop();
+                    }
+                    ServerImpl::response(Status::Success, &[])
+                }
+            };
+            writer
+                .lock()
+                .unwrap()
+                .write_all(&ServerImpl::tag(response, id))?;
+            metrics.observe(command, started.elapsed());
+        }
+    }
+
+    /// puts the request id in front of the response's body if the connection is pipelined
+    pub(crate) fn tag(response: Vec<u8>, id: Option<u32>) -> Vec<u8> {
+        match id {
+            Some(id) => tag_frame(&response, id).to_vec(),
+            None => response,
+        }
+    }
+
+    /// splits the request id off the body if the connection is pipelined, the error is the
+    /// response to a body too short to have one
+    pub(crate) fn untag(pipelined: bool, body: &[u8]) -> Result<(Option<u32>, &[u8]), Vec<u8>> {
+        if !pipelined {
+            return Ok((None, body));
+        }
+
+        match untag_body(body) {
+            Ok((id, body)) => Ok((Some(id), body)),
+            Err(_) => Err(ServerImpl::tag(
+                ServerImpl::error(Status::InvalidBody, "missing request id"),
+                Some(0),
+            )),
         }
     }
+
+    /// how many bytes a list of messages can take in a response, the body may start with a
+    /// request id
+    fn list_budget(max_frame_size: u64) -> usize {
+        (max_frame_size as usize).saturating_sub(REQUEST_ID_SIZE)
+    }
+
+    /// the error is the response to a frame that's too large, it's refused before its body is
+    /// read so the connection has to be closed after the response
+    pub(crate) fn check_frame_size(size: u64, max_frame_size: u64) -> Result<(), Vec<u8>> {
+        if size <= max_frame_size {
+            return Ok(());
+        }
+
+        Err(ServerImpl::error(
+            Status::MalformedHeader,
+            &format!(
+                "frame of {} bytes exceeds the max frame size of {} bytes",
+                size, max_frame_size
+            ),
+        ))
+    }
+
+    /// parses a request whose body has been read, the error is the response to the invalid
+    /// request
+    pub(crate) fn parse(command: Option<Command>, body: &[u8]) -> Result<Request, Vec<u8>> {
+        let command = match command {
+            Some(command) => command,
+            None => return Err(ServerImpl::error(Status::UnknownCommand, "unknown command")),
+        };
+
+        match Request::deserialize(command, body) {
+            Ok(request) => Ok(request),
+            Err(e) => Err(ServerImpl::error(
+                Status::InvalidBody,
+                &format!("invalid request: {:?}", e),
+            )),
+        }
+    }
+
+    /// handles a request, the requests that depend on how the connection is served (waiting for
+    /// a message and subscriptions) are left to the caller. The lists in the responses fit in
+    /// `max_frame_size`
+    pub(crate) fn dispatch(
+        queues: &Queues,
+        membership: &Membership,
+        ownership: &Ownership,
+        request: &Request,
+        max_frame_size: u64,
+    ) -> Action {
+        let command = request.get_command();
+        let queue = request.get_queue();
+        if let Err(response) = ownership.check(command, queue) {
+            return Action::Respond(response);
+        }
+        if let Err(response) = membership.check(command, queue) {
+            return Action::Respond(response);
+        }
+
+        let response = match command {
+            Command::Push => {
+                info!("Got a push message for queue {}", queue);
+                let envelope = match Envelope::deserialize(request.get_payload()) {
+                    Ok(e) => e,
+                    Err(_) => return ServerImpl::invalid("invalid envelope"),
+                };
+
+                match ServerImpl::enqueue(queues, queue, envelope.clone()) {
+                    Err(ServerError::QueueFull) if queues.blocks(queue) => {
+                        return Action::PushWait(envelope)
+                    }
+                    result => ServerImpl::pushed(result),
+                }
+            }
+            Command::PushBatch => {
+                let envelopes = match Envelope::deserialize_list(request.get_payload()) {
+                    Ok(envelopes) => envelopes,
+                    Err(_) => return ServerImpl::invalid("invalid envelopes"),
+                };
+                info!(
+                    "Got a batch push message of {} messages for queue {}",
+                    envelopes.len(),
+                    queue
+                );
+
+                // one status per message, the failed pushes don't have a message
+                match ServerImpl::enqueue_batch(queues, queue, envelopes) {
+                    Ok(results) => {
+                        let statuses: Vec<u8> = results
+                            .iter()
+                            .map(|result| match result {
+                                Ok(_) => Status::Success as u8,
+                                Err(e) => ServerImpl::explain(e).0 as u8,
+                            })
+                            .collect();
+                        ServerImpl::response(Status::Success, &statuses)
+                    }
+                    Err(e) => ServerImpl::failure(&e),
+                }
+            }
+            Command::PullBatch => {
+                let max = match ServerImpl::parse_u32(request.get_payload()) {
+                    Ok(max) => max,
+                    Err(_) => return ServerImpl::invalid("invalid batch size"),
+                };
+                info!(
+                    "Got a batch pull message of up to {} messages for queue {}",
+                    max, queue
+                );
+
+                let max_bytes = ServerImpl::list_budget(max_frame_size);
+                let messages = ServerImpl::dequeue_batch(queues, queue, max as usize, max_bytes);
+                ServerImpl::response(Status::Success, &Message::serialize_list(&messages))
+            }
+            Command::PushScheduled => {
+                info!("Got a scheduled push message for queue {}", queue);
+                let payload = request.get_payload();
+                if payload.len() < SCHEDULE_SIZE {
+                    return ServerImpl::invalid("invalid schedule");
+                }
+                let (schedule, envelope) = match (
+                    Schedule::deserialize(&payload[..SCHEDULE_SIZE]),
+                    Envelope::deserialize(&payload[SCHEDULE_SIZE..]),
+                ) {
+                    (Ok(s), Ok(e)) => (s, e),
+                    _ => return ServerImpl::invalid("invalid schedule or envelope"),
+                };
+
+                // only the plain pushes wait for room
+                ServerImpl::pushed(ServerImpl::schedule(queues, queue, envelope, schedule))
+            }
+            Command::Pull => {
+                info!("Got a pull message for queue {}", queue);
+                let msg = ServerImpl::dequeue(queues, queue).serialize();
+                ServerImpl::response(Status::Success, &msg)
+            }
+            Command::PullWait => {
+                let timeout = match ServerImpl::parse_u64(request.get_payload()) {
+                    Ok(Some(timeout)) => Duration::from_millis(timeout),
+                    _ => return ServerImpl::invalid("invalid wait timeout"),
+                };
+                info!(
+                    "Got a pull message for queue {} waiting {:?}",
+                    queue, timeout
+                );
+                return Action::PullWait(timeout);
+            }
+            Command::CreateQueue => {
+                info!("Got a create message for queue {}", queue);
+                // queues created without a config don't expire their messages and have no limits
+                let config = match &request.get_payload()[..] {
+                    [] => QueueConfig::default(),
+                    payload => match QueueConfig::deserialize(payload) {
+                        Ok(config) => config,
+                        Err(_) => return ServerImpl::invalid("invalid queue config"),
+                    },
+                };
+
+                match ServerImpl::create_queue(queues, queue, config) {
+                    Ok(created) => ServerImpl::response(Status::Success, &[created as u8]),
+                    Err(e) => ServerImpl::failure(&e),
+                }
+            }
+            Command::Reserve => {
+                info!("Got a reserve message for queue {}", queue);
+                let timeout = match ServerImpl::parse_u64(request.get_payload()) {
+                    Ok(timeout) => timeout.map(Duration::from_millis),
+                    Err(_) => return ServerImpl::invalid("invalid visibility timeout"),
+                };
+
+                let delivery = ServerImpl::reserve(queues, queue, timeout).serialize();
+                ServerImpl::response(Status::Success, &delivery)
+            }
+            Command::Ack | Command::Nack => {
+                info!("Got an {:?} message for queue {}", command, queue);
+                let delivery_id = match ServerImpl::parse_u64(request.get_payload()) {
+                    Ok(Some(id)) => id,
+                    _ => return ServerImpl::invalid("invalid delivery id"),
+                };
+
+                let result = if command == Command::Ack {
+                    ServerImpl::ack(queues, queue, delivery_id)
+                } else {
+                    Ok(ServerImpl::nack(queues, queue, delivery_id))
+                };
+                match result {
+                    Ok(found) => ServerImpl::response(Status::Success, &[found as u8]),
+                    Err(e) => ServerImpl::failure(&e),
+                }
+            }
+            Command::ListDeadLetters => {
+                info!("Got a list dead letters message for queue {}", queue);
+                let dead_letters = ServerImpl::dead_letters(queues, queue);
+                ServerImpl::response(Status::Success, &DeadLetter::serialize_list(&dead_letters))
+            }
+            Command::InspectDeadLetter => {
+                info!("Got an inspect dead letter message for queue {}", queue);
+                let id = match ServerImpl::parse_u64(request.get_payload()) {
+                    Ok(Some(id)) => id,
+                    _ => return ServerImpl::invalid("invalid dead letter id"),
+                };
+
+                let msg = match ServerImpl::inspect_dead_letter(queues, queue, id) {
+                    Some(dead_letter) => dead_letter.get_message().serialize(),
+                    None => Message::empty_message().serialize(),
+                };
+                ServerImpl::response(Status::Success, &msg)
+            }
+            Command::Redrive => {
+                info!("Got a redrive message for queue {}", queue);
+                let id = match ServerImpl::parse_u64(request.get_payload()) {
+                    Ok(id) => id,
+                    Err(_) => return ServerImpl::invalid("invalid dead letter id"),
+                };
+
+                match ServerImpl::redrive(queues, queue, id) {
+                    Ok(count) => {
+                        ServerImpl::response(Status::Success, &(count as u64).to_be_bytes())
+                    }
+                    Err(e) => ServerImpl::failure(&e),
+                }
+            }
+            Command::Publish => {
+                info!("Got a publish message for topic {}", queue);
+                let msg = match Message::deserialize(request.get_payload()) {
+                    Ok(m) => m,
+                    Err(_) => return ServerImpl::invalid("invalid message"),
+                };
+
+                match ServerImpl::publish(queues, queue, msg) {
+                    Ok(count) => {
+                        ServerImpl::response(Status::Success, &(count as u64).to_be_bytes())
+                    }
+                    Err(e) => ServerImpl::failure(&e),
+                }
+            }
+            Command::SubscribeTopic | Command::UnsubscribeTopic => {
+                info!("Got an {:?} message for topic {}", command, queue);
+                let subscriber = match decode_name(request.get_payload()) {
+                    Ok((subscriber, [])) => subscriber,
+                    _ => return ServerImpl::invalid("invalid queue name"),
+                };
+                // the subscriber is a queue too, a reply or group queue has to exist
+                if let Err(response) = ownership
+                    .check(command, &subscriber)
+                    .and_then(|_| membership.check(command, &subscriber))
+                {
+                    return Action::Respond(response);
+                }
+
+                let result = if command == Command::SubscribeTopic {
+                    ServerImpl::subscribe_topic(queues, queue, &subscriber)
+                } else {
+                    ServerImpl::unsubscribe_topic(queues, queue, &subscriber)
+                };
+                match result {
+                    Ok(changed) => ServerImpl::response(Status::Success, &[changed as u8]),
+                    Err(e) => ServerImpl::failure(&e),
+                }
+            }
+            Command::JoinGroup => {
+                let group = match decode_name(request.get_payload()) {
+                    Ok((group, [])) => group,
+                    _ => return ServerImpl::invalid("invalid group name"),
+                };
+                info!(
+                    "Got a join group message for group {} of topic {}",
+                    group, queue
+                );
+
+                match membership.join(queue, &group) {
+                    Ok(group_queue) => {
+                        ServerImpl::response(Status::Success, group_queue.as_bytes())
+                    }
+                    Err(e) => ServerImpl::failure(&e),
+                }
+            }
+            Command::LeaveGroup => {
+                let group = match decode_name(request.get_payload()) {
+                    Ok((group, [])) => group,
+                    _ => return ServerImpl::invalid("invalid group name"),
+                };
+                info!(
+                    "Got a leave group message for group {} of topic {}",
+                    group, queue
+                );
+
+                let left = membership.leave(queue, &group);
+                ServerImpl::response(Status::Success, &[left as u8])
+            }
+            Command::CreateReplyQueue => {
+                info!("Got a create reply queue message from client {}", queue);
+                match ownership.create() {
+                    Ok(reply_queue) => {
+                        ServerImpl::response(Status::Success, reply_queue.as_bytes())
+                    }
+                    Err(e) => ServerImpl::failure(&e),
+                }
+            }
+            Command::ListQueues => {
+                info!("Got a list queues message from client {}", queue);
+                match encode_names(&ServerImpl::list_queues(queues)) {
+                    Ok(names) => ServerImpl::response(Status::Success, &names),
+                    Err(e) => ServerImpl::failure(&ServerError::MessageError(e)),
+                }
+            }
+            Command::QueueStats => {
+                info!("Got a queue stats message for queue {}", queue);
+                // the body is empty if there's no such queue
+                let stats = ServerImpl::queue_stats(queues, queue)
+                    .map(|stats| stats.serialize())
+                    .unwrap_or_default();
+                ServerImpl::response(Status::Success, &stats)
+            }
+            Command::Peek => {
+                let max = match ServerImpl::parse_u32(request.get_payload()) {
+                    Ok(max) => max,
+                    Err(_) => return ServerImpl::invalid("invalid peek size"),
+                };
+                info!(
+                    "Got a peek message of up to {} messages for queue {}",
+                    max, queue
+                );
+
+                let max_bytes = ServerImpl::list_budget(max_frame_size);
+                let messages = ServerImpl::peek(queues, queue, max as usize, max_bytes);
+                ServerImpl::response(Status::Success, &Message::serialize_list(&messages))
+            }
+            Command::Purge => {
+                info!("Got a purge message for queue {}", queue);
+                let count = ServerImpl::purge(queues, queue) as u64;
+                ServerImpl::response(Status::Success, &count.to_be_bytes())
+            }
+            Command::DeleteQueue => {
+                info!("Got a delete message for queue {}", queue);
+                match ServerImpl::delete_queue(queues, queue) {
+                    Ok(deleted) => {
+                        ownership.forget(queue);
+                        ServerImpl::response(Status::Success, &[deleted as u8])
+                    }
+                    Err(e) => ServerImpl::failure(&e),
+                }
+            }
+            Command::Subscribe => {
+                info!("Got a subscribe message for queue {}", queue);
+                return match ServerImpl::parse_u32(request.get_payload()) {
+                    Ok(prefetch) => Action::Subscribe(prefetch),
+                    Err(_) => ServerImpl::invalid("invalid prefetch"),
+                };
+            }
+            Command::Credit => {
+                return match ServerImpl::parse_u32(request.get_payload()) {
+                    Ok(credits) => Action::Credit(credits),
+                    Err(_) => ServerImpl::invalid("invalid credits"),
+                };
+            }
+            Command::Unsubscribe => {
+                info!("Got an unsubscribe message for queue {}", queue);
+                return Action::Unsubscribe;
+            }
+            Command::Hello => {
+                let hello = match Hello::deserialize(request.get_payload()) {
+                    Ok(hello) => hello,
+                    Err(_) => return ServerImpl::invalid("invalid hello"),
+                };
+                info!(
+                    "Got a hello from client {} speaking version {}",
+                    queue,
+                    hello.get_version()
+                );
+
+                match Hello::current().negotiate(&hello) {
+                    Some(negotiated) => return Action::Hello(negotiated),
+                    None => ServerImpl::error(
+                        Status::UnsupportedVersion,
+                        &format!(
+                            "protocol version {} isn't supported, the server speaks versions {} to {}",
+                            hello.get_version(),
+                            MIN_PROTOCOL_VERSION,
+                            PROTOCOL_VERSION
+                        ),
+                    ),
+                }
+            }
+            Command::Disconnect => unreachable!(),
+        };
+
+        Action::Respond(response)
+    }
 }
 
 impl Server for ServerImpl {
+    type Queues = Queues;
+
     fn bind(&mut self, port: Option<usize>) -> Result<(), ServerError> {
         let addr = format!("0.0.0.0:{}", port.unwrap_or(8080));
 
@@ -112,6 +885,23 @@ impl Server for ServerImpl {
             Some(listener) => listener,
             None => return Err(ServerError::ServerNotYetStarted),
         };
+        let shared = &self.shared;
+        self.reaper = Some(Reaper::start(shared.queues.clone(), self.reap_interval));
+        if let Some(port) = self.metrics_port {
+            match Exporter::start(port, shared.queues.clone(), shared.metrics.clone()) {
+                Ok(exporter) => self.exporter = Some(exporter),
+                Err(e) => return Err(ServerError::UnableToStartServer(e.to_string())),
+            }
+        }
+
+        #[cfg(feature = "async")]
+        if self.async_runtime {
+            let listener = match listener.try_clone() {
+                Ok(listener) => listener,
+                Err(e) => return Err(ServerError::UnableToStartServer(e.to_string())),
+            };
+            return crate::async_server::serve(listener, shared.clone(), self.max_frame_size);
+        }
 
         let (tx_id, rx_id) = mpsc::channel::<Uuid>();
         let (tx_done, rx_done) = mpsc::channel::<()>();
@@ -152,9 +942,12 @@ impl Server for ServerImpl {
 
             let id = Uuid::new_v4();
 
-            let queue = self.queue.clone();
+            let shared = shared.clone();
             let tx = tx_id.clone();
-            let t = thread::spawn(move || ServerImpl::handle_incoming(queue, stream, id, tx));
+            let max_frame_size = self.max_frame_size;
+            let t = thread::spawn(move || {
+                ServerImpl::handle_incoming(shared, stream, id, tx, max_frame_size)
+            });
             self.threads.lock().unwrap().insert(id, t);
         }
 
@@ -165,28 +958,1207 @@ impl Server for ServerImpl {
     }
 
     fn stop(&mut self) -> Result<(), ServerError> {
+        if let Some(reaper) = self.reaper.take() {
+            reaper.stop();
+        }
+        if let Some(exporter) = self.exporter.take() {
+            exporter.stop();
+        }
+
         info!("Joining worker threads...");
         let mut threads = self.threads.lock().unwrap();
         for thread in threads.drain() {
             let _ = thread.1.join();
         }
 
+        if let Err(e) = self.shared.queues.sync() {
+            error!("Can't flush the message log: {:?}", e);
+        }
+
         info!("Good bye~");
         Ok(())
     }
 
-    fn enqueue(queue: &mut VecDeque<Message>, message: Message) -> Result<(), MessageError> {
-        message.validate()?;
+    fn create_queue(
+        queues: &Queues,
+        queue: &str,
+        config: QueueConfig,
+    ) -> Result<bool, ServerError> {
+        queues.create(queue, config)
+    }
 
-        queue.push_back(message);
+    fn enqueue(queues: &Queues, queue: &str, envelope: Envelope) -> Result<(), ServerError> {
+        if let Err(e) = envelope.get_message().validate() {
+            return Err(ServerError::MessageError(e));
+        }
 
-        Ok(())
+        queues.push(queue, envelope)
+    }
+
+    fn enqueue_batch(
+        queues: &Queues,
+        queue: &str,
+        envelopes: Vec<Envelope>,
+    ) -> Result<Vec<Result<(), ServerError>>, ServerError> {
+        // the invalid messages are left out of the batch but keep their place in the results
+        let mut results: Vec<Result<(), ServerError>> = vec![];
+        let mut valid = vec![];
+        for envelope in envelopes {
+            match envelope.get_message().validate() {
+                Ok(_) => {
+                    results.push(Ok(()));
+                    valid.push(envelope);
+                }
+                Err(e) => results.push(Err(ServerError::MessageError(e))),
+            }
+        }
+
+        let mut pushed = queues.push_batch(queue, valid)?.into_iter();
+        for result in results.iter_mut().filter(|result| result.is_ok()) {
+            *result = pushed.next().unwrap();
+        }
+
+        Ok(results)
+    }
+
+    fn schedule(
+        queues: &Queues,
+        queue: &str,
+        envelope: Envelope,
+        schedule: Schedule,
+    ) -> Result<(), ServerError> {
+        if let Err(e) = envelope.get_message().validate() {
+            return Err(ServerError::MessageError(e));
+        }
+
+        queues.schedule(queue, envelope, schedule.deliver_at(SystemTime::now()))
     }
 
-    fn dequeue(queue: &mut VecDeque<Message>) -> Message {
-        if queue.is_empty() {
-            return Message::empty_message();
+    fn dequeue(queues: &Queues, queue: &str) -> Message {
+        queues.pop(queue).unwrap_or_else(Message::empty_message)
+    }
+
+    fn dequeue_batch(queues: &Queues, queue: &str, max: usize, max_bytes: usize) -> Vec<Message> {
+        queues.pop_batch(queue, max, max_bytes)
+    }
+
+    fn dequeue_wait(queues: &Queues, queue: &str, timeout: Duration) -> Message {
+        queues
+            .pop_wait(queue, timeout)
+            .unwrap_or_else(Message::empty_message)
+    }
+
+    fn reserve(queues: &Queues, queue: &str, timeout: Option<Duration>) -> Delivery {
+        match queues.reserve(queue, timeout) {
+            Some((id, attempts, message)) => Delivery::new(id, attempts, message),
+            None => Delivery::empty_delivery(),
+        }
+    }
+
+    fn ack(queues: &Queues, queue: &str, delivery_id: u64) -> Result<bool, ServerError> {
+        queues.ack(queue, delivery_id)
+    }
+
+    fn nack(queues: &Queues, queue: &str, delivery_id: u64) -> bool {
+        queues.nack(queue, delivery_id)
+    }
+
+    fn dead_letters(queues: &Queues, queue: &str) -> Vec<DeadLetter> {
+        queues.dead_letters(queue)
+    }
+
+    fn inspect_dead_letter(queues: &Queues, queue: &str, id: u64) -> Option<DeadLetter> {
+        queues.dead_letter(queue, id)
+    }
+
+    fn redrive(queues: &Queues, queue: &str, id: Option<u64>) -> Result<usize, ServerError> {
+        queues.redrive(queue, id)
+    }
+
+    fn subscribe_topic(queues: &Queues, topic: &str, queue: &str) -> Result<bool, ServerError> {
+        queues.subscribe_topic(topic, queue)
+    }
+
+    fn unsubscribe_topic(queues: &Queues, topic: &str, queue: &str) -> Result<bool, ServerError> {
+        queues.unsubscribe_topic(topic, queue)
+    }
+
+    fn publish(queues: &Queues, topic: &str, message: Message) -> Result<usize, ServerError> {
+        if let Err(e) = message.validate() {
+            return Err(ServerError::MessageError(e));
+        }
+
+        queues.publish(topic, message)
+    }
+
+    fn list_queues(queues: &Queues) -> Vec<String> {
+        queues.names()
+    }
+
+    fn queue_stats(queues: &Queues, queue: &str) -> Option<QueueStats> {
+        queues.stats(queue)
+    }
+
+    fn peek(queues: &Queues, queue: &str, max: usize, max_bytes: usize) -> Vec<Message> {
+        queues.peek(queue, max, max_bytes)
+    }
+
+    fn purge(queues: &Queues, queue: &str) -> usize {
+        queues.purge(queue)
+    }
+
+    fn delete_queue(queues: &Queues, queue: &str) -> Result<bool, ServerError> {
+        queues.delete_unused(queue)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ServerImpl, Shared, MAX_WAITING_REQUESTS};
+    use crate::group::Groups;
+    use crate::metrics::{Exporter, Metrics};
+    use crate::queue::Queues;
+    use crate::reply::ReplyQueues;
+    use crate::wal::{Event, FsyncPolicy, Wal};
+    use smq_lib::enums::command::Command;
+    use smq_lib::enums::feature::Feature;
+    use smq_lib::enums::overflow::Overflow;
+    use smq_lib::enums::status::Status;
+    use smq_lib::structs::envelope::Envelope;
+    use smq_lib::structs::hello::{Hello, PROTOCOL_VERSION};
+    use smq_lib::structs::message::Message;
+    use smq_lib::structs::queue_config::QueueConfig;
+    use smq_lib::structs::queue_stats::QueueStats;
+    use smq_lib::structs::request::{decode_names, tag_frame, untag_body, Request, HEADER_SIZE};
+    use std::fs::OpenOptions;
+    use std::io::{Read, Write};
+    use std::net::{Shutdown, TcpListener, TcpStream};
+    use std::path::{Path, PathBuf};
+    use std::sync::{mpsc, Arc};
+    use std::thread::{self, JoinHandle};
+    use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+    use uuid::Uuid;
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+    const MAX_FRAME_SIZE: u64 = 1024;
+    const DEDUP_WINDOW: Duration = Duration::from_secs(60);
+
+    /// a connection handled by its own handler thread, like the server does
+    struct Connection {
+        client: TcpStream,
+        handler: JoinHandle<()>,
+        done: mpsc::Receiver<Uuid>,
+        id: Uuid,
+        queues: Arc<Queues>,
+        reply_queues: Arc<ReplyQueues>,
+        metrics: Arc<Metrics>,
+    }
+
+    fn connect() -> Connection {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        client.set_read_timeout(Some(TIMEOUT)).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+
+        let queues = Arc::new(Queues::new(TIMEOUT, None, TIMEOUT, DEDUP_WINDOW));
+        let groups = Arc::new(Groups::new());
+        let reply_queues = Arc::new(ReplyQueues::new());
+        let metrics = Arc::new(Metrics::new());
+        let (tx, done) = mpsc::channel();
+        let id = Uuid::new_v4();
+        let shared = Shared {
+            queues: queues.clone(),
+            groups,
+            reply_queues: reply_queues.clone(),
+            metrics: metrics.clone(),
+        };
+        let handler = thread::spawn(move || {
+            ServerImpl::handle_incoming(shared, stream, id, tx, MAX_FRAME_SIZE)
+        });
+
+        Connection {
+            client,
+            handler,
+            done,
+            id,
+            queues,
+            reply_queues,
+            metrics,
+        }
+    }
+
+    impl Connection {
+        fn request(&mut self, command: Command, queue: &str, payload: &[u8]) -> (u8, Vec<u8>) {
+            let request = Request::new(command, queue, payload.to_vec().into()).unwrap();
+            self.client.write_all(&request.serialize()).unwrap();
+            self.response()
+        }
+
+        fn response(&mut self) -> (u8, Vec<u8>) {
+            let mut header = [0; HEADER_SIZE];
+            self.client.read_exact(&mut header).unwrap();
+            let size = u64::from_be_bytes(header[1..].try_into().unwrap());
+            let mut body = vec![0; size as usize];
+            self.client.read_exact(&mut body).unwrap();
+            (header[0], body)
+        }
+
+        /// sends a request tagged with its id, once pipelining is negotiated
+        fn send_tagged(&mut self, command: Command, queue: &str, payload: &[u8], id: u32) {
+            let request = Request::new(command, queue, payload.to_vec().into()).unwrap();
+            let frame = tag_frame(&request.serialize(), id);
+            self.client.write_all(&frame).unwrap();
+        }
+
+        fn tagged_response(&mut self) -> (u32, u8, Vec<u8>) {
+            let (status, body) = self.response();
+            let (id, body) = untag_body(&body).unwrap();
+            (id, status, body.to_vec())
+        }
+
+        /// the handler has to finish without panicking and report itself as done
+        fn assert_cleaned_up(self) {
+            assert_eq!(self.done.recv_timeout(TIMEOUT).unwrap(), self.id);
+            assert!(self.handler.join().is_ok());
+        }
+    }
+
+    fn push_payload(value: u8) -> Vec<u8> {
+        Envelope::new(Message::from_u8_arr(&[value]), 0, None)
+            .serialize()
+            .to_vec()
+    }
+
+    #[test]
+    fn disconnect_header() {
+        let mut connection = connect();
+        connection.client.write_all(&[0xFF; HEADER_SIZE]).unwrap();
+        connection.assert_cleaned_up();
+    }
+
+    #[test]
+    fn closed_without_disconnect() {
+        let connection = connect();
+        connection.client.shutdown(Shutdown::Both).unwrap();
+        connection.assert_cleaned_up();
+    }
+
+    #[test]
+    fn closed_after_requests() {
+        let mut connection = connect();
+        let (status, _) = connection.request(Command::Push, "q", &push_payload(1));
+        assert_eq!(status, Status::Success as u8);
+        drop(connection.client);
+
+        assert_eq!(
+            connection.done.recv_timeout(TIMEOUT).unwrap(),
+            connection.id
+        );
+        assert!(connection.handler.join().is_ok());
+        assert!(connection.queues.pop("q").is_some());
+    }
+
+    #[test]
+    fn partial_header() {
+        let mut connection = connect();
+        connection.client.write_all(&[0, 0, 0]).unwrap();
+        connection.client.shutdown(Shutdown::Write).unwrap();
+        connection.assert_cleaned_up();
+    }
+
+    #[test]
+    fn partial_body() {
+        let mut connection = connect();
+        let queues = connection.queues.clone();
+        let header = [&[Command::Push as u8][..], &100_u64.to_be_bytes()].concat();
+        connection.client.write_all(&header).unwrap();
+        connection.client.write_all(&[1, b'q', 0, 0]).unwrap();
+        connection.client.shutdown(Shutdown::Write).unwrap();
+        connection.assert_cleaned_up();
+
+        // the partial push never reaches the queue
+        assert!(queues.pop("q").is_none());
+    }
+
+    #[test]
+    fn oversized_frame() {
+        let mut connection = connect();
+        let header = [&[Command::Push as u8][..], &u64::MAX.to_be_bytes()].concat();
+        connection.client.write_all(&header).unwrap();
+        let (status, message) = connection.response();
+        assert_eq!(status, Status::MalformedHeader as u8);
+        assert!(!message.is_empty());
+
+        // the server closes the connection without waiting for the body
+        let mut rest = Vec::new();
+        connection.client.read_to_end(&mut rest).unwrap();
+        assert!(rest.is_empty());
+        connection.assert_cleaned_up();
+    }
+
+    #[test]
+    fn max_frame_size_is_accepted() {
+        let mut connection = connect();
+        let name = [&[1][..], b"q"].concat();
+        let payload = vec![0; MAX_FRAME_SIZE as usize - name.len()];
+        let header = [&[Command::Push as u8][..], &MAX_FRAME_SIZE.to_be_bytes()].concat();
+        connection.client.write_all(&header).unwrap();
+        connection.client.write_all(&name).unwrap();
+        connection.client.write_all(&payload).unwrap();
+
+        // the frame is read, the all-zero envelope is refused as a bad body
+        let (status, _) = connection.response();
+        assert_eq!(status, Status::InvalidBody as u8);
+        connection.client.write_all(&[0xFF; HEADER_SIZE]).unwrap();
+        connection.assert_cleaned_up();
+    }
+
+    #[test]
+    fn hello_negotiates_the_version() {
+        let mut connection = connect();
+        // the frames stay untagged without pipelining
+        let hello = Hello::new(PROTOCOL_VERSION + 1, u32::MAX).without(Feature::Pipelining);
+        let (status, body) = connection.request(Command::Hello, "client", &hello.serialize());
+        assert_eq!(status, Status::Success as u8);
+        assert_eq!(
+            Hello::deserialize(&body).unwrap(),
+            Hello::current().without(Feature::Pipelining)
+        );
+
+        // an unsupported version is refused, the connection stays usable
+        let (status, message) =
+            connection.request(Command::Hello, "client", &Hello::new(0, 0).serialize());
+        assert_eq!(status, Status::UnsupportedVersion as u8);
+        assert!(!message.is_empty());
+
+        let (status, _) = connection.request(Command::Push, "q", &push_payload(1));
+        assert_eq!(status, Status::Success as u8);
+        connection.client.write_all(&[0xFF; HEADER_SIZE]).unwrap();
+        connection.assert_cleaned_up();
+    }
+
+    #[test]
+    fn batch_push_and_pull() {
+        let mut connection = connect();
+        let config = QueueConfig {
+            max_length: Some(2),
+            ..QueueConfig::default()
+        };
+        connection.queues.create("q", config).unwrap();
+
+        // the message that doesn't fit is the only one rejected
+        let envelopes: Vec<Envelope> = (1..=3)
+            .map(|i| Envelope::new(Message::from_u8_arr(&[i]), 0, None))
+            .collect();
+        let payload = Envelope::serialize_list(&envelopes);
+        let (status, statuses) = connection.request(Command::PushBatch, "q", &payload);
+        assert_eq!(status, Status::Success as u8);
+        assert_eq!(statuses, [0, 0, Status::QueueFull as u8]);
+
+        let (status, body) = connection.request(Command::PullBatch, "q", &5_u32.to_be_bytes());
+        assert_eq!(status, Status::Success as u8);
+        let messages = Message::deserialize_list(&body).unwrap();
+        assert_eq!(
+            messages,
+            [Message::from_u8_arr(&[1]), Message::from_u8_arr(&[2])]
+        );
+
+        let (status, _) = connection.request(Command::PushBatch, "q", &[1, 2, 3]);
+        assert_eq!(status, Status::InvalidBody as u8);
+        connection.client.write_all(&[0xFF; HEADER_SIZE]).unwrap();
+        connection.assert_cleaned_up();
+    }
+
+    #[test]
+    fn batches_fit_in_a_frame() {
+        let mut connection = connect();
+        for value in 1..=5 {
+            let message = Message::from_u8_arr(&[value; 250]);
+            let payload = Envelope::new(message, 0, None).serialize();
+            let (status, _) = connection.request(Command::Push, "q", &payload);
+            assert_eq!(status, Status::Success as u8);
+        }
+
+        // a fourth message would go past the frame size
+        let values = |body: &[u8]| -> Vec<u8> {
+            assert!(body.len() as u64 <= MAX_FRAME_SIZE);
+            let messages = Message::deserialize_list(body).unwrap();
+            messages
+                .iter()
+                .map(|message| message.get_data()[0])
+                .collect()
+        };
+        let (_, body) = connection.request(Command::Peek, "q", &5_u32.to_be_bytes());
+        assert_eq!(values(&body), [1, 2, 3]);
+        let (_, body) = connection.request(Command::PullBatch, "q", &5_u32.to_be_bytes());
+        assert_eq!(values(&body), [1, 2, 3]);
+        let (_, body) = connection.request(Command::PullBatch, "q", &5_u32.to_be_bytes());
+        assert_eq!(values(&body), [4, 5]);
+
+        connection.client.write_all(&[0xFF; HEADER_SIZE]).unwrap();
+        connection.assert_cleaned_up();
+    }
+
+    #[test]
+    fn full_queue_rejects_or_blocks_the_push() {
+        let mut connection = connect();
+        let config = |overflow| QueueConfig {
+            max_length: Some(1),
+            overflow,
+            ..QueueConfig::default()
+        };
+        connection
+            .queues
+            .create("rejects", config(Overflow::RejectNew))
+            .unwrap();
+        connection
+            .queues
+            .create("blocks", config(Overflow::Block))
+            .unwrap();
+        for queue in ["rejects", "blocks"] {
+            let (status, _) = connection.request(Command::Push, queue, &push_payload(1));
+            assert_eq!(status, Status::Success as u8);
+        }
+
+        let (status, _) = connection.request(Command::Push, "rejects", &push_payload(2));
+        assert_eq!(status, Status::QueueFull as u8);
+
+        // the push is answered once a pull makes room for it
+        let request = Request::new(Command::Push, "blocks", push_payload(2).into()).unwrap();
+        connection.client.write_all(&request.serialize()).unwrap();
+        let queues = connection.queues.clone();
+        let puller = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(100));
+            queues.pop("blocks")
+        });
+        let (status, _) = connection.response();
+        assert_eq!(status, Status::Success as u8);
+        assert_eq!(puller.join().unwrap(), Some(Message::from_u8_arr(&[1])));
+        assert_eq!(
+            connection.queues.pop("blocks"),
+            Some(Message::from_u8_arr(&[2]))
+        );
+
+        connection.client.write_all(&[0xFF; HEADER_SIZE]).unwrap();
+        connection.assert_cleaned_up();
+    }
+
+    #[test]
+    fn duplicate_pushes_are_dropped() {
+        let mut connection = connect();
+        let message = Message::from_u8_arr(&[1]).with_message_id("m1");
+        let payload = Envelope::new(message, 0, None).serialize();
+        for _ in 0..2 {
+            let (status, _) = connection.request(Command::Push, "q", &payload);
+            assert_eq!(status, Status::Success as u8);
+        }
+        let payload = push_payload(2);
+        let (status, _) = connection.request(Command::Push, "q", &payload);
+        assert_eq!(status, Status::Success as u8);
+
+        let (_, body) = connection.request(Command::Pull, "q", &[]);
+        let message = Message::deserialize(&body).unwrap();
+        assert_eq!(message.get_message_id(), Some("m1"));
+        let (_, body) = connection.request(Command::Pull, "q", &[]);
+        assert_eq!(Message::deserialize(&body).unwrap().get_data()[0], 2);
+        let (_, body) = connection.request(Command::Pull, "q", &[]);
+        assert_eq!(
+            Message::deserialize(&body).unwrap(),
+            Message::empty_message()
+        );
+
+        // the id is remembered after the message is pulled
+        let message = Message::from_u8_arr(&[1]).with_message_id("m1");
+        let payload = Envelope::new(message, 0, None).serialize();
+        let (status, _) = connection.request(Command::Push, "q", &payload);
+        assert_eq!(status, Status::Success as u8);
+        assert!(connection.queues.pop("q").is_none());
+
+        connection.client.shutdown(Shutdown::Both).unwrap();
+        connection.assert_cleaned_up();
+    }
+
+    /// the queues restored from the log at `path`
+    fn restore(path: &Path) -> Queues {
+        let (wal, events) = Wal::open(path, FsyncPolicy::Always).unwrap();
+        Queues::restore(wal, events, TIMEOUT, None, TIMEOUT, DEDUP_WINDOW).unwrap()
+    }
+
+    fn wal_path() -> PathBuf {
+        std::env::temp_dir().join(format!("smq-{}.wal", Uuid::new_v4()))
+    }
+
+    fn envelope(value: u8, priority: u8, ttl: Option<Duration>) -> Envelope {
+        Envelope::new(Message::from_u8_arr(&[value]), priority, ttl)
+    }
+
+    #[test]
+    fn log_is_replayed_and_compacted() {
+        let path = wal_path();
+        let queues = restore(&path);
+        queues.push("q", envelope(1, 0, None)).unwrap();
+        queues.push("q", envelope(2, 5, None)).unwrap();
+        queues
+            .push("q", envelope(3, 0, Some(Duration::from_secs(60))))
+            .unwrap();
+        let due = SystemTime::now().duration_since(UNIX_EPOCH).unwrap() + Duration::from_secs(60);
+        queues
+            .schedule("q", envelope(4, 0, None), due.as_millis() as u64)
+            .unwrap();
+        assert_eq!(queues.pop("q").unwrap().get_data()[0], 2);
+        drop(queues);
+
+        // the restore compacts the log to the queue and the messages left in it
+        drop(restore(&path));
+        let (_, events) = Wal::open(&path, FsyncPolicy::Always).unwrap();
+        assert_eq!(events.len(), 5);
+        assert!(!events
+            .iter()
+            .any(|event| matches!(event, Event::Dequeue { .. })));
+
+        let queues = restore(&path);
+        let stats = queues.stats("q").unwrap();
+        assert_eq!((stats.depth, stats.scheduled), (2, 1));
+        assert_eq!(queues.pop("q").unwrap().get_data()[0], 1);
+        assert_eq!(queues.pop("q").unwrap().get_data()[0], 3);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn torn_record_at_the_end_of_the_log_is_dropped() {
+        let path = wal_path();
+        let queues = restore(&path);
+        queues.push("q", envelope(1, 0, None)).unwrap();
+        drop(queues);
+        let size = std::fs::metadata(&path).unwrap().len();
+
+        // a push cut off by a crash
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&[7, 1, b'q', 0, 0, 0]).unwrap();
+        drop(file);
+
+        let (_, events) = Wal::open(&path, FsyncPolicy::Always).unwrap();
+        assert_eq!(events.len(), 2);
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), size);
+        let queues = restore(&path);
+        queues.push("q", envelope(2, 0, None)).unwrap();
+        drop(queues);
+        assert_eq!(restore(&path).stats("q").unwrap().depth, 2);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn corrupt_record_in_the_log_is_refused() {
+        let path = wal_path();
+        let queues = restore(&path);
+        queues.push("q", envelope(1, 0, None)).unwrap();
+        queues.push("q", envelope(2, 0, None)).unwrap();
+        drop(queues);
+
+        // the first record's event is unknown, the records after it are kept
+        let mut content = std::fs::read(&path).unwrap();
+        content[0] = 0xEE;
+        std::fs::write(&path, &content).unwrap();
+
+        assert!(Wal::open(&path, FsyncPolicy::Always).is_err());
+        assert_eq!(std::fs::read(&path).unwrap(), content);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn dedup_window_survives_restart() {
+        let path = wal_path();
+        let envelope = Envelope::new(Message::from_u8_arr(&[1]).with_message_id("m1"), 0, None);
+
+        let queues = restore(&path);
+        queues.push("q", envelope.clone()).unwrap();
+        assert!(queues.pop("q").is_some());
+        drop(queues);
+
+        // restored twice to go through a compacted log
+        for _ in 0..2 {
+            let queues = restore(&path);
+            queues.push("q", envelope.clone()).unwrap();
+            assert!(queues.pop("q").is_none());
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn reply_queue_is_exclusive_and_temporary() {
+        let mut connection = connect();
+        let (status, body) = connection.request(Command::CreateReplyQueue, "client", &[]);
+        assert_eq!(status, Status::Success as u8);
+        let reply_queue = String::from_utf8(body).unwrap();
+
+        let (status, _) = connection.request(Command::Push, &reply_queue, &push_payload(1));
+        assert_eq!(status, Status::Success as u8);
+        let (status, body) = connection.request(Command::Pull, &reply_queue, &[]);
+        assert_eq!(status, Status::Success as u8);
+        assert_eq!(Message::deserialize(&body).unwrap().get_data()[0], 1);
+
+        // the other connections can only push to it
+        let other = Uuid::new_v4();
+        let reply_queues = connection.reply_queues.clone();
+        assert!(reply_queues
+            .check(Command::Push, &reply_queue, other)
+            .is_ok());
+        assert!(reply_queues
+            .check(Command::Pull, &reply_queue, other)
+            .is_err());
+        let (status, _) = connection.request(Command::Pull, "#reply-gone", &[]);
+        assert_eq!(status, Status::Failed as u8);
+
+        // nor can a topic's subscriber be named like one
+        let gone = [&[11][..], b"#reply-gone"].concat();
+        let (status, _) = connection.request(Command::SubscribeTopic, "t", &gone);
+        assert_eq!(status, Status::Failed as u8);
+        assert!(connection.queues.get("#reply-gone").is_none());
+        let subscriber = [&[reply_queue.len() as u8][..], reply_queue.as_bytes()].concat();
+        let (status, _) = connection.request(Command::SubscribeTopic, "t", &subscriber);
+        assert_eq!(status, Status::Success as u8);
+        assert!(reply_queues
+            .check(Command::SubscribeTopic, &reply_queue, other)
+            .is_err());
+
+        let queues = connection.queues.clone();
+        connection.client.shutdown(Shutdown::Both).unwrap();
+        connection.assert_cleaned_up();
+        assert!(queues.get(&reply_queue).is_none());
+        assert!(reply_queues
+            .check(Command::Push, &reply_queue, other)
+            .is_err());
+    }
+
+    #[test]
+    fn reply_queues_are_dropped_on_restart() {
+        let path = std::env::temp_dir().join(format!("smq-reply-{}.wal", Uuid::new_v4()));
+        let restore = || {
+            let (wal, events) = Wal::open(&path, FsyncPolicy::Always).unwrap();
+            Queues::restore(wal, events, TIMEOUT, None, TIMEOUT, DEDUP_WINDOW).unwrap()
+        };
+
+        let queues = restore();
+        queues.create_reply_queue("#reply-a").unwrap();
+        queues.create_reply_queue("#reply-b").unwrap();
+        let envelope = Envelope::new(Message::from_u8_arr(&[1]), 0, None);
+        queues.push("#reply-a", envelope.clone()).unwrap();
+        queues.push("q", envelope).unwrap();
+        assert!(queues.delete("#reply-b").unwrap());
+        assert!(!queues.delete("#reply-b").unwrap());
+        drop(queues);
+
+        let queues = restore();
+        assert!(queues.get("#reply-a").is_none());
+        assert!(queues.get("#reply-b").is_none());
+        assert!(queues.pop("q").is_some());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn consumer_groups_have_their_own_queues() {
+        let path = wal_path();
+        let queues = restore(&path);
+        let groups = Groups::new();
+        let (a, b) = (Uuid::new_v4(), Uuid::new_v4());
+
+        // the names can't be split the same way twice
+        let first = groups.join(&queues, "t#x", "g", a).unwrap();
+        let second = groups.join(&queues, "t", "x#g", a).unwrap();
+        assert_ne!(first, second);
+        assert_eq!(groups.join(&queues, "t", "x#g", b).unwrap(), second);
+        assert_eq!(queues.publish("t", Message::from_u8_arr(&[1])).unwrap(), 1);
+        assert!(queues.pop(&second).is_some());
+        assert!(queues.pop(&first).is_none());
+
+        // only the groups' queues can be named like them
+        assert!(groups.check(Command::Push, &first).is_ok());
+        assert!(groups.check(Command::Push, "#group-1:tg").is_err());
+        assert!(groups.check(Command::ListQueues, "#group-1:tg").is_ok());
+
+        // the queue goes once the last member leaves
+        assert!(groups.leave(&queues, "t", "x#g", a));
+        assert!(!groups.leave(&queues, "t", "x#g", a));
+        assert!(queues.get(&second).is_some());
+        assert!(groups.leave(&queues, "t", "x#g", b));
+        assert!(queues.get(&second).is_none());
+        assert!(groups.check(Command::Pull, &second).is_err());
+        assert_eq!(queues.publish("t", Message::from_u8_arr(&[2])).unwrap(), 0);
+
+        // the members are gone after a restart, so are the groups
+        queues.publish("t#x", Message::from_u8_arr(&[3])).unwrap();
+        drop(queues);
+        let queues = restore(&path);
+        assert!(queues.get(&first).is_none());
+        assert_eq!(
+            queues.publish("t#x", Message::from_u8_arr(&[4])).unwrap(),
+            0
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn groups_are_left_on_disconnect() {
+        let mut connection = connect();
+        let (status, body) = connection.request(Command::JoinGroup, "t", &[1, b'g']);
+        assert_eq!(status, Status::Success as u8);
+        let queue = String::from_utf8(body).unwrap();
+        let (status, _) = connection.request(Command::Push, "#group-1:tx", &push_payload(1));
+        assert_eq!(status, Status::Failed as u8);
+        let subscriber = [&[11][..], b"#group-1:tx"].concat();
+        let (status, _) = connection.request(Command::SubscribeTopic, "u", &subscriber);
+        assert_eq!(status, Status::Failed as u8);
+        let (status, _) = connection.request(Command::Push, &queue, &push_payload(1));
+        assert_eq!(status, Status::Success as u8);
+
+        connection.client.shutdown(Shutdown::Both).unwrap();
+        let queues = connection.queues.clone();
+        connection.assert_cleaned_up();
+        assert!(queues.names().is_empty());
+    }
+
+    #[test]
+    fn message_groups_are_handed_out_in_order() {
+        let queues = Queues::new(TIMEOUT, None, TIMEOUT, DEDUP_WINDOW);
+        let push = |value: u8, group: Option<&str>, priority: u8| {
+            let message = Message::from_u8_arr(&[value]);
+            let message = match group {
+                Some(group) => message.with_message_group(group),
+                None => message,
+            };
+            queues
+                .push("q", Envelope::new(message, priority, None))
+                .unwrap();
+        };
+        let value = |message: &Message| message.get_data()[0];
+
+        push(1, Some("a"), 0);
+        push(2, Some("a"), 9);
+        push(3, Some("b"), 0);
+        push(4, None, 5);
+
+        // the ungrouped message first, the second message of `a` waits for the first one
+        let (_, _, message) = queues.reserve("q", None).unwrap();
+        assert_eq!(value(&message), 4);
+        let (a1, _, message) = queues.reserve("q", None).unwrap();
+        assert_eq!(value(&message), 1);
+        let (b3, _, message) = queues.reserve("q", None).unwrap();
+        assert_eq!(value(&message), 3);
+        assert!(queues.reserve("q", None).is_none());
+
+        assert!(queues.nack("q", a1));
+        let (a1, _, message) = queues.reserve("q", None).unwrap();
+        assert_eq!(value(&message), 1);
+        assert!(queues.ack("q", a1).unwrap());
+        let (_, _, message) = queues.reserve("q", None).unwrap();
+        assert_eq!(value(&message), 2);
+
+        // an expired reservation puts its message back at the head of the group
+        push(5, Some("b"), 9);
+        assert!(queues.reserve("q", None).is_none());
+        assert!(queues.ack("q", b3).unwrap());
+        let short = Some(Duration::from_millis(10));
+        let (_, _, message) = queues.reserve("q", short).unwrap();
+        assert_eq!(value(&message), 5);
+        push(6, Some("b"), 9);
+        assert!(queues.reserve("q", None).is_none());
+        thread::sleep(Duration::from_millis(20));
+        let (_, _, message) = queues.reserve("q", None).unwrap();
+        assert_eq!(value(&message), 5);
+    }
+
+    #[test]
+    fn admin_commands() {
+        let mut connection = connect();
+        for value in 1..=3 {
+            let (status, _) = connection.request(Command::Push, "q", &push_payload(value));
+            assert_eq!(status, Status::Success as u8);
+        }
+        let (status, _) = connection.request(Command::Reserve, "q", &[]);
+        assert_eq!(status, Status::Success as u8);
+        let consumer = connection.queues.consumer("q").unwrap();
+
+        let (status, body) = connection.request(Command::ListQueues, "admin", &[]);
+        assert_eq!(status, Status::Success as u8);
+        assert_eq!(decode_names(&body).unwrap(), ["q"]);
+
+        let (_, body) = connection.request(Command::QueueStats, "q", &[]);
+        let stats = QueueStats::deserialize(&body).unwrap();
+        assert_eq!((stats.depth, stats.bytes, stats.in_flight), (2, 2, 1));
+        assert_eq!(stats.consumers, 1);
+        drop(consumer);
+        assert!(connection.queues.consumer("missing").is_none());
+        assert!(connection.queues.get("missing").is_none());
+
+        // a queue streamed by a subscription isn't deleted
+        let (status, _) = connection.request(Command::Subscribe, "q", &0_u32.to_be_bytes());
+        assert_eq!(status, Status::Success as u8);
+        let (status, _) = connection.request(Command::DeleteQueue, "q", &[]);
+        assert_eq!(status, Status::Failed as u8);
+        let (status, _) = connection.request(Command::Unsubscribe, "q", &[]);
+        assert_eq!(status, Status::Success as u8);
+
+        let (_, body) = connection.request(Command::Peek, "q", &1_u32.to_be_bytes());
+        let messages = Message::deserialize_list(&body).unwrap();
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].get_data()[0], 2);
+
+        let (_, body) = connection.request(Command::Purge, "q", &[]);
+        assert_eq!(body, 2_u64.to_be_bytes());
+        let (_, body) = connection.request(Command::QueueStats, "q", &[]);
+        let stats = QueueStats::deserialize(&body).unwrap();
+        assert_eq!((stats.depth, stats.in_flight, stats.consumers), (0, 1, 0));
+
+        let (_, body) = connection.request(Command::DeleteQueue, "q", &[]);
+        assert_eq!(body, [1]);
+        let (_, body) = connection.request(Command::DeleteQueue, "q", &[]);
+        assert_eq!(body, [0]);
+        let (status, body) = connection.request(Command::QueueStats, "q", &[]);
+        assert_eq!(status, Status::Success as u8);
+        assert!(body.is_empty());
+
+        connection.client.shutdown(Shutdown::Both).unwrap();
+        connection.assert_cleaned_up();
+    }
+
+    #[test]
+    fn metrics_are_exported() {
+        let mut connection = connect();
+        let config = QueueConfig {
+            max_length: Some(2),
+            ..QueueConfig::default()
+        };
+        connection.queues.create("q", config).unwrap();
+        for (value, status) in [
+            (1, Status::Success),
+            (2, Status::Success),
+            (3, Status::QueueFull),
+        ] {
+            let (got, _) = connection.request(Command::Push, "q", &push_payload(value));
+            assert_eq!(got, status as u8);
+        }
+        let (status, _) = connection.request(Command::Pull, "q", &[]);
+        assert_eq!(status, Status::Success as u8);
+        // the latency is recorded after the response is written, the next response is written
+        // once it's recorded
+        let (status, _) = connection.request(Command::QueueStats, "q", &[]);
+        assert_eq!(status, Status::Success as u8);
+
+        // a free port for the exporter
+        let port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let exporter = Exporter::start(
+            port as usize,
+            connection.queues.clone(),
+            connection.metrics.clone(),
+        )
+        .unwrap();
+        let scrape = |path: &str| {
+            let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+            write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            response
+        };
+
+        // a scrape that doesn't send its request doesn't hold the others back
+        let idle = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        let started = Instant::now();
+        let response = scrape("/metrics");
+        assert!(started.elapsed() < Duration::from_secs(1));
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        for line in [
+            "smq_messages_enqueued_total{queue=\"q\"} 2",
+            "smq_messages_dequeued_total{queue=\"q\"} 1",
+            "smq_messages_rejected_total{queue=\"q\"} 1",
+            "smq_queue_depth{queue=\"q\"} 1",
+            "smq_connections 1",
+            "smq_request_duration_seconds_count{command=\"Push\"} 3",
+            "smq_request_duration_seconds_bucket{command=\"Pull\",le=\"+Inf\"} 1",
+        ] {
+            assert!(response.lines().any(|l| l == line), "missing {}", line);
+        }
+        assert!(!response.contains("smq_received_bytes_total 0\n"));
+        assert!(!response.contains("smq_sent_bytes_total 0\n"));
+        assert!(scrape("/").starts_with("HTTP/1.1 404 Not Found\r\n"));
+
+        // a scrape doesn't release the expired reservations
+        let short = Some(Duration::from_millis(1));
+        connection.queues.reserve("q", short).unwrap();
+        thread::sleep(Duration::from_millis(5));
+        let depth = "smq_queue_depth{queue=\"q\"} 0";
+        assert!(scrape("/metrics").lines().any(|l| l == depth));
+        assert_eq!(connection.queues.depth("q"), Some(0));
+
+        connection.client.shutdown(Shutdown::Both).unwrap();
+        assert_eq!(
+            connection.done.recv_timeout(TIMEOUT).unwrap(),
+            connection.id
+        );
+        assert!(connection.handler.join().is_ok());
+        assert!(scrape("/metrics").contains("\nsmq_connections 0\n"));
+        drop(idle);
+        exporter.stop();
+    }
+
+    #[test]
+    fn pipelined_responses_out_of_order() {
+        let mut connection = connect();
+        let hello = Hello::legacy().with(Feature::Pipelining);
+        let (status, body) = connection.request(Command::Hello, "client", &hello.serialize());
+        assert_eq!(status, Status::Success as u8);
+        assert!(Hello::deserialize(&body)
+            .unwrap()
+            .supports(Feature::Pipelining));
+
+        // the pull waits while the next requests are answered
+        let timeout = TIMEOUT.as_millis() as u64;
+        connection.send_tagged(Command::PullWait, "a", &timeout.to_be_bytes(), 1);
+        connection.send_tagged(Command::Push, "b", &push_payload(1), 2);
+        assert_eq!(
+            connection.tagged_response(),
+            (2, Status::Success as u8, vec![])
+        );
+
+        connection.send_tagged(Command::Push, "a", &push_payload(7), 3);
+        let mut responses = [connection.tagged_response(), connection.tagged_response()];
+        responses.sort();
+        assert_eq!(
+            responses[0],
+            (1, Status::Success as u8, push_payload(7)[9..].to_vec())
+        );
+        assert_eq!(responses[1], (3, Status::Success as u8, vec![]));
+
+        // a body without a request id is refused with the id 0
+        let header = [&[Command::Pull as u8][..], &2_u64.to_be_bytes()].concat();
+        connection.client.write_all(&header).unwrap();
+        connection.client.write_all(&[0, 0]).unwrap();
+        let (id, status, _) = connection.tagged_response();
+        assert_eq!((id, status), (0, Status::InvalidBody as u8));
+
+        connection.client.write_all(&[0xFF; HEADER_SIZE]).unwrap();
+        connection.assert_cleaned_up();
+    }
+
+    #[test]
+    fn waiting_requests_are_capped_and_stopped() {
+        let mut connection = connect();
+        let hello = Hello::legacy().with(Feature::Pipelining);
+        let (status, _) = connection.request(Command::Hello, "client", &hello.serialize());
+        assert_eq!(status, Status::Success as u8);
+
+        // the pulls would wait far longer than the test
+        let timeout = 60_000_u64.to_be_bytes();
+        for id in 1..=MAX_WAITING_REQUESTS as u32 {
+            connection.send_tagged(Command::PullWait, "q", &timeout, id);
+        }
+        let over = MAX_WAITING_REQUESTS as u32 + 1;
+        connection.send_tagged(Command::PullWait, "q", &timeout, over);
+        let (id, status, _) = connection.tagged_response();
+        assert_eq!((id, status), (over, Status::Failed as u8));
+
+        // a waiting pull still gets the next message
+        connection.send_tagged(Command::Push, "q", &push_payload(1), over + 1);
+        let mut responses = [connection.tagged_response(), connection.tagged_response()];
+        responses.sort();
+        assert_eq!(responses[0].1, Status::Success as u8);
+        assert!(responses[0].0 <= MAX_WAITING_REQUESTS as u32);
+        assert_eq!(responses[1], (over + 1, Status::Success as u8, vec![]));
+
+        // the handler doesn't wait for the pulls' timeout to finish
+        connection.client.write_all(&[0xFF; HEADER_SIZE]).unwrap();
+        connection.assert_cleaned_up();
+    }
+
+    #[test]
+    fn unknown_command_keeps_the_connection() {
+        let mut connection = connect();
+        let header = [&[42][..], &3_u64.to_be_bytes()].concat();
+        connection.client.write_all(&header).unwrap();
+        connection.client.write_all(&[1, b'q', 7]).unwrap();
+        let (status, message) = connection.response();
+        assert_eq!(status, Status::UnknownCommand as u8);
+        assert!(!message.is_empty());
+
+        let (status, _) = connection.request(Command::Push, "q", &push_payload(1));
+        assert_eq!(status, Status::Success as u8);
+        connection.client.write_all(&[0xFF; HEADER_SIZE]).unwrap();
+        connection.assert_cleaned_up();
+    }
+
+    #[test]
+    fn invalid_body_keeps_the_connection() {
+        let mut connection = connect();
+        // the queue name is longer than the body
+        let header = [&[Command::Pull as u8][..], &2_u64.to_be_bytes()].concat();
+        connection.client.write_all(&header).unwrap();
+        connection.client.write_all(&[9, b'q']).unwrap();
+        let (status, _) = connection.response();
+        assert_eq!(status, Status::InvalidBody as u8);
+
+        let (status, _) = connection.request(Command::Push, "q", &[1, 2, 3]);
+        assert_eq!(status, Status::InvalidBody as u8);
+
+        let (status, _) = connection.request(Command::Push, "q", &push_payload(1));
+        assert_eq!(status, Status::Success as u8);
+        connection.client.shutdown(Shutdown::Both).unwrap();
+        connection.assert_cleaned_up();
+    }
+
+    #[test]
+    fn closed_while_subscribed() {
+        let mut connection = connect();
+        let (status, _) = connection.request(Command::Subscribe, "q", &1_u32.to_be_bytes());
+        assert_eq!(status, Status::Success as u8);
+        connection
+            .queues
+            .push("q", Envelope::new(Message::from_u8_arr(&[1]), 0, None))
+            .unwrap();
+        let (status, body) = connection.response();
+        assert_eq!(status, Status::Success as u8);
+        assert!(!body.is_empty());
+
+        connection.client.shutdown(Shutdown::Both).unwrap();
+        connection.assert_cleaned_up();
+    }
+
+    /// the value of the next message streamed to the subscription
+    fn streamed(connection: &mut Connection) -> u8 {
+        let (status, body) = connection.response();
+        assert_eq!(status, Status::Success as u8);
+        Message::deserialize(&body).unwrap().get_data()[0]
+    }
+
+    /// the ready and the reserved messages of the queue
+    fn depth(queues: &Queues, queue: &str) -> (u64, u64) {
+        let stats = queues.stats(queue).unwrap();
+        (stats.depth, stats.in_flight)
+    }
+
+    #[test]
+    fn subscription_streams_a_message_per_credit() {
+        let mut connection = connect();
+        for value in 1..=3 {
+            let (status, _) = connection.request(Command::Push, "q", &push_payload(value));
+            assert_eq!(status, Status::Success as u8);
+        }
+        let (status, _) = connection.request(Command::Subscribe, "q", &2_u32.to_be_bytes());
+        assert_eq!(status, Status::Success as u8);
+        assert_eq!(
+            (streamed(&mut connection), streamed(&mut connection)),
+            (1, 2)
+        );
+        // the streamed messages stay reserved until they're consumed
+        assert_eq!(depth(&connection.queues, "q"), (1, 2));
+
+        let credit = Request::new(Command::Credit, "q", 1_u32.to_be_bytes().to_vec().into());
+        let credit = credit.unwrap().serialize();
+        connection.client.write_all(&credit).unwrap();
+        assert_eq!(streamed(&mut connection), 3);
+        assert_eq!(depth(&connection.queues, "q"), (0, 2));
+
+        // the unconsumed messages go back to the head of the queue in their order
+        let (status, body) = connection.request(Command::Unsubscribe, "q", &[]);
+        assert_eq!((status, body.len()), (Status::Success as u8, 0));
+        assert_eq!(depth(&connection.queues, "q"), (2, 0));
+        for value in 2..=3 {
+            let (_, body) = connection.request(Command::Pull, "q", &[]);
+            assert_eq!(Message::deserialize(&body).unwrap().get_data()[0], value);
+        }
+
+        // the requeued messages aren't counted as delivery attempts
+        connection
+            .queues
+            .push("q", Envelope::new(Message::from_u8_arr(&[4]), 0, None))
+            .unwrap();
+        let (status, _) = connection.request(Command::Subscribe, "q", &1_u32.to_be_bytes());
+        assert_eq!(status, Status::Success as u8);
+        assert_eq!(streamed(&mut connection), 4);
+        let (_, body) = connection.request(Command::Unsubscribe, "q", &[]);
+        assert!(body.is_empty());
+        let (_, attempts, _) = connection.queues.reserve("q", None).unwrap();
+        assert_eq!(attempts, 1);
+
+        connection.client.write_all(&[0xFF; HEADER_SIZE]).unwrap();
+        connection.assert_cleaned_up();
+    }
+
+    #[test]
+    fn closed_while_streaming() {
+        let mut connection = connect();
+        for value in 1..=3 {
+            let (status, _) = connection.request(Command::Push, "q", &push_payload(value));
+            assert_eq!(status, Status::Success as u8);
+        }
+        let (status, _) = connection.request(Command::Subscribe, "q", &2_u32.to_be_bytes());
+        assert_eq!(status, Status::Success as u8);
+        assert_eq!(streamed(&mut connection), 1);
+
+        // nothing is consumed without giving the credit back, every message is handed out again
+        connection.client.shutdown(Shutdown::Both).unwrap();
+        let queues = connection.queues.clone();
+        connection.assert_cleaned_up();
+        assert_eq!(depth(&queues, "q"), (3, 0));
+        let values: Vec<u8> = std::iter::from_fn(|| queues.pop("q"))
+            .map(|message| message.get_data()[0])
+            .collect();
+        assert_eq!(values, [1, 2, 3]);
+    }
+
+    #[test]
+    fn closed_while_waiting() {
+        let mut connection = connect();
+        let request = Request::new(
+            Command::PullWait,
+            "q",
+            1000_u64.to_be_bytes().to_vec().into(),
+        )
+        .unwrap();
+        connection.client.write_all(&request.serialize()).unwrap();
+        connection.client.shutdown(Shutdown::Both).unwrap();
+        connection.assert_cleaned_up();
+    }
+
+    #[test]
+    fn waiting_for_a_missing_queue_wakes_one_puller_per_message() {
+        let queues = Arc::new(Queues::new(TIMEOUT, None, TIMEOUT, DEDUP_WINDOW));
+
+        // a wait on a missing queue times out without creating it
+        assert_eq!(queues.pop_wait("q", Duration::from_millis(10)), None);
+        assert!(queues.names().is_empty());
+
+        let (tx, pulled) = mpsc::channel();
+        let pullers: Vec<JoinHandle<()>> = (0..3)
+            .map(|_| {
+                let (queues, tx) = (queues.clone(), tx.clone());
+                thread::spawn(move || tx.send(queues.pop_wait("q", TIMEOUT)).unwrap())
+            })
+            .collect();
+        thread::sleep(Duration::from_millis(50));
+        assert!(queues.names().is_empty());
+
+        let message = |value| Envelope::new(Message::from_u8_arr(&[value]), 0, None);
+        queues.push("q", message(1)).unwrap();
+        let first = pulled.recv_timeout(TIMEOUT).unwrap();
+        assert_eq!(first, Some(Message::from_u8_arr(&[1])));
+        assert!(pulled.recv_timeout(Duration::from_millis(50)).is_err());
+
+        queues.push("q", message(2)).unwrap();
+        queues.push("q", message(3)).unwrap();
+        let mut rest = vec![
+            pulled.recv_timeout(TIMEOUT).unwrap().unwrap(),
+            pulled.recv_timeout(TIMEOUT).unwrap().unwrap(),
+        ];
+        rest.sort_by_key(|message| message.get_data().to_vec());
+        assert_eq!(
+            rest,
+            [Message::from_u8_arr(&[2]), Message::from_u8_arr(&[3])]
+        );
+        for puller in pullers {
+            puller.join().unwrap();
         }
-        queue.pop_front().unwrap()
     }
 }
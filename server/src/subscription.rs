@@ -0,0 +1,181 @@
+use crate::metrics::Metered;
+use crate::queue::{Consumer, Queues};
+use crate::server::ServerImpl;
+use log::{error, info};
+use smq_lib::enums::status::Status;
+use std::collections::VecDeque;
+use std::io::Write;
+use std::net::TcpStream;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// how long the streaming thread waits for a message before checking whether it's stopped
+pub(crate) const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// the credits left to a subscription, streaming stops once it's no longer active
+pub(crate) struct Credits {
+    pub available: u32,
+    pub active: bool,
+    /// the deliveries of the messages streamed but not consumed yet, the oldest first
+    unconsumed: VecDeque<u64>,
+}
+
+impl Credits {
+    pub fn new(prefetch: u32) -> Self {
+        Credits {
+            available: prefetch,
+            active: true,
+            unconsumed: VecDeque::new(),
+        }
+    }
+
+    /// takes a credit to stream a reserved message, returns `false` if the subscription has
+    /// stopped in the meantime
+    pub fn take(&mut self, delivery_id: u64) -> bool {
+        if !self.active {
+            return false;
+        }
+        self.available -= 1;
+        self.unconsumed.push_back(delivery_id);
+        true
+    }
+
+    /// gives credits back, the subscriber gives one back for each message it consumes. Returns
+    /// the deliveries of the consumed messages
+    pub fn give(&mut self, n: u32) -> Vec<u64> {
+        self.available = self.available.saturating_add(n);
+        let consumed = self.unconsumed.len().min(n as usize);
+        self.unconsumed.drain(..consumed).collect()
+    }
+
+    /// the deliveries of the messages streamed but never consumed
+    pub fn unconsumed(&mut self) -> Vec<u64> {
+        self.unconsumed.drain(..).collect()
+    }
+}
+
+/// acknowledges the messages the subscriber consumed
+pub(crate) fn ack_consumed(queues: &Queues, queue: &str, consumed: Vec<u64>) {
+    for delivery_id in consumed {
+        if let Err(e) = queues.ack(queue, delivery_id) {
+            error!("Can't acknowledge a message of queue {}: {:?}", queue, e);
+        }
+    }
+}
+
+/// streams the messages of a queue to a connection as they are pushed. The subscriber gives
+/// credits to the subscription, a message is only streamed if there's a credit left for it.
+/// The streamed messages stay reserved until the subscriber gives their credit back, the ones
+/// it never consumes go back to the head of the queue when the subscription stops
+pub(crate) struct Subscription {
+    queues: Arc<Queues>,
+    queue: String,
+    credits: Arc<(Mutex<Credits>, Condvar)>,
+    thread: JoinHandle<()>,
+}
+
+impl Subscription {
+    /// starts streaming with `prefetch` credits, the messages are written as responses with the
+    /// success status through `writer`. On a pipelined connection, they carry the id of the
+    /// subscribe request
+    pub fn start(
+        queues: Arc<Queues>,
+        queue: &str,
+        prefetch: u32,
+        writer: Arc<Mutex<Metered<TcpStream>>>,
+        id: Option<u32>,
+    ) -> Self {
+        let credits = Arc::new((Mutex::new(Credits::new(prefetch)), Condvar::new()));
+        // counted before the response, a missing queue is counted once it's created
+        let consumer = queues.consumer(queue);
+
+        let (thread_queues, thread_queue) = (queues.clone(), queue.to_string());
+        let thread_credits = credits.clone();
+        let thread = thread::spawn(move || {
+            info!("Started streaming queue {}", thread_queue);
+            Subscription::stream(
+                thread_queues,
+                &thread_queue,
+                consumer,
+                thread_credits,
+                writer,
+                id,
+            );
+            info!("Stopped streaming queue {}", thread_queue);
+        });
+
+        Subscription {
+            queues,
+            queue: queue.to_string(),
+            credits,
+            thread,
+        }
+    }
+
+    pub fn add_credits(&self, n: u32) {
+        let (credits, cvar) = &*self.credits;
+        let mut credits = credits.lock().unwrap();
+        // the consumed messages are acknowledged before the credits can be taken again
+        ack_consumed(&self.queues, &self.queue, credits.give(n));
+        cvar.notify_one();
+    }
+
+    /// stops streaming, no message is written after this returns. The messages that are
+    /// streamed but not consumed are put back to the head of the queue
+    pub fn stop(self) {
+        {
+            let (credits, cvar) = &*self.credits;
+            credits.lock().unwrap().active = false;
+            cvar.notify_one();
+        }
+        let _ = self.thread.join();
+
+        let unconsumed = self.credits.0.lock().unwrap().unconsumed();
+        self.queues.requeue(&self.queue, &unconsumed);
+    }
+
+    fn stream(
+        queues: Arc<Queues>,
+        queue: &str,
+        mut consumer: Option<Consumer>,
+        credits: Arc<(Mutex<Credits>, Condvar)>,
+        writer: Arc<Mutex<Metered<TcpStream>>>,
+        id: Option<u32>,
+    ) {
+        let (lock, cvar) = &*credits;
+
+        loop {
+            {
+                let mut credits = lock.lock().unwrap();
+                while credits.active && credits.available == 0 {
+                    credits = cvar.wait(credits).unwrap();
+                }
+                if !credits.active {
+                    return;
+                }
+            }
+
+            if consumer.is_none() {
+                consumer = queues.consumer(queue);
+            }
+            let (delivery_id, msg) = match queues.hold_wait(queue, POLL_INTERVAL) {
+                Some(held) => held,
+                None => continue,
+            };
+            // the subscription can stop while waiting for the message
+            if !lock.lock().unwrap().take(delivery_id) {
+                queues.requeue(queue, &[delivery_id]);
+                return;
+            }
+
+            let response = ServerImpl::response(Status::Success, &msg.serialize());
+            let response = ServerImpl::tag(response, id);
+            // the message is put back once the subscription stops
+            if let Err(e) = writer.lock().unwrap().write_all(&response) {
+                error!("Can't stream a message of queue {}: {}", queue, e);
+                return;
+            }
+        }
+    }
+}
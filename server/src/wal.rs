@@ -0,0 +1,468 @@
+use crate::queue::{Entry, Queue};
+use log::{info, warn};
+use smq_lib::structs::message::Message;
+use smq_lib::structs::queue_config::QueueConfig;
+use std::collections::{BTreeSet, HashMap};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+/// when the log is flushed to the disk
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub(crate) enum FsyncPolicy {
+    /// fsync after every write
+    Always,
+    /// fsync after every n writes
+    Batch(usize),
+    /// never fsync explicitly, the OS decides when to flush its buffers
+    Os,
+}
+
+impl FsyncPolicy {
+    /// parses `always`, `os`, `batch` or `batch:<n>`
+    pub fn parse(s: &str) -> Option<FsyncPolicy> {
+        match s {
+            "always" => Some(FsyncPolicy::Always),
+            "os" => Some(FsyncPolicy::Os),
+            "batch" => Some(FsyncPolicy::Batch(DEFAULT_BATCH_SIZE)),
+            _ => match s.strip_prefix("batch:").map(str::parse) {
+                Some(Ok(n)) if n > 0 => Some(FsyncPolicy::Batch(n)),
+                _ => None,
+            },
+        }
+    }
+}
+
+const DEFAULT_BATCH_SIZE: usize = 64;
+
+const CREATE_QUEUE_EVENT: u8 = 0;
+const ENQUEUE_EVENT: u8 = 1;
+const DEQUEUE_EVENT: u8 = 2;
+const DEAD_LETTER_EVENT: u8 = 3;
+const REDRIVE_EVENT: u8 = 4;
+const SUBSCRIBE_TOPIC_EVENT: u8 = 5;
+const UNSUBSCRIBE_TOPIC_EVENT: u8 = 6;
+/// replaces `ENQUEUE_EVENT`, which is still read back from older logs as priority `0`
+const PRIORITY_ENQUEUE_EVENT: u8 = 7;
+const SCHEDULE_EVENT: u8 = 8;
+const EXPIRE_EVENT: u8 = 9;
+const DEDUP_EVENT: u8 = 10;
+const DELETE_QUEUE_EVENT: u8 = 11;
+const CREATE_REPLY_QUEUE_EVENT: u8 = 12;
+
+/// an event read back from the log
+pub(crate) enum Event {
+    CreateQueue {
+        queue: String,
+        config: QueueConfig,
+    },
+    CreateReplyQueue {
+        queue: String,
+    },
+    DeleteQueue {
+        queue: String,
+    },
+    Enqueue {
+        queue: String,
+        id: u64,
+        message: Message,
+        priority: u8,
+    },
+    Schedule {
+        queue: String,
+        id: u64,
+        message: Message,
+        priority: u8,
+        deliver_at: u64,
+    },
+    Expire {
+        queue: String,
+        id: u64,
+        expires_at: u64,
+    },
+    Dequeue {
+        queue: String,
+        id: u64,
+    },
+    DeadLetter {
+        queue: String,
+        id: u64,
+    },
+    Redrive {
+        queue: String,
+        id: u64,
+    },
+    Dedup {
+        queue: String,
+        message_id: String,
+        seen_at: u64,
+    },
+    SubscribeTopic {
+        topic: String,
+        queue: String,
+    },
+    UnsubscribeTopic {
+        topic: String,
+        queue: String,
+    },
+}
+
+/// an append-only log of the changes made to the queues.
+///
+/// each record is `[event: u8][name size: u8][queue name][id: u64][size: u64][payload]`, for
+/// enqueue events the payload is the priority followed by the serialized message, schedule events
+/// have when the message is due (`u64`, in milliseconds since the unix epoch) before that. Create
+/// events have the queue's config and expire events have when the message expires (`u64`, in
+/// milliseconds since the unix epoch), the record follows the message's enqueue or schedule
+/// record. Dedup events have when the message id is pushed (`u64`, in milliseconds since the unix
+/// epoch) followed by the id. For the other events it's empty.
+/// Topic events use the topic as the queue name and the subscribed queue's name as the payload.
+pub(crate) struct Wal {
+    path: PathBuf,
+    file: File,
+    policy: FsyncPolicy,
+    unsynced: usize,
+}
+
+impl Wal {
+    /// opens the log at `path` (creating it if needed) and reads every event in it. A partially
+    /// written record at the end of the log is discarded, a record that can't be read anywhere
+    /// else is an error and the log is left as it is.
+    pub fn open(path: &Path, policy: FsyncPolicy) -> io::Result<(Wal, Vec<Event>)> {
+        let mut content = vec![];
+        if path.exists() {
+            File::open(path)?.read_to_end(&mut content)?;
+        }
+
+        let (events, valid_size) = Wal::parse(&content)?;
+        if valid_size < content.len() {
+            warn!(
+                "Discarding {} trailing bytes of the message log",
+                content.len() - valid_size
+            );
+        }
+
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        file.set_len(valid_size as u64)?;
+        info!("Read {} events from the message log", events.len());
+
+        let wal = Wal {
+            path: path.to_path_buf(),
+            file,
+            policy,
+            unsynced: 0,
+        };
+        Ok((wal, events))
+    }
+
+    pub fn log_create_queue(&mut self, queue: &str, config: &QueueConfig) -> io::Result<()> {
+        self.append(&Wal::record(
+            CREATE_QUEUE_EVENT,
+            queue,
+            0,
+            &config.serialize(),
+        ))
+    }
+
+    pub fn log_create_reply_queue(&mut self, queue: &str) -> io::Result<()> {
+        self.append(&Wal::record(CREATE_REPLY_QUEUE_EVENT, queue, 0, &[]))
+    }
+
+    pub fn log_delete_queue(&mut self, queue: &str) -> io::Result<()> {
+        self.append(&Wal::record(DELETE_QUEUE_EVENT, queue, 0, &[]))
+    }
+
+    /// logs a pushed message, scheduled if `deliver_at` is set, along with when it expires and
+    /// its id (with when it's pushed) if it has them. The records are written (and flushed) at
+    /// once
+    pub fn log_push(
+        &mut self,
+        queue: &str,
+        entry: &Entry,
+        deliver_at: Option<u64>,
+        message_id: Option<(&str, u64)>,
+    ) -> io::Result<()> {
+        let mut records = match deliver_at {
+            Some(deliver_at) => {
+                Wal::schedule_record(queue, entry.id, &entry.message, entry.priority, deliver_at)
+            }
+            None => Wal::enqueue_record(queue, entry.id, &entry.message, entry.priority),
+        };
+        if let Some(expires_at) = entry.expires_at {
+            records.extend(Wal::expire_record(queue, entry.id, expires_at));
+        }
+        if let Some((message_id, seen_at)) = message_id {
+            records.extend(Wal::dedup_record(queue, message_id, seen_at));
+        }
+
+        self.append(&records)
+    }
+
+    pub fn log_dequeue(&mut self, queue: &str, id: u64) -> io::Result<()> {
+        self.append(&Wal::record(DEQUEUE_EVENT, queue, id, &[]))
+    }
+
+    pub fn log_dead_letter(&mut self, queue: &str, id: u64) -> io::Result<()> {
+        self.append(&Wal::record(DEAD_LETTER_EVENT, queue, id, &[]))
+    }
+
+    pub fn log_redrive(&mut self, queue: &str, id: u64) -> io::Result<()> {
+        self.append(&Wal::record(REDRIVE_EVENT, queue, id, &[]))
+    }
+
+    pub fn log_subscribe_topic(&mut self, topic: &str, queue: &str) -> io::Result<()> {
+        self.append(&Wal::record(
+            SUBSCRIBE_TOPIC_EVENT,
+            topic,
+            0,
+            queue.as_bytes(),
+        ))
+    }
+
+    pub fn log_unsubscribe_topic(&mut self, topic: &str, queue: &str) -> io::Result<()> {
+        self.append(&Wal::record(
+            UNSUBSCRIBE_TOPIC_EVENT,
+            topic,
+            0,
+            queue.as_bytes(),
+        ))
+    }
+
+    /// replaces the log with the current state of the queues and topics, used to drop the events
+    /// that no longer matter after a replay
+    pub fn compact(
+        &mut self,
+        queues: &HashMap<String, Queue>,
+        topics: &HashMap<String, BTreeSet<String>>,
+    ) -> io::Result<()> {
+        let tmp_path = self.path.with_extension("compact");
+        let mut tmp = File::create(&tmp_path)?;
+        for (queue, q) in queues {
+            let config = q.config().serialize();
+            tmp.write_all(&Wal::record(CREATE_QUEUE_EVENT, queue, 0, &config))?;
+            for entry in q.ready().chain(q.dead_letters()) {
+                let record = Wal::enqueue_record(queue, entry.id, &entry.message, entry.priority);
+                tmp.write_all(&record)?;
+            }
+            for (deliver_at, entry) in q.scheduled() {
+                let record = Wal::schedule_record(
+                    queue,
+                    entry.id,
+                    &entry.message,
+                    entry.priority,
+                    deliver_at,
+                );
+                tmp.write_all(&record)?;
+            }
+            let scheduled = q.scheduled().map(|(_, entry)| entry);
+            for entry in q.ready().chain(scheduled) {
+                if let Some(expires_at) = entry.expires_at {
+                    tmp.write_all(&Wal::expire_record(queue, entry.id, expires_at))?;
+                }
+            }
+            for entry in q.dead_letters() {
+                tmp.write_all(&Wal::record(DEAD_LETTER_EVENT, queue, entry.id, &[]))?;
+            }
+            for (seen_at, message_id) in q.dedup() {
+                tmp.write_all(&Wal::dedup_record(queue, message_id, seen_at))?;
+            }
+        }
+        for (topic, subscribers) in topics {
+            for queue in subscribers {
+                let record = Wal::record(SUBSCRIBE_TOPIC_EVENT, topic, 0, queue.as_bytes());
+                tmp.write_all(&record)?;
+            }
+        }
+        tmp.sync_all()?;
+        fs::rename(&tmp_path, &self.path)?;
+
+        self.file = OpenOptions::new().append(true).open(&self.path)?;
+        self.unsynced = 0;
+        Ok(())
+    }
+
+    /// flushes every pending write to the disk
+    pub fn sync(&mut self) -> io::Result<()> {
+        self.unsynced = 0;
+        self.file.sync_data()
+    }
+
+    fn append(&mut self, record: &[u8]) -> io::Result<()> {
+        self.file.write_all(record)?;
+        self.unsynced += 1;
+
+        match self.policy {
+            FsyncPolicy::Always => self.sync(),
+            FsyncPolicy::Batch(n) if self.unsynced >= n => self.sync(),
+            _ => Ok(()),
+        }
+    }
+
+    fn record(event: u8, queue: &str, id: u64, payload: &[u8]) -> Vec<u8> {
+        [
+            &[event, queue.len() as u8][..],
+            queue.as_bytes(),
+            &id.to_be_bytes(),
+            &(payload.len() as u64).to_be_bytes(),
+            payload,
+        ]
+        .concat()
+    }
+
+    fn enqueue_record(queue: &str, id: u64, message: &Message, priority: u8) -> Vec<u8> {
+        let payload = [&[priority][..], &message.serialize()].concat();
+        Wal::record(PRIORITY_ENQUEUE_EVENT, queue, id, &payload)
+    }
+
+    fn schedule_record(
+        queue: &str,
+        id: u64,
+        message: &Message,
+        priority: u8,
+        deliver_at: u64,
+    ) -> Vec<u8> {
+        let payload = [
+            &deliver_at.to_be_bytes()[..],
+            &[priority],
+            &message.serialize(),
+        ]
+        .concat();
+        Wal::record(SCHEDULE_EVENT, queue, id, &payload)
+    }
+
+    fn expire_record(queue: &str, id: u64, expires_at: u64) -> Vec<u8> {
+        Wal::record(EXPIRE_EVENT, queue, id, &expires_at.to_be_bytes())
+    }
+
+    fn dedup_record(queue: &str, message_id: &str, seen_at: u64) -> Vec<u8> {
+        let payload = [&seen_at.to_be_bytes()[..], message_id.as_bytes()].concat();
+        Wal::record(DEDUP_EVENT, queue, 0, &payload)
+    }
+
+    /// returns the parsed events and the size of the valid part of the log, only the last record
+    /// can be cut off
+    fn parse(content: &[u8]) -> io::Result<(Vec<Event>, usize)> {
+        let mut events = vec![];
+        let mut offset = 0;
+
+        // a record running past the end of the log has been cut off while it was written
+        while let Some(record) = Wal::frame(&content[offset..]) {
+            let event = match Wal::parse_record(&record) {
+                Some(event) => event,
+                None => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("invalid record at byte {} of the message log", offset),
+                    ))
+                }
+            };
+            events.push(event);
+            offset += record.size;
+        }
+
+        Ok((events, offset))
+    }
+
+    /// splits the next record off the log, `None` if the log ends before the record does
+    fn frame(content: &[u8]) -> Option<Record<'_>> {
+        let event = *content.first()?;
+        let name_size = *content.get(1)? as usize;
+        let queue = content.get(2..2 + name_size)?;
+
+        let mut offset = 2 + name_size;
+        let id = u64::from_be_bytes(content.get(offset..offset + 8)?.try_into().ok()?);
+        offset += 8;
+        let size = u64::from_be_bytes(content.get(offset..offset + 8)?.try_into().ok()?);
+        offset += 8;
+        let end = offset.checked_add(usize::try_from(size).ok()?)?;
+        let payload = content.get(offset..end)?;
+
+        Some(Record {
+            event,
+            queue,
+            id,
+            payload,
+            size: end,
+        })
+    }
+
+    fn parse_record(record: &Record) -> Option<Event> {
+        let (event, id, payload) = (record.event, record.id, record.payload);
+        let queue = String::from_utf8(record.queue.to_vec()).ok()?;
+        if (event == ENQUEUE_EVENT && payload.len() < 5)
+            || (event == PRIORITY_ENQUEUE_EVENT && payload.len() < 6)
+            || (event == SCHEDULE_EVENT && payload.len() < 14)
+            || (event == EXPIRE_EVENT && payload.len() != 8)
+            || (event == DEDUP_EVENT && payload.len() < 8)
+        {
+            return None;
+        }
+
+        let event = match event {
+            // older logs don't have the config
+            CREATE_QUEUE_EVENT if payload.is_empty() => Event::CreateQueue {
+                queue,
+                config: QueueConfig::default(),
+            },
+            CREATE_QUEUE_EVENT => Event::CreateQueue {
+                queue,
+                config: QueueConfig::deserialize(payload).ok()?,
+            },
+            ENQUEUE_EVENT => Event::Enqueue {
+                queue,
+                id,
+                message: Message::deserialize(payload).ok()?,
+                priority: 0,
+            },
+            PRIORITY_ENQUEUE_EVENT => Event::Enqueue {
+                queue,
+                id,
+                message: Message::deserialize(&payload[1..]).ok()?,
+                priority: payload[0],
+            },
+            SCHEDULE_EVENT => Event::Schedule {
+                queue,
+                id,
+                message: Message::deserialize(&payload[9..]).ok()?,
+                priority: payload[8],
+                deliver_at: u64::from_be_bytes(payload[..8].try_into().ok()?),
+            },
+            EXPIRE_EVENT => Event::Expire {
+                queue,
+                id,
+                expires_at: u64::from_be_bytes(payload.try_into().ok()?),
+            },
+            DEDUP_EVENT => Event::Dedup {
+                queue,
+                message_id: String::from_utf8(payload[8..].to_vec()).ok()?,
+                seen_at: u64::from_be_bytes(payload[..8].try_into().ok()?),
+            },
+            CREATE_REPLY_QUEUE_EVENT => Event::CreateReplyQueue { queue },
+            DELETE_QUEUE_EVENT => Event::DeleteQueue { queue },
+            DEQUEUE_EVENT => Event::Dequeue { queue, id },
+            DEAD_LETTER_EVENT => Event::DeadLetter { queue, id },
+            REDRIVE_EVENT => Event::Redrive { queue, id },
+            SUBSCRIBE_TOPIC_EVENT => Event::SubscribeTopic {
+                topic: queue,
+                queue: String::from_utf8(payload.to_vec()).ok()?,
+            },
+            UNSUBSCRIBE_TOPIC_EVENT => Event::UnsubscribeTopic {
+                topic: queue,
+                queue: String::from_utf8(payload.to_vec()).ok()?,
+            },
+            _ => return None,
+        };
+
+        Some(event)
+    }
+}
+
+/// a record of the log whose contents haven't been read yet
+struct Record<'a> {
+    event: u8,
+    queue: &'a [u8],
+    id: u64,
+    payload: &'a [u8],
+    /// the size of the whole record
+    size: usize,
+}